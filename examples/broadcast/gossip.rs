@@ -0,0 +1,252 @@
+//! Gossip replication shared by the broadcast and gset examples.
+//! Each node queues new messages for its neighbors, and sends every neighbor
+//! what it hasn't acknowledged yet once per round.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use maelbreaker::types::Try;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How often, and how much, unreplicated messages are sent to neighbors.
+/// A shorter interval lowers broadcast latency, a longer one sends fewer messages.
+#[derive(Debug, Clone, Copy)]
+pub struct Gossip {
+    /// time between rounds of replication
+    pub interval: Duration,
+    /// up to this much is added to each interval at random,
+    /// so nodes started together don't replicate in lockstep
+    pub jitter: Duration,
+    /// most messages sent in one replicate, larger sets are split across several
+    pub max_batch: usize,
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(600),
+            jitter: Duration::from_millis(100),
+            max_batch: 1024,
+        }
+    }
+}
+
+impl Gossip {
+    /// Reads the gossip settings from `<prefix>_INTERVAL_MS`, `<prefix>_JITTER_MS`
+    /// and `<prefix>_MAX_BATCH`, using defaults for any unset
+    pub fn from_env(prefix: &str) -> Self {
+        let default = Gossip::default();
+        Self {
+            interval: env_millis(&format!("{prefix}_INTERVAL_MS")).unwrap_or(default.interval),
+            jitter: env_millis(&format!("{prefix}_JITTER_MS")).unwrap_or(default.jitter),
+            max_batch: env_var(&format!("{prefix}_MAX_BATCH"))
+                .unwrap_or(default.max_batch)
+                .max(1),
+        }
+    }
+
+    /// When the round after one starting now is due
+    pub fn next_round(&self) -> Instant {
+        self.after(self.interval)
+    }
+
+    /// `interval` from now, plus jitter
+    pub fn after(&self, interval: Duration) -> Instant {
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+        Instant::now() + interval + jitter
+    }
+}
+
+pub fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok()?.parse().ok()
+}
+
+pub fn env_millis(name: &str) -> Option<Duration> {
+    env_var(name).map(Duration::from_millis)
+}
+
+/// Identifies the sequence of seqs a replicated batch belongs to,
+/// so a `Sequencer` can tell a sender that started numbering again from a retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Epoch {
+    /// picked at random whenever the sender starts numbering a neighbor's messages from 0,
+    /// on startup or when the neighbor is dropped and added again
+    pub nonce: u64,
+    /// the oldest seq the neighbor hasn't acked, it acked every earlier one
+    pub base: usize,
+}
+
+/// Messages queued for each neighbor until the neighbor acknowledges them.
+/// Every queued message is numbered with a seq, which acks refer to.
+/// Each neighbor has its own unbroken sequence of seqs in an epoch, see `Sequencer`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Replicator<T> {
+    // neighbor -> the seq its next message is numbered with
+    seqs: HashMap<String, usize>,
+    // neighbor -> the nonce of its current epoch
+    epochs: HashMap<String, u64>,
+    // neighbor -> seq -> message
+    unreplicated: HashMap<String, BTreeMap<usize, T>>,
+}
+
+impl<T> Default for Replicator<T> {
+    fn default() -> Self {
+        Self {
+            seqs: HashMap::new(),
+            epochs: HashMap::new(),
+            unreplicated: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone> Replicator<T> {
+    /// Queues a message for every neighbor, other than the one it came `from`
+    pub fn queue(&mut self, neighbors: &[String], message: T, from: Option<&str>) {
+        for peer in neighbors {
+            if Some(peer.as_str()) == from {
+                continue;
+            }
+
+            self.epochs.entry(peer.clone()).or_insert_with(rand::random);
+            let seq = self.seqs.entry(peer.clone()).or_default();
+            self.unreplicated
+                .entry(peer.clone())
+                .or_default()
+                .insert(*seq, message.clone());
+            *seq += 1;
+        }
+    }
+
+    /// Drops the queues of peers that are no longer neighbors.
+    /// Their seqs start from 0 in a new epoch if they become neighbors again.
+    pub fn retain(&mut self, neighbors: &[String]) {
+        self.unreplicated.retain(|peer, _| neighbors.contains(peer));
+        self.seqs.retain(|peer, _| neighbors.contains(peer));
+        self.epochs.retain(|peer, _| neighbors.contains(peer));
+    }
+
+    /// The epoch of the messages queued for `peer`, if any have been
+    pub fn epoch(&self, peer: &str) -> Option<Epoch> {
+        let nonce = *self.epochs.get(peer)?;
+        let base = self
+            .unreplicated
+            .get(peer)
+            .and_then(|unreplicated| unreplicated.keys().next())
+            .or_else(|| self.seqs.get(peer))
+            .copied()
+            .unwrap_or_default();
+
+        Some(Epoch { nonce, base })
+    }
+
+    /// Removes only the sequence numbers the peer acked,
+    /// earlier batches may still be in flight or lost
+    pub fn ack(&mut self, peer: &str, seqs: &[usize]) -> Try {
+        let unreplicated = self
+            .unreplicated
+            .get_mut(peer)
+            .ok_or(anyhow!("missing peer"))?;
+
+        for seq in seqs {
+            unreplicated.remove(seq);
+        }
+
+        Ok(())
+    }
+
+    /// Every neighbor's unacknowledged messages by seq,
+    /// split into batches of at most `max_batch`
+    pub fn batches(
+        &self,
+        neighbors: &[String],
+        max_batch: usize,
+    ) -> Vec<(String, BTreeMap<usize, T>)> {
+        let mut batches = Vec::new();
+        for peer in neighbors {
+            let Some(peer_unreplicated) = self.unreplicated.get(peer) else {
+                continue;
+            };
+
+            let unreplicated: Vec<(usize, T)> = peer_unreplicated
+                .iter()
+                .map(|(seq, message)| (*seq, message.clone()))
+                .collect();
+            for batch in unreplicated.chunks(max_batch) {
+                batches.push((peer.clone(), batch.iter().cloned().collect()));
+            }
+        }
+
+        batches
+    }
+}
+
+/// Delivers the messages each sender replicates in the order it queued them.
+/// Messages after a missing seq are held, and not acknowledged, until a retry fills the gap.
+/// A sender in a new epoch, or one we have no state for after restarting,
+/// is delivered from the epoch's base.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sequencer<T> {
+    // sender -> the nonce of the epoch being delivered
+    epochs: HashMap<String, u64>,
+    // sender -> the seq delivered next, every earlier one was delivered
+    next: HashMap<String, usize>,
+    // sender -> seq -> message waiting on an earlier seq
+    held: HashMap<String, BTreeMap<usize, T>>,
+}
+
+impl<T> Default for Sequencer<T> {
+    fn default() -> Self {
+        Self {
+            epochs: HashMap::new(),
+            next: HashMap::new(),
+            held: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Sequencer<T> {
+    /// Takes a batch of messages by seq from `sender`, returning the messages that can
+    /// now be delivered, in seq order, and the seqs to ack, every one delivered so far
+    /// that the batch retried or this call delivered
+    pub fn receive(
+        &mut self,
+        sender: &str,
+        epoch: Epoch,
+        messages: BTreeMap<usize, T>,
+    ) -> (Vec<T>, Vec<usize>) {
+        if self.epochs.get(sender) != Some(&epoch.nonce) {
+            // the sender numbers from the base again, nothing held from before applies
+            self.epochs.insert(sender.to_string(), epoch.nonce);
+            self.next.insert(sender.to_string(), epoch.base);
+            self.held.remove(sender);
+        }
+
+        let next = self.next.entry(sender.to_string()).or_default();
+        let held = self.held.entry(sender.to_string()).or_default();
+
+        let mut seqs = Vec::new();
+        for (seq, message) in messages {
+            if seq < *next {
+                // a retry of a message whose ack was lost
+                seqs.push(seq);
+            } else {
+                held.insert(seq, message);
+            }
+        }
+
+        let mut delivered = Vec::new();
+        while let Some(message) = held.remove(next) {
+            delivered.push(message);
+            seqs.push(*next);
+            *next += 1;
+        }
+
+        (delivered, seqs)
+    }
+}
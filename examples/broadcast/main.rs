@@ -1,20 +1,25 @@
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    sync::Arc,
-    thread::{self, JoinHandle},
-    time::Duration,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    env, fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, bail};
+use anyhow::{bail, Context};
+use gossip::{env_millis, env_var, Epoch, Gossip, Replicator, Sequencer};
 use maelbreaker::{
+    log,
     network::Network,
     node::Node,
     payload,
     runtime::Runtime,
     types::{BodyBuilder, Message, Try},
 };
-use parking_lot::Mutex;
-use rand::{thread_rng, Rng};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+mod gossip;
 
 payload!(
     enum Payload {
@@ -22,12 +27,16 @@ payload!(
             message: usize,
         },
         BroadcastOk,
+        // messages by the sender's seq for them in the epoch
         Replicate {
-            messages: Vec<usize>,
-            seq: usize,
+            epoch: Epoch,
+            messages: BTreeMap<usize, usize>,
         },
+        // acknowledges exactly the seqs received, so retries are precise,
+        // and the epoch nonce they were numbered in
         ReplicateOk {
-            seq: usize,
+            nonce: u64,
+            seqs: Vec<usize>,
         },
         Read,
         ReadOk {
@@ -37,154 +46,337 @@ payload!(
             topology: HashMap<String, Vec<String>>,
         },
         TopologyOk,
+        // anti-entropy, a node asks a neighbor for everything it has
+        // if the neighbor's messages don't match its digest
+        SyncRequest {
+            count: usize,
+            digest: u64,
+        },
+        SyncResponse {
+            messages: Vec<usize>,
+        },
     }
 );
 
-type Unreplicated = Arc<Mutex<HashMap<String, BTreeMap<usize, usize>>>>;
+/// How often the runtime ticks, checking whether a round of replication is due
+const TICK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Prefix of the env vars overriding the `Gossip` defaults, such as `BROADCAST_INTERVAL_MS`
+const GOSSIP_VAR_PREFIX: &str = "BROADCAST";
+
+/// Time between anti-entropy syncs with a random neighbor,
+/// catching up on messages missed while partitioned
+const SYNC_INTERVAL: Duration = Duration::from_millis(3000);
+const SYNC_INTERVAL_VAR: &str = "BROADCAST_SYNC_INTERVAL_MS";
+
+/// Repeats of a replicate sent within this window are dropped, if set.
+/// Unacked batches are resent every round, so this only saves messages
+/// when the round interval is shorter than the window.
+const DEDUP_WINDOW_VAR: &str = "BROADCAST_DEDUP_WINDOW_MS";
+
+/// Set to `true` to deliver each neighbor's messages in the order it sent them,
+/// holding back messages after one that hasn't arrived yet
+const ORDERED_VAR: &str = "BROADCAST_ORDERED";
+
+/// Directory broadcast snapshots are written to. Snapshots are disabled if unset,
+/// otherwise state from a previous run would be restored into a fresh cluster.
+const SNAPSHOT_DIR_VAR: &str = "BROADCAST_SNAPSHOT_DIR";
+
+/// Node state that survives a restart.
+/// Written as JSON to `<snapshot dir>/broadcast-<node id>.json`, for example:
+/// `{"messages":[1,2],"replicator":{"seqs":{"n2":2},"epochs":{"n2":7},"unreplicated":{"n2":{"1":2}}},..}`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    messages: HashSet<usize>,
+    replicator: Replicator<usize>,
+    /// where each neighbor's messages are delivered up to, if delivery is ordered
+    sequencer: Sequencer<usize>,
+    /// `messages` as read replies them, rebuilt after an insert
+    #[serde(skip)]
+    read_cache: Option<Vec<usize>>,
+}
+
+impl State {
+    /// Adds a message, returning false if we already had it
+    fn insert(&mut self, message: usize) -> bool {
+        let inserted = self.messages.insert(message);
+        if inserted {
+            self.read_cache = None;
+        }
+
+        inserted
+    }
+
+    /// Every message, without cloning the set for reads between inserts
+    fn read(&mut self) -> Vec<usize> {
+        self.read_cache
+            .get_or_insert_with(|| self.messages.iter().copied().collect())
+            .clone()
+    }
+
+    /// Summarizes the set of messages independent of order,
+    /// nodes with the same messages have the same digest
+    fn digest(&self) -> u64 {
+        self.messages.iter().fold(0, |digest, message| {
+            let mut hasher = DefaultHasher::new();
+            message.hash(&mut hasher);
+            digest ^ hasher.finish()
+        })
+    }
+
+    /// Where a node's snapshot is written, if snapshots are enabled
+    fn path(node_id: &str) -> Option<PathBuf> {
+        let dir = PathBuf::from(env::var_os(SNAPSHOT_DIR_VAR)?);
+        Some(dir.join(format!("broadcast-{node_id}.json")))
+    }
+
+    /// Restores the snapshot at `path`, or an empty state if there is none
+    fn load(path: Option<&Path>) -> State {
+        let Some(path) = path else {
+            return State::default();
+        };
+
+        let Ok(json) = fs::read_to_string(path) else {
+            return State::default();
+        };
+
+        match serde_json::from_str(&json) {
+            Ok(state) => {
+                log::info!("Restored snapshot from {}", path.display());
+                state
+            }
+            Err(e) => {
+                log::warn!("Ignoring corrupt snapshot at {}: {e}", path.display());
+                State::default()
+            }
+        }
+    }
+
+    /// Writes the snapshot to `path`, replacing the previous one atomically:
+    /// it's written to a temporary file first, then renamed over the old one
+    fn save(&self, path: Option<&Path>) -> Try {
+        let Some(path) = path else {
+            return Ok(());
+        };
+
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 struct BroadcastNode {
+    id: String,
+    /// nodes we replicate to, every other node until a topology is received
     neighbors: Vec<String>,
     net: Network<Payload>,
-    seq: usize,
-
-    messages: HashSet<usize>,
-    // neighbor -> seq -> message
-    unreplicated: Unreplicated,
+    state: State,
+    /// where state is snapshotted after every change, if enabled
+    snapshot: Option<PathBuf>,
+    /// whether replicated messages are delivered in the order their sender queued them
+    ordered: bool,
+    gossip: Gossip,
+    next_round: Instant,
+    sync_interval: Duration,
+    next_sync: Instant,
 }
 
 impl BroadcastNode {
+    /// Constructs a node, restoring its state from `snapshot` if it was written before
+    fn new(
+        network: Network<Payload>,
+        node_id: String,
+        node_ids: Vec<String>,
+        snapshot: Option<PathBuf>,
+    ) -> Self {
+        let neighbors: Vec<String> = node_ids.into_iter().filter(|id| id != &node_id).collect();
+
+        // pick up where we left off if we are restarting,
+        // the next tick re-sends anything still unreplicated
+        let state = State::load(snapshot.as_deref());
+        let gossip = Gossip::from_env(GOSSIP_VAR_PREFIX);
+        let sync_interval = env_millis(SYNC_INTERVAL_VAR).unwrap_or(SYNC_INTERVAL);
+
+        Self {
+            id: node_id,
+            neighbors,
+            net: network,
+            state,
+            snapshot,
+            ordered: env_var(ORDERED_VAR).unwrap_or(false),
+            gossip,
+            next_round: gossip.next_round(),
+            sync_interval,
+            next_sync: gossip.after(sync_interval),
+        }
+    }
+
+    fn save(&self) -> Try {
+        self.state.save(self.snapshot.as_deref())
+    }
+
     fn handle_broadcast(&mut self, request: Message<Payload>) -> Try {
         let Payload::Broadcast { message } = request.body.payload else {
             bail!("expected broadcast");
         };
 
-        self.messages.insert(message);
-        self.add_unreplicated(self.seq, message)?;
-        self.seq += 1;
+        self.state.insert(message);
+        self.state.replicator.queue(&self.neighbors, message, None);
+        self.save()?;
 
-        let reply = request.into_reply(Payload::BroadcastOk);
-        self.net.send(reply)
+        self.net.reply(request, Payload::BroadcastOk)
     }
 
-    fn handle_read(&self, request: Message<Payload>) -> Try {
-        let reply = request.into_reply(Payload::ReadOk {
-            messages: self.messages.clone().into_iter().collect(),
-        });
-        self.net.send(reply)
+    fn handle_read(&mut self, request: Message<Payload>) -> Try {
+        let messages = self.state.read();
+        self.net.reply(request, Payload::ReadOk { messages })
     }
 
-    fn handle_topology(&self, request: Message<Payload>) -> Try {
-        let reply = request.into_reply(Payload::TopologyOk);
-        self.net.send(reply)
+    fn handle_topology(&mut self, request: Message<Payload>) -> Try {
+        let Payload::Topology { topology } = &request.body.payload else {
+            bail!("expected topology");
+        };
+
+        if let Some(neighbors) = topology.get(&self.id) {
+            self.neighbors = neighbors.clone();
+            self.state.replicator.retain(&self.neighbors);
+            self.save()?;
+        }
+
+        self.net.reply(request, Payload::TopologyOk)
     }
 
     fn handle_replicate(&mut self, request: Message<Payload>) -> Try {
-        let Payload::Replicate { messages, seq } = &request.body.payload else {
+        let Payload::Replicate { epoch, messages } = &request.body.payload else {
             bail!("expected replicate");
         };
 
+        let nonce = epoch.nonce;
+        let (messages, seqs) = if self.ordered {
+            self.state
+                .sequencer
+                .receive(&request.src, *epoch, messages.clone())
+        } else {
+            (
+                messages.values().copied().collect(),
+                messages.keys().copied().collect(),
+            )
+        };
+
+        // pass new messages on, our neighbors may not be connected to the sender
         for message in messages {
-            self.messages.insert(*message);
+            if self.state.insert(message) {
+                self.state
+                    .replicator
+                    .queue(&self.neighbors, message, Some(&request.src));
+            }
         }
+        self.save()?;
 
-        let seq = *seq;
-        let reply = request.into_reply(Payload::ReplicateOk { seq });
-        self.net.send(reply)
+        self.net
+            .reply(request, Payload::ReplicateOk { nonce, seqs })
     }
 
     fn handle_replicate_ok(&mut self, request: Message<Payload>) -> Try {
-        let Payload::ReplicateOk { seq } = &request.body.payload else {
+        let Payload::ReplicateOk { nonce, seqs } = &request.body.payload else {
             bail!("expected replicate_ok");
         };
 
-        self.remove_unreplicated(&request.src, *seq)
+        // acks from an earlier epoch refer to seqs that have since been reused
+        let epoch = self.state.replicator.epoch(&request.src);
+        if epoch.map(|epoch| epoch.nonce) != Some(*nonce) {
+            return Ok(());
+        }
+
+        self.state.replicator.ack(&request.src, seqs)?;
+        self.save()
     }
 
-    fn add_unreplicated(&self, seq: usize, message: usize) -> Try {
-        let mut unreplicated = self.unreplicated.lock();
+    fn handle_sync_request(&self, request: Message<Payload>) -> Try {
+        let Payload::SyncRequest { count, digest } = request.body.payload else {
+            bail!("expected sync_request");
+        };
 
-        for peer in &self.neighbors {
-            unreplicated
-                .entry(peer.clone())
-                .or_insert(Default::default())
-                .insert(seq, message);
-        }
+        // the requester only learns what it lacks from everything we have,
+        // anything we lack is caught up on by our own sync
+        let in_sync = count == self.state.messages.len() && digest == self.state.digest();
+        let messages = if in_sync {
+            Vec::new()
+        } else {
+            self.state.messages.iter().copied().collect()
+        };
 
-        Ok(())
+        self.net.reply(request, Payload::SyncResponse { messages })
     }
 
-    fn remove_unreplicated(&self, peer: &str, seq: usize) -> Try {
-        let mut unreplicated = self.unreplicated.lock();
+    fn handle_sync_response(&mut self, response: Message<Payload>) -> Try {
+        let Payload::SyncResponse { messages } = &response.body.payload else {
+            bail!("expected sync_response");
+        };
+
+        // pass missed messages on like replicated ones
+        for message in messages {
+            if self.state.insert(*message) {
+                self.state
+                    .replicator
+                    .queue(&self.neighbors, *message, Some(&response.src));
+            }
+        }
+
+        self.save()
+    }
 
-        // remove all unreplicated data <= acked sequence number from peer
-        unreplicated
-            .get_mut(peer)
-            .ok_or(anyhow!("missing peer"))?
-            .retain(|sequence, _| *sequence > seq);
+    fn replicate(&self, network: &Network<Payload>) -> Try {
+        let batches = self
+            .state
+            .replicator
+            .batches(&self.neighbors, self.gossip.max_batch);
+
+        for (peer, messages) in batches {
+            let Some(epoch) = self.state.replicator.epoch(&peer) else {
+                continue;
+            };
+
+            let replicate = Message::new(
+                &self.id,
+                peer,
+                BodyBuilder::new(Payload::Replicate { epoch, messages }).build(),
+            );
+
+            network
+                .send(replicate)
+                .context("failed to send replicate")?;
+        }
 
         Ok(())
     }
 
-    fn replicator(
-        network: Network<Payload>,
-        id: String,
-        neighbors: Vec<String>,
-        unreplicated: Unreplicated,
-    ) -> JoinHandle<Try> {
-        thread::spawn::<_, Try>(move || loop {
-            thread::sleep(Duration::from_millis(600 + thread_rng().gen_range(0..100)));
-            {
-                let locked = unreplicated.lock();
-
-                for peer in &neighbors {
-                    let Some(peer_unreplicated) = locked.get(peer) else {
-                        continue;
-                    };
-
-                    let Some(highest_seq) = peer_unreplicated.keys().max() else {
-                        continue;
-                    };
-
-                    let replicate = Message::new(
-                        &id,
-                        peer,
-                        BodyBuilder::new(Payload::Replicate {
-                            messages: peer_unreplicated.values().cloned().collect(),
-                            seq: *highest_seq,
-                        })
-                        .build(),
-                    );
-
-                    network
-                        .send(replicate)
-                        .map_err(|_| anyhow!("failed to send replicate"))?;
-                }
-            }
-        })
+    /// Sends our digest to a random neighbor, which answers with its messages if they differ
+    fn sync(&self, network: &Network<Payload>) -> Try {
+        let Some(peer) = self.neighbors.choose(&mut rand::thread_rng()) else {
+            return Ok(());
+        };
+
+        let sync = Message::new(
+            &self.id,
+            peer,
+            BodyBuilder::new(Payload::SyncRequest {
+                count: self.state.messages.len(),
+                digest: self.state.digest(),
+            })
+            .build(),
+        );
+
+        network.send(sync).context("failed to send sync_request")
     }
 }
 
 impl Node<Payload> for BroadcastNode {
     fn from_init(network: Network<Payload>, node_id: String, node_ids: Vec<String>) -> Self {
-        let neighbors: Vec<String> = node_ids.into_iter().filter(|id| id != &node_id).collect();
-        let unreplicated = Unreplicated::default();
-
-        // start batch replicator
-        BroadcastNode::replicator(
-            network.clone(),
-            node_id,
-            neighbors.clone(),
-            unreplicated.clone(),
-        );
-
-        Self {
-            neighbors,
-            net: network,
-            seq: 0,
-            messages: Default::default(),
-            unreplicated,
-        }
+        let snapshot = State::path(&node_id);
+        BroadcastNode::new(network, node_id, node_ids, snapshot)
     }
 
     fn handle_message(&mut self, msg: Message<Payload>) -> Try {
@@ -194,13 +386,490 @@ impl Node<Payload> for BroadcastNode {
             Payload::Topology { .. } => self.handle_topology(msg)?,
             Payload::Replicate { .. } => self.handle_replicate(msg)?,
             Payload::ReplicateOk { .. } => self.handle_replicate_ok(msg)?,
+            Payload::SyncRequest { .. } => self.handle_sync_request(msg)?,
+            Payload::SyncResponse { .. } => self.handle_sync_response(msg)?,
             _ => {}
         };
 
         Ok(())
     }
+
+    // batch replication and syncs run on the runtime's tick,
+    // so they have exclusive access to unreplicated
+    fn tick(&mut self, network: &Network<Payload>) -> Try {
+        let now = Instant::now();
+        if now >= self.next_round {
+            self.next_round = self.gossip.next_round();
+            self.replicate(network)?;
+        }
+
+        if now >= self.next_sync {
+            self.next_sync = self.gossip.after(self.sync_interval);
+            self.sync(network)?;
+        }
+
+        Ok(())
+    }
 }
 
 fn main() -> Try {
-    Runtime::<Payload, BroadcastNode>::run()
+    let mut runtime = Runtime::<Payload, BroadcastNode>::new().with_tick(TICK_INTERVAL);
+    if let Some(window) = env_millis(DEDUP_WINDOW_VAR) {
+        runtime = runtime.with_outbound_dedup(window);
+    }
+
+    runtime.start()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use maelbreaker::testing::MockNetwork;
+
+    use super::*;
+
+    fn node_ids() -> Vec<String> {
+        (0..4).map(|i| format!("n{i}")).collect()
+    }
+
+    fn request(payload: Payload) -> Message<Payload> {
+        Message::new("c1", "n0", BodyBuilder::new(payload).msg_id(1).build())
+    }
+
+    /// Destinations of the replicate messages sent on the network
+    fn replicated_to(mock: &MockNetwork<Payload>) -> Vec<String> {
+        let mut dests: Vec<String> = mock
+            .take_sent()
+            .into_iter()
+            .filter(|msg| matches!(msg.body.payload, Payload::Replicate { .. }))
+            .map(|msg| msg.dest)
+            .collect();
+        dests.sort();
+        dests
+    }
+
+    #[test]
+    fn test_replicates_to_all_without_topology() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::from_init(mock.network(), "n0".into(), node_ids());
+
+        node.handle_message(request(Payload::Broadcast { message: 1 }))?;
+        node.replicate(&mock.network())?;
+        assert_eq!(vec!["n1", "n2", "n3"], replicated_to(&mock));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_drops_repeated_replicates() -> Try {
+        let (network, outbound) = Network::with_dedup(Duration::from_millis(100));
+        let mut node = BroadcastNode::new(network.clone(), "n0".into(), node_ids(), None);
+        let replicates = || {
+            outbound
+                .try_iter()
+                .filter(|msg| matches!(msg.body.payload, Payload::Replicate { .. }))
+                .count()
+        };
+
+        node.handle_message(request(Payload::Broadcast { message: 1 }))?;
+        node.replicate(&network)?;
+        assert_eq!(3, replicates());
+
+        // the unacked batch is the same, so it isn't sent again within the window
+        node.replicate(&network)?;
+        assert_eq!(0, replicates());
+
+        thread::sleep(Duration::from_millis(150));
+        node.replicate(&network)?;
+        assert_eq!(3, replicates());
+        Ok(())
+    }
+
+    /// Reads the node's messages, sorted
+    fn read(node: &mut BroadcastNode, mock: &MockNetwork<Payload>) -> anyhow::Result<Vec<usize>> {
+        node.handle_message(request(Payload::Read))?;
+        let Some(Payload::ReadOk { mut messages }) =
+            mock.take_sent().pop().map(|msg| msg.body.payload)
+        else {
+            bail!("expected read_ok");
+        };
+
+        messages.sort();
+        Ok(messages)
+    }
+
+    #[test]
+    fn test_read_sees_inserts() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::from_init(mock.network(), "n0".into(), node_ids());
+        assert_eq!(Vec::<usize>::new(), read(&mut node, &mock)?);
+
+        node.handle_message(request(Payload::Broadcast { message: 1 }))?;
+        node.handle_message(request(Payload::Broadcast { message: 2 }))?;
+        assert_eq!(vec![1, 2], read(&mut node, &mock)?);
+        assert_eq!(vec![1, 2], read(&mut node, &mock)?);
+
+        // an insert invalidates the cached read
+        node.handle_message(request(Payload::Broadcast { message: 3 }))?;
+        assert_eq!(vec![1, 2, 3], read(&mut node, &mock)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replicates_to_topology_neighbors() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::from_init(mock.network(), "n0".into(), node_ids());
+
+        let topology = HashMap::from([
+            ("n0".to_string(), vec!["n1".to_string(), "n3".to_string()]),
+            ("n1".to_string(), vec!["n0".to_string(), "n2".to_string()]),
+        ]);
+        node.handle_message(request(Payload::Topology { topology }))?;
+        node.handle_message(request(Payload::Broadcast { message: 1 }))?;
+        node.replicate(&mock.network())?;
+        assert_eq!(vec!["n1", "n3"], replicated_to(&mock));
+
+        // messages from a neighbor are passed on to the others
+        let replicate = BodyBuilder::new(Payload::Replicate {
+            epoch: Epoch { nonce: 1, base: 0 },
+            messages: BTreeMap::from([(0, 2)]),
+        })
+        .msg_id(2)
+        .build();
+        node.handle_message(Message::new("n1", "n0", replicate))?;
+        let nonce = node.state.replicator.epoch("n1").unwrap().nonce;
+        node.handle_message(Message::new(
+            "n1",
+            "n0",
+            BodyBuilder::new(Payload::ReplicateOk {
+                nonce,
+                seqs: vec![0],
+            })
+            .build(),
+        ))?;
+        node.replicate(&mock.network())?;
+        assert_eq!(vec!["n3"], replicated_to(&mock));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batches_capped() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::from_init(mock.network(), "n0".into(), node_ids());
+        node.gossip.max_batch = 2;
+        node.neighbors = vec!["n1".into()];
+
+        for message in 0..5 {
+            node.handle_message(request(Payload::Broadcast { message }))?;
+        }
+        mock.take_sent();
+
+        node.replicate(&mock.network())?;
+        assert_eq!(vec![vec![0, 1], vec![2, 3], vec![4]], batches(&mock));
+        Ok(())
+    }
+
+    /// Sequence numbers of the replicate messages sent on the network
+    fn batches(mock: &MockNetwork<Payload>) -> Vec<Vec<usize>> {
+        mock.take_sent()
+            .into_iter()
+            .filter_map(|msg| match msg.body.payload {
+                Payload::Replicate { messages, .. } => Some(messages.into_keys().collect()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_out_of_order_acks() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::from_init(mock.network(), "n0".into(), node_ids());
+        node.gossip.max_batch = 2;
+        node.neighbors = vec!["n1".into()];
+
+        for message in 0..5 {
+            node.handle_message(request(Payload::Broadcast { message }))?;
+        }
+        node.replicate(&mock.network())?;
+        assert_eq!(vec![vec![0, 1], vec![2, 3], vec![4]], batches(&mock));
+
+        // the last batch is acked first, earlier batches are still unacked
+        let nonce = node.state.replicator.epoch("n1").unwrap().nonce;
+        let ack = |nonce, seqs| {
+            Message::new(
+                "n1",
+                "n0",
+                BodyBuilder::new(Payload::ReplicateOk { nonce, seqs }).build(),
+            )
+        };
+        node.handle_message(ack(nonce, vec![4]))?;
+        node.handle_message(ack(nonce, vec![0, 1]))?;
+
+        // an ack from another epoch is ignored
+        node.handle_message(ack(nonce.wrapping_add(1), vec![2]))?;
+
+        node.replicate(&mock.network())?;
+        assert_eq!(vec![vec![2, 3]], batches(&mock));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_delivery_waits_for_gap() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::from_init(mock.network(), "n0".into(), node_ids());
+        node.ordered = true;
+
+        let replicate = |messages: &[(usize, usize)]| {
+            let epoch = Epoch { nonce: 1, base: 0 };
+            let messages = messages.iter().copied().collect();
+            let body = BodyBuilder::new(Payload::Replicate { epoch, messages }).build();
+            Message::new("n1", "n0", body)
+        };
+
+        // seq 3 is missing, so 4 is held back and not acked
+        node.handle_message(replicate(&[(0, 10), (1, 11), (2, 12), (4, 14)]))?;
+        assert_eq!(vec![0, 1, 2], acked(&mock));
+        assert_eq!(HashSet::from([10, 11, 12]), node.state.messages);
+
+        // the retry fills the gap, delivering both
+        node.handle_message(replicate(&[(3, 13), (4, 14)]))?;
+        assert_eq!(vec![3, 4], acked(&mock));
+        assert_eq!(HashSet::from([10, 11, 12, 13, 14]), node.state.messages);
+
+        // a retry of delivered messages is acked again
+        node.handle_message(replicate(&[(2, 12)]))?;
+        assert_eq!(vec![2], acked(&mock));
+        Ok(())
+    }
+
+    /// Seqs acked by the last message sent, if it's a replicate_ok
+    fn acked(mock: &MockNetwork<Payload>) -> Vec<usize> {
+        match mock.take_sent().pop() {
+            Some(msg) => match msg.body.payload {
+                Payload::ReplicateOk { seqs, .. } => seqs,
+                _ => Vec::new(),
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// A node replicating only to `neighbor`, delivering in order
+    fn ordered_node(mock: &MockNetwork<Payload>, id: &str, neighbor: &str) -> BroadcastNode {
+        let mut node = BroadcastNode::from_init(mock.network(), id.into(), node_ids());
+        node.neighbors = vec![neighbor.into()];
+        node.ordered = true;
+        node
+    }
+
+    /// Has `from` replicate to `to`, delivering the replicates and their acks
+    fn replicate(
+        from: &mut BroadcastNode,
+        from_mock: &MockNetwork<Payload>,
+        to: &mut BroadcastNode,
+        to_mock: &MockNetwork<Payload>,
+    ) -> Try {
+        from.replicate(&from_mock.network())?;
+        for msg in from_mock.take_sent() {
+            to.handle_message(msg)?;
+        }
+        for msg in to_mock.take_sent() {
+            from.handle_message(msg)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_delivery_after_sender_restart() -> Try {
+        let (mock0, mock1) = (MockNetwork::new(), MockNetwork::new());
+        let mut n0 = ordered_node(&mock0, "n0", "n1");
+        let mut n1 = ordered_node(&mock1, "n1", "n0");
+
+        for message in [1, 2] {
+            n1.handle_message(request(Payload::Broadcast { message }))?;
+        }
+        mock1.take_sent();
+        replicate(&mut n1, &mock1, &mut n0, &mock0)?;
+        assert_eq!(HashSet::from([1, 2]), n0.state.messages);
+
+        // restarted without its state, n1 numbers its messages from 0 again
+        let mut n1 = ordered_node(&mock1, "n1", "n0");
+        n1.handle_message(request(Payload::Broadcast { message: 3 }))?;
+        mock1.take_sent();
+        replicate(&mut n1, &mock1, &mut n0, &mock0)?;
+        assert_eq!(HashSet::from([1, 2, 3]), n0.state.messages);
+        assert!(n1.state.replicator.batches(&n1.neighbors, 1).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_delivery_after_receiver_restart() -> Try {
+        let (mock0, mock1) = (MockNetwork::new(), MockNetwork::new());
+        let mut n0 = ordered_node(&mock0, "n0", "n1");
+        let mut n1 = ordered_node(&mock1, "n1", "n0");
+
+        for message in [1, 2] {
+            n1.handle_message(request(Payload::Broadcast { message }))?;
+        }
+        mock1.take_sent();
+        replicate(&mut n1, &mock1, &mut n0, &mock0)?;
+
+        // restarted without its state, n0 picks up from n1's oldest unacked seq
+        let mut n0 = ordered_node(&mock0, "n0", "n1");
+        n1.handle_message(request(Payload::Broadcast { message: 3 }))?;
+        mock1.take_sent();
+        replicate(&mut n1, &mock1, &mut n0, &mock0)?;
+        assert_eq!(HashSet::from([3]), n0.state.messages);
+        assert!(n1.state.replicator.batches(&n1.neighbors, 1).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_neighbor_starts_new_epoch() {
+        let neighbors = vec!["n1".to_string()];
+        let mut replicator = Replicator::default();
+        replicator.queue(&neighbors, 1, None);
+        replicator.ack("n1", &[0]).unwrap();
+        let before = replicator.epoch("n1").unwrap();
+        assert_eq!(1, before.base);
+
+        // dropped and added again, n1's seqs start over
+        replicator.retain(&[]);
+        assert_eq!(None, replicator.epoch("n1"));
+        replicator.queue(&neighbors, 2, None);
+        let after = replicator.epoch("n1").unwrap();
+        assert_eq!(0, after.base);
+        assert_ne!(before.nonce, after.nonce);
+
+        // so a sequencer delivers them rather than taking them for retries
+        let mut sequencer = Sequencer::default();
+        sequencer.receive("n0", before, BTreeMap::from([(1, 1)]));
+        let (delivered, seqs) = sequencer.receive("n0", after, BTreeMap::from([(0, 2)]));
+        assert_eq!(vec![2], delivered);
+        assert_eq!(vec![0], seqs);
+    }
+
+    #[test]
+    fn test_restart_restores_snapshot() -> Try {
+        let dir = env::temp_dir().join(format!("broadcast-test-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir)?;
+        let snapshot = dir.join("broadcast-n0.json");
+
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::new(
+            mock.network(),
+            "n0".into(),
+            node_ids(),
+            Some(snapshot.clone()),
+        );
+        node.handle_message(request(Payload::Broadcast { message: 1 }))?;
+        node.handle_message(request(Payload::Broadcast { message: 2 }))?;
+
+        // only n1 acknowledges before the crash
+        let nonce = node.state.replicator.epoch("n1").unwrap().nonce;
+        let ack = BodyBuilder::new(Payload::ReplicateOk {
+            nonce,
+            seqs: vec![0, 1],
+        })
+        .build();
+        node.handle_message(Message::new("n1", "n0", ack))?;
+        drop(node);
+
+        // the restarted node has its messages, and re-sends what n2 and n3 didn't ack
+        let mock = MockNetwork::new();
+        let node = BroadcastNode::new(
+            mock.network(),
+            "n0".into(),
+            node_ids(),
+            Some(snapshot.clone()),
+        );
+        assert_eq!(HashSet::from([1, 2]), node.state.messages);
+        assert!(!dir.join("broadcast-n0.json.tmp").exists());
+
+        node.replicate(&mock.network())?;
+        let sent = mock.take_sent();
+        let dests: Vec<&str> = sent.iter().map(|msg| msg.dest.as_str()).collect();
+        assert_eq!(vec!["n2", "n3"], dests);
+        for msg in &sent {
+            let Payload::Replicate { messages, .. } = &msg.body.payload else {
+                bail!("expected replicate");
+            };
+            assert_eq!(vec![1, 2], messages.values().copied().collect::<Vec<_>>());
+        }
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_tick_waits_for_round() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::from_init(mock.network(), "n0".into(), node_ids());
+        node.handle_message(request(Payload::Broadcast { message: 1 }))?;
+
+        node.tick(&mock.network())?;
+        assert!(replicated_to(&mock).is_empty());
+
+        node.next_round = Instant::now();
+        node.tick(&mock.network())?;
+        assert_eq!(3, replicated_to(&mock).len());
+        assert!(node.next_round > Instant::now());
+        Ok(())
+    }
+
+    /// Has `from` sync with `to`, delivering only the sync request and its response
+    fn sync(
+        from: &mut BroadcastNode,
+        from_mock: &MockNetwork<Payload>,
+        to: &mut BroadcastNode,
+        to_mock: &MockNetwork<Payload>,
+    ) -> Try {
+        from.sync(&from_mock.network())?;
+        for msg in from_mock.take_sent() {
+            to.handle_message(msg)?;
+        }
+        for msg in to_mock.take_sent() {
+            from.handle_message(msg)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_after_partition_heals() -> Try {
+        let node_ids = vec!["n0".to_string(), "n1".to_string()];
+        let (mock0, mock1) = (MockNetwork::new(), MockNetwork::new());
+        let mut n0 = BroadcastNode::from_init(mock0.network(), "n0".into(), node_ids.clone());
+        let mut n1 = BroadcastNode::from_init(mock1.network(), "n1".into(), node_ids);
+
+        // partitioned, every replicate between the nodes is lost
+        n0.handle_message(request(Payload::Broadcast { message: 1 }))?;
+        n0.handle_message(request(Payload::Broadcast { message: 2 }))?;
+        n1.handle_message(request(Payload::Broadcast { message: 3 }))?;
+        n0.replicate(&mock0.network())?;
+        n1.replicate(&mock1.network())?;
+        mock0.take_sent();
+        mock1.take_sent();
+
+        // healed, each node catches up by syncing
+        sync(&mut n0, &mock0, &mut n1, &mock1)?;
+        sync(&mut n1, &mock1, &mut n0, &mock0)?;
+        let all = HashSet::from([1, 2, 3]);
+        assert_eq!(all, n0.state.messages);
+        assert_eq!(all, n1.state.messages);
+        assert_eq!(n0.state.digest(), n1.state.digest());
+
+        // once converged, syncs carry no messages
+        n0.sync(&mock0.network())?;
+        for msg in mock0.take_sent() {
+            n1.handle_message(msg)?;
+        }
+        let responses = mock1.take_sent();
+        assert_eq!(1, responses.len());
+        assert_eq!(
+            Payload::SyncResponse { messages: vec![] },
+            responses[0].body.payload
+        );
+        Ok(())
+    }
 }
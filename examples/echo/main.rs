@@ -26,10 +26,11 @@ impl Node<Payload> for EchoNode {
         let Payload::Echo { echo } = &msg.body.payload else {
             bail!("expected echo");
         };
+        // maelstrom matches the echo_ok to the echo by its msg_id
+        msg.expect_msg_id()?;
 
         let echo = echo.clone();
-        let reply = msg.into_reply(Payload::EchoOk { echo });
-        self.network.send(reply)
+        self.network.reply(msg, Payload::EchoOk { echo })
     }
 }
 
@@ -0,0 +1,138 @@
+//! A grow-only counter kept in a seq-kv key, shared by the gcount and pncount examples
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use maelbreaker::{
+    error::{is_definite, ErrorCode},
+    kv::KvClient,
+    types::Payload,
+};
+use rand::Rng;
+
+/// How long the worker waits on seq-kv before retrying, so a request
+/// lost to a partition doesn't stall it. `apply` treats a timed out cas as maybe applied.
+pub const WORKER_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Backoff between the worker's failed cas attempts
+pub const CAS_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(5),
+    max: Duration::from_millis(500),
+};
+
+/// Exponential backoff with jitter, so retries don't spin hot against seq-kv
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// delay after the first failure, doubled after each one that follows
+    pub initial: Duration,
+    /// the delay stops doubling once it reaches this
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Delay before retrying after `attempt` consecutive failures, counting from 0
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max)
+    }
+
+    /// The delay to sleep for, between half of and the full ceiling at random
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        rand::thread_rng().gen_range(ceiling / 2..=ceiling)
+    }
+}
+
+/// Handle to a worker adding deltas to a seq-kv key in the background
+#[derive(Debug, Clone, Default)]
+pub struct Counter {
+    /// Total delta that we have not yet written to the DB
+    unapplied: Arc<AtomicUsize>,
+}
+
+impl Counter {
+    /// Starts a worker adding to `key`, which must be the key's only writer.
+    /// The worker's requests time out after `WORKER_TIMEOUT`, whatever `kv`'s own timeout.
+    pub fn start<P: Payload>(kv: KvClient<P>, key: String) -> Self {
+        let kv = kv.with_timeout(WORKER_TIMEOUT);
+        let counter = Counter::default();
+        let unapplied = counter.unapplied.clone();
+
+        thread::spawn(move || {
+            // seed DB to ensure key is created, we don't care if we fail
+            let seed = kv.cas(&key, 0, 0, true);
+            eprintln!("seed result: {seed:#?}");
+            eprintln!("initializing counter worker {key}");
+
+            // runs until the node stops
+            while !kv.network().is_shut_down() {
+                apply(&kv, &key, &unapplied, &CAS_BACKOFF);
+            }
+        });
+
+        counter
+    }
+
+    /// Queues `delta` to be added to the key
+    pub fn add(&self, delta: usize) {
+        self.unapplied.fetch_add(delta, SeqCst);
+    }
+}
+
+/// Adds the unapplied delta to `key`, consuming it only once a cas succeeds.
+/// The worker is the key's only writer, so after a cas that may have been applied,
+/// finding the key at the value we wrote means that cas went through.
+pub fn apply<P: Payload>(kv: &KvClient<P>, key: &str, unapplied: &AtomicUsize, backoff: &Backoff) {
+    let to_apply = unapplied.load(SeqCst);
+    if to_apply == 0 {
+        return;
+    }
+
+    let Ok(from) = kv.read::<usize>(key) else {
+        return;
+    };
+
+    let to = from + to_apply;
+    let mut maybe_applied = false;
+    for attempt in 0.. {
+        match kv.cas(key, from, to, true) {
+            Ok(()) => {
+                unapplied.fetch_sub(to_apply, SeqCst);
+                return;
+            }
+            // the key changed since our read, either an earlier attempt of ours
+            // was applied after all, or we retry from a fresh read
+            Err(e) if e.downcast_ref() == Some(&ErrorCode::PreconditionFailed) => {
+                if maybe_applied {
+                    match kv.read::<usize>(key) {
+                        Ok(value) if value == to => {
+                            unapplied.fetch_sub(to_apply, SeqCst);
+                            return;
+                        }
+                        Ok(_) => {}
+                        // we can't tell yet, the cas is conditional so it's safe to repeat
+                        Err(_) => {
+                            thread::sleep(backoff.delay(attempt));
+                            continue;
+                        }
+                    }
+                }
+                thread::sleep(backoff.delay(attempt));
+                return;
+            }
+            // the delta may not have been applied, retry the same cas
+            Err(e) => {
+                eprintln!("failed to cas {key}: {e:#}");
+                maybe_applied |= !e.downcast_ref().is_some_and(|code| is_definite(*code));
+                thread::sleep(backoff.delay(attempt));
+            }
+        }
+    }
+}
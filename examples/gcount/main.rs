@@ -1,22 +1,18 @@
-use std::{
-    collections::HashMap,
-    error::Error,
-    sync::{
-        atomic::{AtomicUsize, Ordering::SeqCst},
-        Arc,
-    },
-    thread,
-};
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::bail;
+use counter::Counter;
 use maelbreaker::{
+    kv::KvClient,
     network::Network,
     node::Node,
     payload,
     runtime::Runtime,
-    types::{BodyBuilder, Message, Try},
+    types::{ErrorBody, Message, Service, Try},
 };
 
+mod counter;
+
 // To use a service, simply send an RPC request to the node ID of the service you want to use:
 // for instance, seq-kv. The service will send you a response message.
 payload!(
@@ -25,188 +21,82 @@ payload!(
             delta: usize,
         },
         AddOk,
-        Read,
 
-        // shared by challenge and seq-kv
+        // shared by challenge and seq-kv, only seq-kv reads have a key
+        Read {
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            key: Option<String>,
+        },
         ReadOk {
             value: usize,
         },
 
-        #[serde(rename = "read")]
-        KvRead {
-            key: String,
-        },
-
         Cas {
             key: String,
             from: usize,
             to: usize,
             create_if_not_exists: bool,
         },
-        #[serde(rename = "cas_ok")]
         CasOk,
 
-        Error {
-            code: usize,
-            text: String,
-        },
+        Error(ErrorBody),
     }
 );
 
+/// How long a client read waits on seq-kv before answering from the cache,
+/// so reads stay responsive while seq-kv is partitioned away
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
 struct GCountNode {
-    id: String,
     ids: Vec<String>,
 
     /// last seen value for seq-db keys
     cache: HashMap<String, usize>,
     network: Network<Payload>,
-
-    /// Total delta that we have not yet written to the DB
-    unapplied: Arc<AtomicUsize>,
-    seq: Arc<AtomicUsize>,
+    kv: KvClient<Payload>,
+    counter: Counter,
 }
 
 impl Node<Payload> for GCountNode {
     fn from_init(network: Network<Payload>, id: String, ids: Vec<String>) -> Self {
         eprintln!("initializing gcount node {id}");
-        let unapplied = Arc::new(AtomicUsize::new(0));
-        let seq = Arc::new(AtomicUsize::new(5));
-
-        GCountNode::worker(id.clone(), network.clone(), unapplied.clone(), seq.clone());
+        let kv = KvClient::new(network.clone(), &id, Service::SeqKv);
+        let counter = Counter::start(kv.clone(), id);
         Self {
-            id,
             ids,
             cache: Default::default(),
             network,
-            unapplied,
-            seq,
+            kv: kv.with_timeout(READ_TIMEOUT),
+            counter,
         }
     }
 
     fn handle_message(&mut self, msg: Message<Payload>) -> Try {
         match &msg.body.payload {
             Payload::Add { .. } => self.handle_add(msg),
-            Payload::Read => self.handle_read(msg),
+            Payload::Read { .. } => self.handle_read(msg),
             _ => Ok(()),
         }
     }
 }
 
 impl GCountNode {
-    fn worker(
-        id: String,
-        network: Network<Payload>,
-        unapplied: Arc<AtomicUsize>,
-        seq: Arc<AtomicUsize>,
-    ) {
-        thread::spawn(move || {
-            // seed DB to ensure key is created, we don't care if we fail
-            let seed = GCountNode::cas_db(&id, &network, &seq, &id, 0, 0);
-            eprintln!("seed result: {seed:#?}");
-            eprintln!("initializing gcount worker {id}");
-
-            loop {
-                let to_apply = unapplied.load(SeqCst);
-                if to_apply > 0 {
-                    let Ok(from) = GCountNode::read_db(&id, &network, &seq, &id) else {
-                        continue;
-                    };
-
-                    let to = from + to_apply;
-
-                    // cas until we get cas_ok or precondition failed, either way
-                    // we know our write was applied since we are the only node writing
-                    // to this seq-kv key
-                    loop {
-                        let result = GCountNode::cas_db(&id, &network, &seq, &id, from, to);
-                        let Ok(result) = result else {
-                            eprintln!("failed to send/recv cas: {:#?}", result.unwrap_err());
-                            continue;
-                        };
-
-                        match result.body.payload {
-                            // todo: we are assuming error == precondition failed
-                            Payload::CasOk | Payload::Error { .. } => {
-                                unapplied.fetch_sub(to_apply, SeqCst);
-                                break;
-                            }
-                            _ => continue,
-                        }
-                    }
-                }
-            }
-        });
-    }
-
-    fn read_db(
-        id: &str,
-        network: &Network<Payload>,
-        seq: &Arc<AtomicUsize>,
-        key: &str,
-    ) -> Result<usize, Box<dyn Error>> {
-        let seq = seq.fetch_add(1, SeqCst);
-        eprintln!("reading from seq-kv {seq}");
-
-        let body = BodyBuilder::new(Payload::KvRead { key: key.into() })
-            .msg_id(seq)
-            .build();
-        let read = Message::new(id, "seq-kv", body);
-
-        eprintln!("waiting for response from seq-kv {seq}");
-        let Payload::ReadOk { value } = network
-            .rpc(read)
-            .map_err(|_| "failed to read")?
-            .recv()?.body.payload else {
-                return Err("expected read_ok")?;
-            };
-
-        Ok(value)
-    }
-
-    fn cas_db(
-        id: &str,
-        network: &Network<Payload>,
-        seq: &Arc<AtomicUsize>,
-        key: &str,
-        previous: usize,
-        target: usize,
-    ) -> Result<Message<Payload>, Box<dyn Error>> {
-        let seq = seq.fetch_add(1, SeqCst);
-
-        let body = BodyBuilder::new(Payload::Cas {
-            key: key.into(),
-            from: previous,
-            to: target,
-            create_if_not_exists: true,
-        })
-        .msg_id(seq)
-        .build();
-        let cas = Message::new(id, "seq-kv", body);
-
-        let cas_callback = network.rpc(cas).map_err(|_| "failed to send cas rpc")?;
-        let cas_resp = cas_callback
-            .recv()
-            .map_err(|_| "failed to recv cas response")?;
-        Ok(cas_resp)
-    }
-
     fn handle_add(&self, msg: Message<Payload>) -> Try {
         let Payload::Add { delta } = &msg.body.payload else {
             bail!("expected add");
         };
 
-        self.unapplied.fetch_add(*delta, SeqCst);
-        let reply = msg.into_reply(Payload::AddOk);
-        self.network.send(reply)
+        self.counter.add(*delta);
+        self.network.reply(msg, Payload::AddOk)
     }
 
     fn handle_read(&mut self, msg: Message<Payload>) -> Try {
         let mut value = 0;
 
         // read db entry for each node, or returned the cached value
-        for id in &self.ids {
-            let read_result = GCountNode::read_db(&self.id, &self.network, &self.seq, id);
-            let read = match read_result {
+        let reads = self.kv.read_many(&self.ids);
+        for (id, read) in self.ids.iter().zip(reads) {
+            let read = match read {
                 Ok(read) => {
                     // update cache
                     self.cache.insert(id.clone(), read);
@@ -217,11 +107,204 @@ impl GCountNode {
             value += read;
         }
 
-        let reply = msg.into_reply(Payload::ReadOk { value });
-        self.network.send(reply)
+        self.network.reply(msg, Payload::ReadOk { value })
     }
 }
 
 fn main() -> Try {
     Runtime::<Payload, GCountNode>::run()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering::SeqCst},
+        thread,
+        time::Instant,
+    };
+
+    use counter::{apply, Backoff};
+    use maelbreaker::{error::ErrorCode, testing::MockNetwork, types::BodyBuilder};
+
+    use super::*;
+
+    const NO_BACKOFF: Backoff = Backoff {
+        initial: Duration::ZERO,
+        max: Duration::ZERO,
+    };
+
+    /// Waits for the counter worker to send `count` messages in total
+    fn wait_for_sent(mock: &MockNetwork<Payload>, count: usize) -> Vec<Message<Payload>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let sent = mock.sent();
+            if sent.len() >= count || Instant::now() > deadline {
+                return sent;
+            }
+
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_backoff_increases() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+        };
+
+        let ceilings: Vec<u128> = (0..6)
+            .map(|attempt| backoff.ceiling(attempt).as_millis())
+            .collect();
+        assert_eq!(vec![10, 20, 40, 80, 100, 100], ceilings);
+        assert_eq!(backoff.max, backoff.ceiling(u32::MAX));
+
+        for attempt in 0..6 {
+            let delay = backoff.delay(attempt);
+            let ceiling = backoff.ceiling(attempt);
+            assert!(ceiling / 2 <= delay && delay <= ceiling, "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_error_keeps_delta() {
+        let mock = MockNetwork::new();
+        let kv = KvClient::new(mock.network(), "n0", Service::SeqKv);
+        let unapplied = AtomicUsize::new(3);
+
+        mock.respond(0, Payload::ReadOk { value: 5 });
+        mock.respond(
+            1,
+            Payload::Error(ErrorBody::new(
+                ErrorCode::TemporarilyUnavailable,
+                "try again",
+            )),
+        );
+        mock.respond(2, Payload::CasOk);
+        apply(&kv, "n0", &unapplied, &NO_BACKOFF);
+
+        // the failed cas is retried, not counted as applied
+        let cas: Vec<Payload> = mock
+            .sent()
+            .into_iter()
+            .map(|msg| msg.body.payload)
+            .filter(|payload| matches!(payload, Payload::Cas { .. }))
+            .collect();
+        assert_eq!(2, cas.len());
+        assert_eq!(cas[0], cas[1]);
+        assert_eq!(0, unapplied.load(SeqCst));
+    }
+
+    #[test]
+    fn test_precondition_failed_rereads() {
+        let mock = MockNetwork::new();
+        let kv = KvClient::new(mock.network(), "n0", Service::SeqKv);
+        let unapplied = AtomicUsize::new(3);
+
+        mock.respond(0, Payload::ReadOk { value: 5 });
+        mock.respond(
+            1,
+            Payload::Error(ErrorBody::new(ErrorCode::PreconditionFailed, "was 6")),
+        );
+        apply(&kv, "n0", &unapplied, &NO_BACKOFF);
+        assert_eq!(3, unapplied.load(SeqCst));
+
+        mock.respond(2, Payload::ReadOk { value: 6 });
+        mock.respond(3, Payload::CasOk);
+        apply(&kv, "n0", &unapplied, &NO_BACKOFF);
+        assert_eq!(0, unapplied.load(SeqCst));
+        assert_eq!(
+            Payload::Cas {
+                key: "n0".into(),
+                from: 6,
+                to: 9,
+                create_if_not_exists: true
+            },
+            mock.sent()[3].body.payload
+        );
+    }
+
+    #[test]
+    fn test_indefinite_error_then_precondition_failed() {
+        let mock = MockNetwork::new();
+        let kv = KvClient::new(mock.network(), "n0", Service::SeqKv);
+        let unapplied = AtomicUsize::new(3);
+
+        // the crashed cas was applied, so the retry finds the key already at 8
+        mock.respond(0, Payload::ReadOk { value: 5 });
+        mock.respond(
+            1,
+            Payload::Error(ErrorBody::new(ErrorCode::Crash, "maybe applied")),
+        );
+        mock.respond(
+            2,
+            Payload::Error(ErrorBody::new(ErrorCode::PreconditionFailed, "was 8")),
+        );
+        mock.respond(3, Payload::ReadOk { value: 8 });
+        apply(&kv, "n0", &unapplied, &NO_BACKOFF);
+
+        // the delta is counted once, not added again on top of 8
+        assert_eq!(0, unapplied.load(SeqCst));
+        apply(&kv, "n0", &unapplied, &NO_BACKOFF);
+        assert_eq!(4, mock.sent().len());
+    }
+
+    #[test]
+    fn test_worker_resumes_after_lost_cas() {
+        let mock = MockNetwork::new();
+        mock.respond(0, Payload::CasOk);
+        mock.respond(1, Payload::ReadOk { value: 5 });
+        let counter = Counter::start(
+            KvClient::new(mock.network(), "n0", Service::SeqKv),
+            "n0".into(),
+        );
+        counter.add(3);
+
+        // the first cas is never answered, the worker times out and retries it
+        let cas = wait_for_sent(&mock, 3).remove(2);
+        mock.respond(3, Payload::CasOk);
+        assert_eq!(cas.body.payload, wait_for_sent(&mock, 4)[3].body.payload);
+
+        // and goes on to apply later deltas
+        mock.respond(4, Payload::ReadOk { value: 8 });
+        counter.add(1);
+        let sent = wait_for_sent(&mock, 6);
+        assert_eq!(
+            Payload::Cas {
+                key: "n0".into(),
+                from: 8,
+                to: 9,
+                create_if_not_exists: true
+            },
+            sent[5].body.payload
+        );
+    }
+
+    #[test]
+    fn test_read_falls_back_to_cache() -> Try {
+        // seq-kv never replies, as if it were partitioned away
+        let mock = MockNetwork::new();
+        let network = mock.network();
+        let mut node = GCountNode {
+            ids: vec!["n0".into(), "n1".into()],
+            cache: HashMap::from([("n0".into(), 2), ("n1".into(), 3)]),
+            kv: KvClient::new(network.clone(), "n0", Service::SeqKv).with_timeout(READ_TIMEOUT),
+            network,
+            counter: Counter::default(),
+        };
+
+        let read = Message::new(
+            "c1",
+            "n0",
+            BodyBuilder::new(Payload::Read { key: None })
+                .msg_id(1)
+                .build(),
+        );
+        node.handle_message(read)?;
+
+        let reply = mock.sent().pop().expect("read was answered");
+        assert_eq!("c1", reply.dest);
+        assert_eq!(Payload::ReadOk { value: 5 }, reply.body.payload);
+        Ok(())
+    }
+}
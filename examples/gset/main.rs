@@ -0,0 +1,268 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use gossip::{Gossip, Replicator};
+use maelbreaker::types::prelude::*;
+use serde_json::Value;
+
+// element order doesn't matter in a set, so the sequencer is unused
+#[allow(dead_code)]
+#[path = "../broadcast/gossip.rs"]
+mod gossip;
+
+/*
+
+implementation: grow-only set
+
+    like broadcast, each node keeps every element it has seen and gossips new ones
+    to its neighbors until they acknowledge them, with the same replicator.
+    elements are any JSON value, which can't be hashed, so the set is kept
+    keyed by each element's JSON text.
+*/
+
+payload!(
+    include(Read, ReadOk<Vec<Value>>),
+    enum Payload {
+        Add {
+            element: Value,
+        },
+        AddOk,
+        Topology {
+            topology: HashMap<String, Vec<String>>,
+        },
+        TopologyOk,
+        // elements by the sender's seq for them
+        Replicate {
+            elements: BTreeMap<usize, Value>,
+        },
+        ReplicateOk {
+            seqs: Vec<usize>,
+        },
+    }
+);
+
+/// How often the runtime ticks, checking whether a round of replication is due
+const TICK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Prefix of the env vars overriding the `Gossip` defaults, such as `GSET_INTERVAL_MS`
+const GOSSIP_VAR_PREFIX: &str = "GSET";
+
+#[derive(Debug)]
+struct GSetNode {
+    id: String,
+    /// nodes we replicate to, every other node until a topology is received
+    neighbors: Vec<String>,
+    net: Network<Payload>,
+    // element JSON text -> element
+    elements: HashMap<String, Value>,
+    replicator: Replicator<Value>,
+    gossip: Gossip,
+    next_round: Instant,
+}
+
+impl GSetNode {
+    /// Adds an element to the set, returning false if it was already present
+    fn insert(&mut self, element: &Value) -> bool {
+        self.elements
+            .insert(element.to_string(), element.clone())
+            .is_none()
+    }
+
+    fn handle_add(&mut self, request: Message<Payload>) -> Try {
+        let Payload::Add { element } = &request.body.payload else {
+            bail!("expected add");
+        };
+
+        if self.insert(element) {
+            self.replicator
+                .queue(&self.neighbors, element.clone(), None);
+        }
+
+        self.net.reply(request, Payload::AddOk)
+    }
+
+    fn handle_read(&self, request: Message<Payload>) -> Try {
+        let value = self.elements.values().cloned().collect();
+        self.net.reply(request, Payload::ReadOk(ReadOk { value }))
+    }
+
+    fn handle_topology(&mut self, request: Message<Payload>) -> Try {
+        let Payload::Topology { topology } = &request.body.payload else {
+            bail!("expected topology");
+        };
+
+        if let Some(neighbors) = topology.get(&self.id) {
+            self.neighbors = neighbors.clone();
+            self.replicator.retain(&self.neighbors);
+        }
+
+        self.net.reply(request, Payload::TopologyOk)
+    }
+
+    fn handle_replicate(&mut self, request: Message<Payload>) -> Try {
+        let Payload::Replicate { elements } = &request.body.payload else {
+            bail!("expected replicate");
+        };
+
+        // merge, passing new elements on to our other neighbors
+        for element in elements.values() {
+            if self.insert(element) {
+                self.replicator
+                    .queue(&self.neighbors, element.clone(), Some(&request.src));
+            }
+        }
+
+        let seqs = elements.keys().copied().collect();
+        self.net.reply(request, Payload::ReplicateOk { seqs })
+    }
+
+    fn handle_replicate_ok(&mut self, request: Message<Payload>) -> Try {
+        let Payload::ReplicateOk { seqs } = &request.body.payload else {
+            bail!("expected replicate_ok");
+        };
+
+        self.replicator.ack(&request.src, seqs)
+    }
+
+    fn replicate(&self, network: &Network<Payload>) -> Try {
+        let batches = self
+            .replicator
+            .batches(&self.neighbors, self.gossip.max_batch);
+
+        for (peer, elements) in batches {
+            let replicate = Message::new(
+                &self.id,
+                peer,
+                BodyBuilder::new(Payload::Replicate { elements }).build(),
+            );
+
+            network
+                .send(replicate)
+                .context("failed to send replicate")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Node<Payload> for GSetNode {
+    fn from_init(network: Network<Payload>, node_id: String, node_ids: Vec<String>) -> Self {
+        let neighbors: Vec<String> = node_ids.into_iter().filter(|id| id != &node_id).collect();
+        let gossip = Gossip::from_env(GOSSIP_VAR_PREFIX);
+
+        Self {
+            id: node_id,
+            neighbors,
+            net: network,
+            elements: HashMap::new(),
+            replicator: Replicator::default(),
+            gossip,
+            next_round: gossip.next_round(),
+        }
+    }
+
+    fn handle_message(&mut self, msg: Message<Payload>) -> Try {
+        match &msg.body.payload {
+            Payload::Add { .. } => self.handle_add(msg)?,
+            Payload::Read => self.handle_read(msg)?,
+            Payload::Topology { .. } => self.handle_topology(msg)?,
+            Payload::Replicate { .. } => self.handle_replicate(msg)?,
+            Payload::ReplicateOk { .. } => self.handle_replicate_ok(msg)?,
+            _ => {}
+        };
+
+        Ok(())
+    }
+
+    fn tick(&mut self, network: &Network<Payload>) -> Try {
+        if Instant::now() < self.next_round {
+            return Ok(());
+        }
+
+        self.next_round = self.gossip.next_round();
+        self.replicate(network)
+    }
+}
+
+fn main() -> Try {
+    Runtime::<Payload, GSetNode>::new()
+        .with_tick(TICK_INTERVAL)
+        .start()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use maelbreaker::testing::MockNetwork;
+    use serde_json::json;
+
+    use super::*;
+
+    fn add(element: Value) -> Message<Payload> {
+        Message::new(
+            "c1",
+            "n0",
+            BodyBuilder::new(Payload::Add { element }).msg_id(1).build(),
+        )
+    }
+
+    /// Has `from` replicate to `to`, delivering the replicates and their acks
+    fn replicate(
+        from: &mut GSetNode,
+        from_mock: &MockNetwork<Payload>,
+        to: &mut GSetNode,
+        to_mock: &MockNetwork<Payload>,
+    ) -> Try {
+        from.replicate(&from_mock.network())?;
+        for msg in from_mock.take_sent() {
+            to.handle_message(msg)?;
+        }
+        for msg in to_mock.take_sent() {
+            from.handle_message(msg)?;
+        }
+
+        Ok(())
+    }
+
+    fn elements(node: &GSetNode) -> HashSet<String> {
+        node.elements.keys().cloned().collect()
+    }
+
+    #[test]
+    fn test_disjoint_adds_converge() -> Try {
+        let node_ids = vec!["n0".to_string(), "n1".to_string()];
+        let (mock0, mock1) = (MockNetwork::new(), MockNetwork::new());
+        let mut n0 = GSetNode::from_init(mock0.network(), "n0".into(), node_ids.clone());
+        let mut n1 = GSetNode::from_init(mock1.network(), "n1".into(), node_ids);
+
+        n0.handle_message(add(json!(1)))?;
+        n0.handle_message(add(json!("two")))?;
+        n1.handle_message(add(json!({"three": 3})))?;
+        n1.handle_message(add(json!([4])))?;
+        mock0.take_sent();
+        mock1.take_sent();
+
+        replicate(&mut n0, &mock0, &mut n1, &mock1)?;
+        replicate(&mut n1, &mock1, &mut n0, &mock0)?;
+
+        let all = HashSet::from([
+            json!(1).to_string(),
+            json!("two").to_string(),
+            json!({"three": 3}).to_string(),
+            json!([4]).to_string(),
+        ]);
+        assert_eq!(all, elements(&n0));
+        assert_eq!(all, elements(&n1));
+
+        // everything was acknowledged, so there is nothing left to send
+        n0.replicate(&mock0.network())?;
+        n1.replicate(&mock1.network())?;
+        assert!(mock0.take_sent().is_empty());
+        assert!(mock1.take_sent().is_empty());
+        Ok(())
+    }
+}
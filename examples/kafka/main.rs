@@ -1,30 +1,38 @@
 use std::{
-    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
-    hash::{Hash, Hasher},
+    collections::{BTreeMap, HashMap, HashSet},
+    env, mem,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        mpsc::{channel, Sender},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
         Arc,
     },
     thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, bail};
 use maelbreaker::{
-    network::Network,
+    error::{ErrorCode, MaelstromError},
+    kv::KvClient,
+    network::{Network, RpcTimeout},
     node::Node,
+    partition::{Partitioner, Ring},
     payload,
+    peer::Peer,
     runtime::Runtime,
-    types::{BodyBuilder, Message, Try},
+    types::{ErrorBody, Message, Service, Try},
 };
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 /*
 
 implementation: partitioned kafka
 
     each log is owned by exactly 1 KafkaNode.
-        we partition a given log_key across node_ids as follows
-            owner = node_ids[ hash(log_key) % node_ids ]
+        we partition a given log_key across node_ids with a consistent hashing ring,
+        or any other Partitioner passed to KafkaNode::new
+            owner = ring(node_ids).owner(log_key)
 
         we perform this process for each request, and then make a decision
             req = Request()
@@ -111,25 +119,253 @@ payload!(
         ListCommittedOffsetsOk {
             offsets: HashMap<String, usize>,
         },
+
+        // lin-kv, for durable logs
+        Read {
+            key: String,
+        },
+        ReadOk {
+            value: Value,
+        },
+        Write {
+            key: String,
+            value: Value,
+        },
+        WriteOk,
+        Error(ErrorBody),
     }
 );
 
-#[derive(Clone, Default)]
-struct Sequence {
-    shared: Arc<AtomicUsize>,
+impl MaelstromError for Payload {
+    fn error(code: ErrorCode, text: String) -> Self {
+        Payload::Error(ErrorBody::new(code, text))
+    }
+}
+
+/// Set to persist logs to lin-kv, so a node restarted by a crash recovers them.
+/// Logs are only kept in memory if unset.
+const DURABLE_VAR: &str = "KAFKA_DURABLE";
+
+/// How long the log store waits on lin-kv before failing the request being handled
+const STORE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum number of jobs that may be waiting on each background worker
+const WORKER_QUEUE_CAPACITY: usize = 1024;
+
+/// Set to `block` to have handlers wait on a full worker queue, see `QueuePolicy`.
+/// Jobs are shed if unset.
+const QUEUE_POLICY_VAR: &str = "KAFKA_QUEUE_POLICY";
+
+/// What a handler does when a background worker's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueuePolicy {
+    /// Wait for the worker to make room. This applies backpressure to the handler,
+    /// but a handler blocked on a full queue can't serve forwarded requests from
+    /// other partitions, which may stall their workers in turn.
+    Block,
+    /// Drop the job and fail the client request as temporarily unavailable,
+    /// the client will retry.
+    Shed,
+}
+
+impl QueuePolicy {
+    fn from_env() -> Self {
+        match env::var(QUEUE_POLICY_VAR).as_deref() {
+            Ok("block") => QueuePolicy::Block,
+            _ => QueuePolicy::Shed,
+        }
+    }
+}
+
+/// What became of a job pushed to a worker queue
+#[derive(Debug, PartialEq, Eq)]
+enum Pushed<T> {
+    Queued,
+    /// dropped because the queue was full, handed back so its request can be failed
+    Shed(T),
+}
+
+/// Bounded queue feeding a background worker
+struct WorkerQueue<T> {
+    tx: SyncSender<T>,
+    policy: QueuePolicy,
+}
+
+impl<T> WorkerQueue<T> {
+    /// A queue shedding jobs once full, see `KafkaNode::with_queue_policy`
+    fn bounded() -> (Self, Receiver<T>) {
+        WorkerQueue::with_capacity(WORKER_QUEUE_CAPACITY, QueuePolicy::Shed)
+    }
+
+    fn with_capacity(capacity: usize, policy: QueuePolicy) -> (Self, Receiver<T>) {
+        let (tx, rx) = sync_channel(capacity);
+        (Self { tx, policy }, rx)
+    }
+
+    fn push(&self, job: T) -> anyhow::Result<Pushed<T>> {
+        match self.policy {
+            QueuePolicy::Block => self
+                .tx
+                .send(job)
+                .map(|()| Pushed::Queued)
+                .map_err(|_| anyhow!("worker queue disconnected")),
+            QueuePolicy::Shed => match self.tx.try_send(job) {
+                Ok(()) => Ok(Pushed::Queued),
+                Err(TrySendError::Full(job)) => {
+                    eprintln!("worker queue full, shedding job");
+                    Ok(Pushed::Shed(job))
+                }
+                Err(TrySendError::Disconnected(_)) => bail!("worker queue disconnected"),
+            },
+        }
+    }
+}
+
+/// How long a worker waits on another partition before trying again
+const REMOTE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How many times a worker calls another partition before failing the client request
+const REMOTE_ATTEMPTS: usize = 3;
+
+/// Calls another partition, retrying requests that time out up to `REMOTE_ATTEMPTS` times.
+/// Only used for requests that are safe to repeat.
+fn call_partition<T>(
+    peer: Peer<Payload>,
+    payload: Payload,
+    extract: impl Fn(&Payload) -> Option<T>,
+) -> anyhow::Result<T> {
+    let peer = peer.with_timeout(REMOTE_TIMEOUT);
+    let mut attempt = 1;
+    loop {
+        match peer.rpc_expect(payload.clone(), &extract) {
+            Err(e) if e.is::<RpcTimeout>() && attempt < REMOTE_ATTEMPTS => attempt += 1,
+            result => return result,
+        }
+    }
+}
+
+/// How long a remote poll result is served from the poll cache
+const POLL_CACHE_TTL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct CachedPoll {
+    cached_at: Instant,
+    msgs: Vec<[usize; 2]>,
 }
 
-impl Sequence {
-    fn get(&self) -> usize {
-        self.shared.fetch_add(1, Ordering::SeqCst)
+impl CachedPoll {
+    fn is_fresh(&self) -> bool {
+        self.cached_at.elapsed() < POLL_CACHE_TTL
     }
 }
 
-fn get_partition(key: &str, nodes: &[String]) -> String {
-    let mut hasher = DefaultHasher::new();
-    key.hash(&mut hasher);
-    let hash = hasher.finish() as usize;
-    nodes[hash % nodes.len()].clone()
+/// Recent results of polling remote logs, by log key and min offset
+#[derive(Debug, Default)]
+struct PollCache {
+    entries: HashMap<(String, usize), CachedPoll>,
+}
+
+impl PollCache {
+    fn get(&self, log_key: &str, min_offset: usize) -> Option<Vec<[usize; 2]>> {
+        let cached = self.entries.get(&(log_key.to_string(), min_offset))?;
+        cached.is_fresh().then(|| cached.msgs.clone())
+    }
+
+    fn insert(&mut self, log_key: String, min_offset: usize, msgs: Vec<[usize; 2]>) {
+        self.entries.retain(|_, cached| cached.is_fresh());
+        let cached = CachedPoll {
+            cached_at: Instant::now(),
+            msgs,
+        };
+        self.entries.insert((log_key, min_offset), cached);
+    }
+
+    /// Drops every cached result for a log
+    fn invalidate(&mut self, log_key: &str) {
+        self.entries.retain(|(key, _), _| key != log_key);
+    }
+}
+
+/// Number of recent client sends remembered to answer retries
+const SEND_DEDUP_CAPACITY: usize = 4096;
+
+/// Where a remembered client send is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendState {
+    /// forwarded to the partition that owns its log, waiting for an offset
+    Forwarding,
+    /// appended at this offset
+    Appended(usize),
+}
+
+/// Offsets assigned to recent client sends, by the client and msg_id of the send.
+/// A client retrying a send that timed out gets the offset its first attempt was
+/// assigned, instead of appending the message again. A send is remembered before it's
+/// forwarded, so a retry arriving while the forward is in flight isn't forwarded too.
+/// Holds at most `SEND_DEDUP_CAPACITY` sends, evicting the least recently used.
+#[derive(Debug, Default)]
+struct SendDedup {
+    sends: HashMap<(String, usize), (SendState, u64)>,
+    // last use -> send, oldest first
+    recency: BTreeMap<u64, (String, usize)>,
+    clock: u64,
+}
+
+impl SendDedup {
+    fn get(&mut self, send: &(String, usize)) -> Option<SendState> {
+        let (state, last_use) = self.sends.get_mut(send)?;
+        self.recency.remove(last_use);
+        self.clock += 1;
+        *last_use = self.clock;
+        self.recency.insert(self.clock, send.clone());
+        Some(*state)
+    }
+
+    fn insert(&mut self, send: (String, usize), state: SendState) {
+        self.clock += 1;
+        if let Some((_, last_use)) = self.sends.insert(send.clone(), (state, self.clock)) {
+            self.recency.remove(&last_use);
+        }
+        self.recency.insert(self.clock, send);
+
+        while self.sends.len() > SEND_DEDUP_CAPACITY {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.sends.remove(&oldest);
+        }
+    }
+
+    /// Forgets a send whose forward failed, so a retry is forwarded again
+    fn remove(&mut self, send: &(String, usize)) {
+        if let Some((_, last_use)) = self.sends.remove(send) {
+            self.recency.remove(&last_use);
+        }
+    }
+}
+
+/// Identifies a client send for deduplication
+fn send_id(msg: &Message<Payload>) -> Option<(String, usize)> {
+    Some((msg.src.clone(), msg.body.msg_id?))
+}
+
+/// The reply to a request whose change couldn't be stored. The change is undone in memory,
+/// but lin-kv may have applied it regardless, so the error is indefinite.
+fn store_failed(e: &anyhow::Error) -> Payload {
+    Payload::error(ErrorCode::Crash, format!("failed to store log: {e:#}"))
+}
+
+/// Which node owns each log, shared with the workers
+#[derive(Clone)]
+struct Partitions {
+    partitioner: Arc<dyn Partitioner>,
+    node_ids: Arc<[String]>,
+}
+
+impl Partitions {
+    fn owner(&self, log_key: &str) -> String {
+        self.partitioner.owner(log_key, &self.node_ids)
+    }
 }
 
 struct PollJob {
@@ -142,111 +378,338 @@ struct SendJob {
     partition: String,
 }
 
+struct CommitOffsetsJob {
+    client_commit: Message<Payload>,
+}
+
 struct ListCommittedOffsetsJob {
     client_list_committed: Message<Payload>,
     offsets: HashMap<String, usize>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct Log {
     commit_offset: usize,
     entries: BTreeMap<usize, usize>,
 }
 
-struct KafkaNode {
-    sequence: Sequence,
+/// What's stored at `log-<log key>`, the entries are stored separately
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct LogHeader {
+    commit_offset: usize,
+    /// number of entries, stored at offsets `0..len`
+    len: usize,
+}
+
+impl From<&Log> for LogHeader {
+    fn from(log: &Log) -> Self {
+        LogHeader {
+            commit_offset: log.commit_offset,
+            len: log.entries.len(),
+        }
+    }
+}
 
+/// Persists the logs a node owns to lin-kv.
+/// Each entry is stored at `log-<log key>-<offset>` as it's appended, and each log's
+/// header at `log-<log key>`. The keys of every log the node has stored are listed at
+/// `logs-<node id>`. An entry is written before the header that counts it, so a
+/// failed append leaves the stored log as it was.
+struct LogStore {
+    kv: KvClient<Payload>,
+    index_key: String,
+    stored: HashSet<String>,
+}
+
+impl LogStore {
+    fn new(network: Network<Payload>, node_id: &str) -> Self {
+        Self {
+            kv: KvClient::new(network, node_id, Service::LinKv).with_timeout(STORE_TIMEOUT),
+            index_key: format!("logs-{node_id}"),
+            stored: HashSet::new(),
+        }
+    }
+
+    /// Reads back every log the node has stored, none if it is starting fresh
+    fn restore(&mut self) -> anyhow::Result<HashMap<String, Log>> {
+        let keys: Vec<String> = match self.kv.read(&self.index_key) {
+            Ok(keys) => keys,
+            Err(e) if e.downcast_ref::<ErrorCode>() == Some(&ErrorCode::KeyDoesNotExist) => {
+                return Ok(HashMap::new());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut logs = HashMap::new();
+        for key in keys {
+            let header: LogHeader = self.kv.read(format!("log-{key}"))?;
+            let offsets: Vec<String> = (0..header.len)
+                .map(|offset| format!("log-{key}-{offset}"))
+                .collect();
+            let entries = self
+                .kv
+                .read_many(&offsets)
+                .into_iter()
+                .enumerate()
+                .map(|(offset, entry)| Ok((offset, entry?)))
+                .collect::<anyhow::Result<_>>()?;
+
+            self.stored.insert(key.clone());
+            let log = Log {
+                commit_offset: header.commit_offset,
+                entries,
+            };
+            logs.insert(key, log);
+        }
+
+        Ok(logs)
+    }
+
+    /// Stores the entry at `offset`, then the header counting it
+    fn append(&mut self, log_key: &str, log: &Log, offset: usize) -> Try {
+        self.kv
+            .write(format!("log-{log_key}-{offset}"), log.entries[&offset])?;
+        self.save(log_key, log)
+    }
+
+    /// Stores the log's header, such as after its commit offset changed
+    fn save(&mut self, log_key: &str, log: &Log) -> Try {
+        self.kv
+            .write(format!("log-{log_key}"), LogHeader::from(log))?;
+        if self.stored.insert(log_key.to_string()) {
+            if let Err(e) = self.kv.write(&self.index_key, &self.stored) {
+                // the index is written again with the log's next save
+                self.stored.remove(log_key);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct KafkaNode {
     node_id: String,
-    node_ids: Vec<String>,
+    partitions: Partitions,
     network: Network<Payload>,
     logs: HashMap<String, Log>,
-
-    poll_worker: Sender<PollJob>,
-    send_worker: Sender<SendJob>,
-    list_committed_worker: Sender<ListCommittedOffsetsJob>,
+    poll_cache: Arc<Mutex<PollCache>>,
+    sends: Arc<Mutex<SendDedup>>,
+    /// where logs are persisted, if the node is durable
+    store: Option<LogStore>,
+    restored: bool,
+
+    poll_worker: WorkerQueue<PollJob>,
+    send_worker: WorkerQueue<SendJob>,
+    commit_worker: WorkerQueue<CommitOffsetsJob>,
+    list_committed_worker: WorkerQueue<ListCommittedOffsetsJob>,
 }
 
 impl Node<Payload> for KafkaNode {
     fn from_init(network: Network<Payload>, node_id: String, node_ids: Vec<String>) -> Self {
-        let sequence = Sequence::default();
+        let durable = env::var_os(DURABLE_VAR).is_some();
+        let ring = Box::new(Ring::new(&node_ids));
+        KafkaNode::new(network, node_id, node_ids, ring, durable)
+            .with_queue_policy(QueuePolicy::from_env())
+    }
+
+    fn handle_message(&mut self, msg: Message<Payload>) -> Try {
+        // the next message tries again, until then nothing can be served
+        if let Err(e) = self.restore() {
+            eprintln!("failed to restore logs: {e:#}");
+            let error = Payload::error(ErrorCode::TemporarilyUnavailable, format!("{e:#}"));
+            return self.network.reply(msg, error);
+        }
+
+        match &msg.body.payload {
+            Payload::Send { .. } => self.handle_send(msg),
+            Payload::Poll { .. } => self.handle_poll(msg),
+            Payload::CommitOffsets { .. } => self.handle_commit_offsets(msg),
+            Payload::ListCommittedOffsets { .. } => self.handle_list_committed_offsets(msg),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl KafkaNode {
+    /// What handlers do when a background worker's queue is full, shedding jobs by default
+    fn with_queue_policy(mut self, policy: QueuePolicy) -> Self {
+        self.poll_worker.policy = policy;
+        self.send_worker.policy = policy;
+        self.commit_worker.policy = policy;
+        self.list_committed_worker.policy = policy;
+        self
+    }
+
+    fn new(
+        network: Network<Payload>,
+        node_id: String,
+        node_ids: Vec<String>,
+        partitioner: Box<dyn Partitioner>,
+        durable: bool,
+    ) -> Self {
+        let partitions = Partitions {
+            partitioner: partitioner.into(),
+            node_ids: node_ids.into(),
+        };
+        let poll_cache = Arc::<Mutex<PollCache>>::default();
+        let sends = Arc::<Mutex<SendDedup>>::default();
 
         let poll_worker = KafkaNode::poll_worker(
-            sequence.clone(),
             node_id.clone(),
-            node_ids.clone(),
+            partitions.clone(),
             network.clone(),
+            poll_cache.clone(),
         );
 
         let send_worker = KafkaNode::send_worker(
-            sequence.clone(),
             node_id.clone(),
-            node_ids.clone(),
             network.clone(),
+            poll_cache.clone(),
+            sends.clone(),
         );
 
-        let list_committed_worker = KafkaNode::list_committed_worker(
-            sequence.clone(),
-            node_id.clone(),
-            node_ids.clone(),
-            network.clone(),
-        );
+        let commit_worker =
+            KafkaNode::commit_worker(node_id.clone(), partitions.clone(), network.clone());
+
+        let list_committed_worker =
+            KafkaNode::list_committed_worker(node_id.clone(), partitions.clone(), network.clone());
+
+        let store = durable.then(|| LogStore::new(network.clone(), &node_id));
 
         Self {
-            sequence,
             node_id,
-            node_ids,
+            partitions,
             network,
             logs: Default::default(),
+            poll_cache,
+            sends,
+            store,
+            restored: false,
 
             poll_worker,
             send_worker,
+            commit_worker,
             list_committed_worker,
         }
     }
 
-    fn handle_message(&mut self, msg: Message<Payload>) -> Try {
-        match &msg.body.payload {
-            Payload::Send { .. } => self.handle_send(msg),
-            Payload::Poll { .. } => self.handle_poll(msg),
-            Payload::CommitOffsets { .. } => self.handle_commit_offsets(msg),
-            Payload::ListCommittedOffsets { .. } => self.handle_list_committed_offsets(msg),
-            _ => Ok(()),
+    /// Restores stored logs before the first message is handled.
+    /// This can't happen in from_init, the runtime doesn't deliver
+    /// responses from lin-kv until init is done.
+    fn restore(&mut self) -> Try {
+        if self.restored {
+            return Ok(());
         }
+
+        if let Some(store) = &mut self.store {
+            self.logs = store.restore()?;
+            eprintln!("restored {} logs", self.logs.len());
+        }
+
+        self.restored = true;
+        Ok(())
+    }
+
+    /// Persists a log's header, if the node is durable
+    fn save(&mut self, log_key: &str) -> Try {
+        let (Some(store), Some(log)) = (&mut self.store, self.logs.get(log_key)) else {
+            return Ok(());
+        };
+
+        store.save(log_key, log)
+    }
+
+    /// Persists the entry appended to a log at `offset`, if the node is durable
+    fn save_append(&mut self, log_key: &str, offset: usize) -> Try {
+        let (Some(store), Some(log)) = (&mut self.store, self.logs.get(log_key)) else {
+            return Ok(());
+        };
+
+        store.append(log_key, log, offset)
+    }
+
+    /// Fails a request whose job was shed by a full worker queue, the client retries it
+    fn shed(&self, request: Message<Payload>) -> Try {
+        let error = Payload::error(
+            ErrorCode::TemporarilyUnavailable,
+            "worker queue full".into(),
+        );
+        self.network.reply(request, error)
     }
-}
 
-impl KafkaNode {
     fn handle_send(&mut self, msg: Message<Payload>) -> Try {
         let Payload::Send { key, msg: message } = &msg.body.payload else {
             bail!("expected send");
         };
 
-        let partition = get_partition(key, &self.node_ids);
+        // answer a retried send with the offset it was already assigned
+        let send_id = send_id(&msg);
+        if let Some(send_id) = &send_id {
+            match self.sends.lock().get(send_id) {
+                Some(SendState::Appended(offset)) => {
+                    eprintln!("duplicate send {send_id:?}, already at offset {offset}");
+                    return self.network.reply(msg, Payload::SendOk { offset });
+                }
+                // the client retries again once this one times out, by then we know the offset
+                Some(SendState::Forwarding) => {
+                    eprintln!("duplicate send {send_id:?}, still being forwarded");
+                    return Ok(());
+                }
+                None => {}
+            }
+        }
+
+        let partition = self.partitions.owner(key);
 
         // send to remote partition
         if partition != self.node_id {
             eprintln!("send for log {key} owned by remote partition {partition}");
             // we should never get a request belonging to a different node
             // from a server, only a client. else our hashing is busted.
-            assert!(msg.src.starts_with('c'));
+            assert!(msg.src_id().is_client());
+
+            if let Some(send_id) = &send_id {
+                self.sends
+                    .lock()
+                    .insert(send_id.clone(), SendState::Forwarding);
+            }
 
             let job = SendJob {
                 client_send: msg,
                 partition,
             };
 
-            return self
-                .send_worker
-                .send(job)
-                .map_err(|_| anyhow!("failed to run poll job"));
+            // forget a send that never reached the worker, so the client's retry is forwarded
+            let pushed = self.send_worker.push(job);
+            if let (false, Some(send_id)) = (matches!(pushed, Ok(Pushed::Queued)), &send_id) {
+                self.sends.lock().remove(send_id);
+            }
+            return match pushed? {
+                Pushed::Queued => Ok(()),
+                Pushed::Shed(job) => self.shed(job.client_send),
+            };
         }
 
-        // apply locally
+        // apply locally, we own this log so nothing should be cached for it
+        self.poll_cache.lock().invalidate(key);
         let log = self.logs.entry(key.clone()).or_default();
         let offset = log.entries.keys().max().map(|i| i + 1).unwrap_or(0);
         log.entries.insert(offset, *message);
-        let reply = msg.into_reply(Payload::SendOk { offset });
-        self.network.send(reply)
+        if let Err(e) = self.save_append(key, offset) {
+            eprintln!("failed to store send to log {key}: {e:#}");
+            // not served until it's stored, the client retries it
+            if let Some(log) = self.logs.get_mut(key) {
+                log.entries.remove(&offset);
+            }
+            return self.network.reply(msg, store_failed(&e));
+        }
+        if let Some(send_id) = send_id {
+            self.sends
+                .lock()
+                .insert(send_id, SendState::Appended(offset));
+        }
+        self.network.reply(msg, Payload::SendOk { offset })
     }
 
     fn handle_poll(&mut self, msg: Message<Payload>) -> Try {
@@ -257,7 +720,7 @@ impl KafkaNode {
         let mut remote_logs = false;
         let mut msgs = HashMap::<String, Vec<[usize; 2]>>::new();
         for (log_key, min_offset) in offsets {
-            let partition = get_partition(log_key, &self.node_ids);
+            let partition = self.partitions.owner(log_key);
             if partition != self.node_id {
                 eprintln!("poll includes remote log {log_key} owned by partition {partition}");
                 remote_logs = true;
@@ -280,14 +743,13 @@ impl KafkaNode {
                 msgs,
             };
 
-            Ok(self
-                .poll_worker
-                .send(job)
-                .map_err(|_| anyhow!("failed to run poll job"))?)
+            match self.poll_worker.push(job)? {
+                Pushed::Queued => Ok(()),
+                Pushed::Shed(job) => self.shed(job.client_poll),
+            }
         } else {
             // case for when we only have local logs to serve
-            let reply = msg.into_reply(Payload::PollOk { msgs });
-            self.network.send(reply)
+            self.network.reply(msg, Payload::PollOk { msgs })
         }
     }
 
@@ -296,30 +758,35 @@ impl KafkaNode {
             bail!("expected commit_offsets");
         };
 
+        let mut remote_commits = false;
         for (log_key, commit_offset) in offsets {
-            let partition = get_partition(log_key, &self.node_ids);
+            let partition = self.partitions.owner(log_key);
             if partition == self.node_id {
-                self.logs.entry(log_key.clone()).or_default().commit_offset = *commit_offset;
+                let log = self.logs.entry(log_key.clone()).or_default();
+                let committed = mem::replace(&mut log.commit_offset, *commit_offset);
+                if let Err(e) = self.save(log_key) {
+                    eprintln!("failed to store commit of log {log_key}: {e:#}");
+                    if let Some(log) = self.logs.get_mut(log_key) {
+                        log.commit_offset = committed;
+                    }
+                    return self.network.reply(msg, store_failed(&e));
+                }
             } else {
                 eprintln!("commit for log {log_key} owned by partition {partition}");
-                let remote_offset = HashMap::from([(log_key.clone(), *commit_offset)]);
-                let payload = Payload::CommitOffsets {
-                    offsets: remote_offset,
-                };
-                let remote_commit = Message::new(
-                    self.node_id.clone(),
-                    partition,
-                    BodyBuilder::new(payload)
-                        .msg_id(self.sequence.get())
-                        .build(),
-                );
-
-                self.network.send(remote_commit)?;
+                remote_commits = true;
             }
         }
 
-        let reply = msg.into_reply(Payload::CommitOffsetsOk);
-        self.network.send(reply)
+        if remote_commits {
+            // only acknowledge the commit once every remote partition has
+            let job = CommitOffsetsJob { client_commit: msg };
+            match self.commit_worker.push(job)? {
+                Pushed::Queued => Ok(()),
+                Pushed::Shed(job) => self.shed(job.client_commit),
+            }
+        } else {
+            self.network.reply(msg, Payload::CommitOffsetsOk)
+        }
     }
 
     fn handle_list_committed_offsets(&mut self, msg: Message<Payload>) -> Try {
@@ -330,7 +797,7 @@ impl KafkaNode {
         let mut remote_commits = false;
         let mut offsets = HashMap::new();
         for key in keys.clone() {
-            let partition = get_partition(&key, &self.node_ids);
+            let partition = self.partitions.owner(&key);
             if partition != self.node_id {
                 eprintln!("list committed includes log {key} owned by partition {partition}");
                 remote_commits = true;
@@ -346,23 +813,23 @@ impl KafkaNode {
                 offsets,
             };
 
-            Ok(self
-                .list_committed_worker
-                .send(job)
-                .map_err(|_| anyhow!("failed to run list committed job"))?)
+            match self.list_committed_worker.push(job)? {
+                Pushed::Queued => Ok(()),
+                Pushed::Shed(job) => self.shed(job.client_list_committed),
+            }
         } else {
-            let reply = msg.into_reply(Payload::ListCommittedOffsetsOk { offsets });
-            self.network.send(reply)
+            self.network
+                .reply(msg, Payload::ListCommittedOffsetsOk { offsets })
         }
     }
 
     fn poll_worker(
-        seq: Sequence,
         node_id: String,
-        node_ids: Vec<String>,
+        partitions: Partitions,
         network: Network<Payload>,
-    ) -> Sender<PollJob> {
-        let (tx, rx) = channel();
+        cache: Arc<Mutex<PollCache>>,
+    ) -> WorkerQueue<PollJob> {
+        let (tx, rx) = WorkerQueue::bounded();
 
         thread::spawn(move || {
             for job in rx {
@@ -375,37 +842,58 @@ impl KafkaNode {
                     continue;
                 };
 
+                // group uncached remote logs by the partition that owns them,
+                // so each partition is polled once for all of its logs
+                let mut remote_offsets = BTreeMap::<String, HashMap<String, usize>>::new();
                 for (log_key, offset) in offsets {
-                    let partition = get_partition(log_key, &node_ids);
+                    let partition = partitions.owner(log_key);
                     if partition == node_id {
                         // we should already have local logs
                         continue;
                     }
 
-                    let payload = Payload::Poll {
-                        offsets: HashMap::from([(log_key.clone(), *offset)]),
-                    };
-                    let body = BodyBuilder::new(payload).msg_id(seq.get()).build();
-                    let remote_poll = Message::new(&node_id, partition, body);
-                    let Ok(result) = network.rpc(remote_poll) else {
-                        eprintln!("failed to send remote poll rpc");
-                        continue;
-                    };
-
-                    let result = result.recv().unwrap();
-                    let Payload::PollOk { msgs: remote_msgs } = result.body.payload else {
-                        eprintln!("expected poll_ok");
+                    if let Some(cached) = cache.lock().get(log_key, *offset) {
+                        msgs.insert(log_key.clone(), cached);
                         continue;
-                    };
-
-                    for (remote_key, remote_offsets) in remote_msgs {
-                        msgs.insert(remote_key, remote_offsets);
                     }
+
+                    remote_offsets
+                        .entry(partition)
+                        .or_default()
+                        .insert(log_key.clone(), *offset);
                 }
 
-                // send the merged response
-                let reply = client_poll.into_reply(Payload::PollOk { msgs });
-                network.send(reply).unwrap();
+                let polled = remote_offsets
+                    .into_iter()
+                    .try_for_each(|(partition, offsets)| {
+                        let peer = network.peer(&node_id, &partition);
+                        let payload = Payload::Poll {
+                            offsets: offsets.clone(),
+                        };
+                        let remote_msgs = call_partition(peer, payload, |payload| match payload {
+                            Payload::PollOk { msgs } => Some(msgs.clone()),
+                            _ => None,
+                        })?;
+
+                        let mut cache = cache.lock();
+                        for (remote_key, remote_entries) in remote_msgs {
+                            if let Some(offset) = offsets.get(&remote_key) {
+                                cache.insert(remote_key.clone(), *offset, remote_entries.clone());
+                            }
+                            msgs.insert(remote_key, remote_entries);
+                        }
+                        anyhow::Ok(())
+                    });
+
+                // send the merged response, or fail the request rather than omit messages
+                let payload = match polled {
+                    Ok(()) => Payload::PollOk { msgs },
+                    Err(e) => {
+                        eprintln!("failed remote poll: {e:#}");
+                        Payload::error(ErrorCode::Timeout, format!("{e:#}"))
+                    }
+                };
+                network.reply(client_poll, payload).unwrap();
             }
         });
 
@@ -413,12 +901,12 @@ impl KafkaNode {
     }
 
     fn send_worker(
-        seq: Sequence,
         node_id: String,
-        _: Vec<String>,
         network: Network<Payload>,
-    ) -> Sender<SendJob> {
-        let (tx, rx) = channel();
+        cache: Arc<Mutex<PollCache>>,
+        sends: Arc<Mutex<SendDedup>>,
+    ) -> WorkerQueue<SendJob> {
+        let (tx, rx) = WorkerQueue::bounded();
         thread::spawn(move || {
             for job in rx {
                 let SendJob {
@@ -426,29 +914,94 @@ impl KafkaNode {
                     partition,
                 } = job;
 
-                let mut fwd = client_send.clone();
+                let fwd = client_send
+                    .clone()
+                    .forward(&node_id, partition, network.next_id());
+
+                // not retried here, a send isn't safe to repeat.
+                // a lost forward fails like any other, the client's retry is forwarded again
+                let send_id = send_id(&client_send);
+                let forwarded = network
+                    .rpc_timeout(fwd, REMOTE_TIMEOUT)
+                    .map(|result| result.body.payload);
+                let offset = match forwarded {
+                    Ok(Payload::SendOk { offset }) => offset,
+                    failed => {
+                        eprintln!("failed to forward send to remote partition: {failed:?}");
+                        if let Some(send_id) = &send_id {
+                            sends.lock().remove(send_id);
+                        }
+                        continue;
+                    }
+                };
 
-                fwd.src = node_id.clone();
-                fwd.dest = partition;
-                fwd.body.msg_id = Some(seq.get());
+                if let Some(send_id) = send_id {
+                    sends.lock().insert(send_id, SendState::Appended(offset));
+                }
 
-                let Ok(result) = network.rpc(fwd) else {
-                    eprintln!("failed to forward send to remote partition");
-                    continue;
-                };
+                // polls of the log would otherwise miss the message until the cache expires
+                if let Payload::Send { key, .. } = &client_send.body.payload {
+                    cache.lock().invalidate(key);
+                }
 
-                let Ok(result) = result.recv() else {
-                    eprintln!("failed to recv forward send to remote partition");
-                    continue;
-                };
+                network
+                    .reply(client_send, Payload::SendOk { offset })
+                    .unwrap();
+            }
+        });
+
+        tx
+    }
 
-                let Payload::SendOk { offset } = result.body.payload else {
-                    eprintln!("expected send_ok");
+    fn commit_worker(
+        node_id: String,
+        partitions: Partitions,
+        network: Network<Payload>,
+    ) -> WorkerQueue<CommitOffsetsJob> {
+        let (tx, rx) = WorkerQueue::bounded();
+
+        thread::spawn(move || {
+            for job in rx {
+                let CommitOffsetsJob { client_commit } = job;
+                let Payload::CommitOffsets { offsets } = &client_commit.body.payload else {
+                    eprintln!("expected commit_offsets");
                     continue;
                 };
 
-                let reply = client_send.into_reply(Payload::SendOk { offset });
-                network.send(reply).unwrap();
+                let mut remote_offsets = BTreeMap::<String, HashMap<String, usize>>::new();
+                for (log_key, offset) in offsets {
+                    let partition = partitions.owner(log_key);
+                    if partition == node_id {
+                        // we already committed local logs
+                        continue;
+                    }
+
+                    remote_offsets
+                        .entry(partition)
+                        .or_default()
+                        .insert(log_key.clone(), *offset);
+                }
+
+                let committed = remote_offsets.into_iter().all(|(partition, offsets)| {
+                    let peer = network.peer(&node_id, &partition);
+                    let ack = call_partition(peer, Payload::CommitOffsets { offsets }, |payload| {
+                        matches!(payload, Payload::CommitOffsetsOk).then_some(())
+                    });
+
+                    if let Err(e) = &ack {
+                        eprintln!("failed remote commit to {partition}: {e:#}");
+                    }
+                    ack.is_ok()
+                });
+
+                // fail the client request if a partition didn't acknowledge,
+                // it will retry rather than believe a lost commit succeeded
+                let payload = if committed {
+                    Payload::CommitOffsetsOk
+                } else {
+                    Payload::error(ErrorCode::Timeout, "a partition didn't acknowledge".into())
+                };
+                network.reply(client_commit, payload).unwrap();
             }
         });
 
@@ -456,12 +1009,11 @@ impl KafkaNode {
     }
 
     fn list_committed_worker(
-        seq: Sequence,
         node_id: String,
-        node_ids: Vec<String>,
+        partitions: Partitions,
         network: Network<Payload>,
-    ) -> Sender<ListCommittedOffsetsJob> {
-        let (tx, rx) = channel();
+    ) -> WorkerQueue<ListCommittedOffsetsJob> {
+        let (tx, rx) = WorkerQueue::bounded();
 
         thread::spawn(move || {
             for job in rx {
@@ -475,40 +1027,38 @@ impl KafkaNode {
                     continue;
                 };
 
-                for log_key in keys {
-                    let partition = get_partition(log_key, &node_ids);
+                let listed = keys.iter().try_for_each(|log_key| {
+                    let partition = partitions.owner(log_key);
                     if partition == node_id {
                         // we should already have local committs
-                        continue;
+                        return Ok(());
                     }
 
-                    let payload = Payload::ListCommittedOffsets {
-                        keys: vec![log_key.clone()],
-                    };
-
-                    let body = BodyBuilder::new(payload).msg_id(seq.get()).build();
-                    let remote_list_committed = Message::new(&node_id, partition, body);
-
-                    let Ok(result) = network.rpc(remote_list_committed) else {
-                        eprintln!("failed to send remote list committed rpc");
-                        continue;
-                    };
-
-                    let result = result.recv().unwrap();
-                    let Payload::ListCommittedOffsetsOk { offsets : remote_offsets } = result.body.payload else {
-                        eprintln!("expected ListCommittedOffsetsOk");
-                        continue;
-                    };
-
-                    for (remote_key, remote_offset) in remote_offsets {
-                        offsets.insert(remote_key, remote_offset);
+                    let peer = network.peer(&node_id, partition);
+                    let remote_offsets = call_partition(
+                        peer,
+                        Payload::ListCommittedOffsets {
+                            keys: vec![log_key.clone()],
+                        },
+                        |payload| match payload {
+                            Payload::ListCommittedOffsetsOk { offsets } => Some(offsets.clone()),
+                            _ => None,
+                        },
+                    )?;
+
+                    offsets.extend(remote_offsets);
+                    anyhow::Ok(())
+                });
+
+                // send the merged response, or fail the request rather than omit offsets
+                let payload = match listed {
+                    Ok(()) => Payload::ListCommittedOffsetsOk { offsets },
+                    Err(e) => {
+                        eprintln!("failed remote list committed: {e:#}");
+                        Payload::error(ErrorCode::Timeout, format!("{e:#}"))
                     }
-                }
-
-                // send the merged response
-                let reply =
-                    client_list_committed.into_reply(Payload::ListCommittedOffsetsOk { offsets });
-                network.send(reply).unwrap();
+                };
+                network.reply(client_list_committed, payload).unwrap();
             }
         });
 
@@ -519,3 +1069,644 @@ impl KafkaNode {
 fn main() -> Try {
     Runtime::<Payload, KafkaNode>::run()
 }
+
+#[cfg(test)]
+mod tests {
+    use maelbreaker::{partition::RangePartitioner, testing::MockNetwork, types::BodyBuilder};
+
+    use super::*;
+
+    fn node_ids(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("n{i}")).collect()
+    }
+
+    /// The first `count` log keys owned by `owner`
+    fn keys_owned_by(owner: &str, node_ids: &[String], count: usize) -> Vec<String> {
+        (0..)
+            .map(|i| format!("k{i}"))
+            .filter(|key| Ring::new(node_ids).owner(key) == owner)
+            .take(count)
+            .collect()
+    }
+
+    /// Waits for background workers to send `count` messages in total
+    fn wait_for_sent(mock: &MockNetwork<Payload>, count: usize) -> Vec<Message<Payload>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let sent = mock.sent();
+            if sent.len() >= count || Instant::now() > deadline {
+                return sent;
+            }
+
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn client_poll(msg_id: usize, offsets: HashMap<String, usize>) -> Message<Payload> {
+        let body = BodyBuilder::new(Payload::Poll { offsets })
+            .msg_id(msg_id)
+            .build();
+        Message::new("c1", "n0", body)
+    }
+
+    #[test]
+    fn test_cached_remote_poll() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+        let msgs = HashMap::from([(key.clone(), vec![[0, 5]])]);
+        mock.respond(0, Payload::PollOk { msgs: msgs.clone() });
+
+        let offsets = HashMap::from([(key, 0)]);
+        node.handle_message(client_poll(1, offsets.clone()))?;
+        assert_eq!(2, wait_for_sent(&mock, 2).len());
+
+        node.handle_message(client_poll(2, offsets))?;
+        let sent = wait_for_sent(&mock, 3);
+
+        let rpcs = sent.iter().filter(|msg| msg.dest == "n1").count();
+        assert_eq!(1, rpcs);
+        assert_eq!(Payload::PollOk { msgs }, sent[2].body.payload);
+        assert_eq!(Some(2), sent[2].body.in_reply_to);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarded_send_invalidates_poll_cache() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+        let msgs = HashMap::from([(key.clone(), vec![[0, 5]])]);
+        mock.respond(0, Payload::PollOk { msgs });
+        let offsets = HashMap::from([(key.clone(), 0)]);
+        node.handle_message(client_poll(1, offsets.clone()))?;
+        wait_for_sent(&mock, 2);
+
+        // each reply takes a msg_id, so the send is forwarded as 2 and the next poll as 4
+        mock.respond(2, Payload::SendOk { offset: 1 });
+        node.handle_message(client_send(2, &key))?;
+        wait_for_sent(&mock, 4);
+
+        let msgs = HashMap::from([(key.clone(), vec![[0, 5], [1, 10]])]);
+        mock.respond(4, Payload::PollOk { msgs: msgs.clone() });
+        node.handle_message(client_poll(3, offsets))?;
+        let sent = wait_for_sent(&mock, 6);
+
+        let polls = sent
+            .iter()
+            .filter(|msg| msg.dest == "n1" && matches!(msg.body.payload, Payload::Poll { .. }))
+            .count();
+        assert_eq!(2, polls);
+        assert_eq!(Payload::PollOk { msgs }, sent[5].body.payload);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_remote_poll() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+
+        // n1 never answers, the poll fails rather than leaving its log out
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+        node.handle_message(client_poll(1, HashMap::from([(key, 0)])))?;
+
+        let sent = wait_for_sent(&mock, REMOTE_ATTEMPTS + 1);
+        assert!(sent[..REMOTE_ATTEMPTS].iter().all(|poll| poll.dest == "n1"));
+        let reply = &sent[REMOTE_ATTEMPTS];
+        assert_eq!(Some(1), reply.body.in_reply_to);
+        let Payload::Error(error) = &reply.body.payload else {
+            panic!("expected an error, got {:?}", reply.body.payload);
+        };
+        assert_eq!(usize::from(ErrorCode::Timeout), error.code);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_remote_poll() -> Try {
+        let mock = MockNetwork::new();
+        let node_ids = node_ids(3);
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids.clone());
+
+        // partitions are polled in order, n1 then n2
+        let mut offsets = HashMap::new();
+        for (msg_id, partition) in ["n1", "n2"].into_iter().enumerate() {
+            let keys = keys_owned_by(partition, &node_ids, 2);
+            let msgs = keys.iter().map(|key| (key.clone(), vec![[3, 7]])).collect();
+            mock.respond(msg_id, Payload::PollOk { msgs });
+            offsets.extend(keys.into_iter().map(|key| (key, 3)));
+        }
+
+        node.handle_message(client_poll(1, offsets.clone()))?;
+        let sent = wait_for_sent(&mock, 3);
+        assert_eq!(3, sent.len());
+
+        for (poll, partition) in sent.iter().zip(["n1", "n2"]) {
+            assert_eq!(partition, poll.dest);
+            let Payload::Poll { offsets } = &poll.body.payload else {
+                panic!("expected poll");
+            };
+            assert_eq!(2, offsets.len());
+        }
+
+        let Payload::PollOk { msgs } = &sent[2].body.payload else {
+            panic!("expected poll_ok");
+        };
+        assert_eq!(4, msgs.len());
+        assert!(offsets.keys().all(|key| msgs[key] == vec![[3, 7]]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_commit_waits_for_ack() -> Try {
+        let mock = MockNetwork::new();
+        let network = mock.network();
+        let mut node = KafkaNode::from_init(network.clone(), "n0".into(), node_ids(2));
+
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+        let body = BodyBuilder::new(Payload::CommitOffsets {
+            offsets: HashMap::from([(key, 4)]),
+        })
+        .msg_id(1)
+        .build();
+        node.handle_message(Message::new("c1", "n0", body))?;
+
+        let forward = wait_for_sent(&mock, 1).remove(0);
+        assert_eq!("n1", forward.dest);
+
+        // no reply to the client until n1 acknowledges the commit
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(1, mock.sent().len());
+
+        assert!(network
+            .check_callback(forward.into_reply(Payload::CommitOffsetsOk))
+            .is_none());
+        let sent = wait_for_sent(&mock, 2);
+        assert_eq!("c1", sent[1].dest);
+        assert_eq!(Some(1), sent[1].body.in_reply_to);
+        assert_eq!(Payload::CommitOffsetsOk, sent[1].body.payload);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_commit_fails_after_retries() -> Try {
+        // n1 never responds
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+        let body = BodyBuilder::new(Payload::CommitOffsets {
+            offsets: HashMap::from([(key, 4)]),
+        })
+        .msg_id(1)
+        .build();
+        node.handle_message(Message::new("c1", "n0", body))?;
+
+        let sent = wait_for_sent(&mock, REMOTE_ATTEMPTS + 1);
+        let forwards = &sent[..REMOTE_ATTEMPTS];
+        assert!(forwards.iter().all(|forward| forward.dest == "n1"));
+
+        let reply = &sent[REMOTE_ATTEMPTS];
+        assert_eq!("c1", reply.dest);
+        assert_eq!(Some(1), reply.body.in_reply_to);
+        let Payload::Error(error) = &reply.body.payload else {
+            panic!("expected an error, got {:?}", reply.body.payload);
+        };
+        assert_eq!(usize::from(ErrorCode::Timeout), error.code);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_durable_logs() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::new(
+            mock.network(),
+            "n0".into(),
+            node_ids(2),
+            Box::new(Ring::new(&node_ids(2))),
+            true,
+        );
+
+        let key = keys_owned_by("n0", &node_ids(2), 1).remove(0);
+        mock.respond(
+            0,
+            Payload::ReadOk {
+                value: serde_json::json!([key]),
+            },
+        );
+        mock.respond(
+            1,
+            Payload::ReadOk {
+                value: serde_json::json!({"commit_offset": 1, "len": 2}),
+            },
+        );
+        mock.respond(2, Payload::ReadOk { value: 5.into() });
+        mock.respond(3, Payload::ReadOk { value: 6.into() });
+
+        node.handle_message(client_poll(1, HashMap::from([(key.clone(), 0)])))?;
+        let sent = wait_for_sent(&mock, 5);
+
+        assert_eq!(
+            Payload::Read {
+                key: "logs-n0".into()
+            },
+            sent[0].body.payload
+        );
+        assert_eq!(
+            Payload::Read {
+                key: format!("log-{key}")
+            },
+            sent[1].body.payload
+        );
+        assert_eq!(
+            Payload::Read {
+                key: format!("log-{key}-1")
+            },
+            sent[3].body.payload
+        );
+        assert_eq!(
+            Payload::PollOk {
+                msgs: HashMap::from([(key.clone(), vec![[0, 5], [1, 6]])])
+            },
+            sent[4].body.payload
+        );
+        assert_eq!(1, node.logs[&key].commit_offset);
+        Ok(())
+    }
+
+    #[test]
+    fn test_durable_append_writes_entry() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::new(
+            mock.network(),
+            "n0".into(),
+            node_ids(2),
+            Box::new(Ring::new(&node_ids(2))),
+            true,
+        );
+
+        // a restored log with one entry
+        let key = keys_owned_by("n0", &node_ids(2), 1).remove(0);
+        let restored = [
+            serde_json::json!([key]),
+            serde_json::json!({"commit_offset": 0, "len": 1}),
+            serde_json::json!(5),
+        ];
+        for (msg_id, value) in restored.into_iter().enumerate() {
+            mock.respond(msg_id, Payload::ReadOk { value });
+        }
+        for msg_id in 3..5 {
+            mock.respond(msg_id, Payload::WriteOk);
+        }
+
+        node.handle_message(client_send(1, &key))?;
+
+        // only the new entry and the header are written, not the whole log
+        let writes: Vec<Payload> = mock
+            .sent()
+            .into_iter()
+            .map(|msg| msg.body.payload)
+            .filter(|payload| matches!(payload, Payload::Write { .. }))
+            .collect();
+        assert_eq!(
+            vec![
+                Payload::Write {
+                    key: format!("log-{key}-1"),
+                    value: 10.into(),
+                },
+                Payload::Write {
+                    key: format!("log-{key}"),
+                    value: serde_json::json!({"commit_offset": 0, "len": 2}),
+                },
+            ],
+            writes
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_restore_is_retried() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::new(
+            mock.network(),
+            "n0".into(),
+            node_ids(2),
+            Box::new(Ring::new(&node_ids(2))),
+            true,
+        );
+
+        let key = keys_owned_by("n0", &node_ids(2), 1).remove(0);
+        let offsets = HashMap::from([(key, 0)]);
+        mock.respond(0, Payload::error(ErrorCode::Timeout, "slow".into()));
+        node.handle_message(client_poll(1, offsets.clone()))?;
+
+        let sent = mock.sent();
+        let Payload::Error(error) = &sent[1].body.payload else {
+            panic!("expected an error, got {:?}", sent[1].body.payload);
+        };
+        assert_eq!(usize::from(ErrorCode::TemporarilyUnavailable), error.code);
+        assert!(!node.restored);
+
+        // a fresh node has nothing stored
+        mock.respond(2, Payload::error(ErrorCode::KeyDoesNotExist, "".into()));
+        node.handle_message(client_poll(2, offsets))?;
+        assert!(node.restored);
+        assert!(matches!(
+            mock.sent()[3].body.payload,
+            Payload::PollOk { .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_append_is_rolled_back() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::new(
+            mock.network(),
+            "n0".into(),
+            node_ids(2),
+            Box::new(Ring::new(&node_ids(2))),
+            true,
+        );
+
+        let key = keys_owned_by("n0", &node_ids(2), 1).remove(0);
+        mock.respond(0, Payload::error(ErrorCode::KeyDoesNotExist, "".into()));
+        mock.respond(1, Payload::error(ErrorCode::Crash, "down".into()));
+        node.handle_message(client_send(1, &key))?;
+
+        let sent = mock.sent();
+        let Payload::Error(error) = &sent[2].body.payload else {
+            panic!("expected an error, got {:?}", sent[2].body.payload);
+        };
+        assert_eq!(usize::from(ErrorCode::Crash), error.code);
+        assert!(node.logs[&key].entries.is_empty());
+
+        // the retry is appended at the same offset once lin-kv is back
+        for msg_id in 3..6 {
+            mock.respond(msg_id, Payload::WriteOk);
+        }
+        node.handle_message(client_send(1, &key))?;
+        let sent = mock.sent();
+        assert_eq!(
+            Payload::SendOk { offset: 0 },
+            sent.last().unwrap().body.payload
+        );
+        assert_eq!(1, node.logs[&key].entries.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_send() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+
+        let key = keys_owned_by("n0", &node_ids(2), 1).remove(0);
+        let send = |msg_id, msg| {
+            let body = BodyBuilder::new(Payload::Send {
+                key: key.clone(),
+                msg,
+            })
+            .msg_id(msg_id)
+            .build();
+            Message::new("c1", "n0", body)
+        };
+
+        node.handle_message(send(1, 10))?;
+        node.handle_message(send(1, 10))?;
+        node.handle_message(send(2, 20))?;
+
+        let offsets: Vec<Payload> = mock
+            .sent()
+            .into_iter()
+            .map(|reply| reply.body.payload)
+            .collect();
+        assert_eq!(
+            vec![
+                Payload::SendOk { offset: 0 },
+                Payload::SendOk { offset: 0 },
+                Payload::SendOk { offset: 1 },
+            ],
+            offsets
+        );
+        assert_eq!(2, node.logs[&key].entries.len());
+        Ok(())
+    }
+
+    /// A client send of `key` from c1
+    fn client_send(msg_id: usize, key: &str) -> Message<Payload> {
+        let body = BodyBuilder::new(Payload::Send {
+            key: key.to_string(),
+            msg: 10,
+        })
+        .msg_id(msg_id)
+        .build();
+        Message::new("c1", "n0", body)
+    }
+
+    #[test]
+    fn test_retry_during_forward() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+
+        // the retry arrives while the first attempt's forward is waiting on n1
+        node.handle_message(client_send(1, &key))?;
+        let forward = wait_for_sent(&mock, 1).remove(0);
+        node.handle_message(client_send(1, &key))?;
+
+        mock.network()
+            .check_callback(forward.into_reply(Payload::SendOk { offset: 7 }));
+        wait_for_sent(&mock, 2);
+
+        // a later send is forwarded next, the retry never was.
+        // the reply to the first send took msg_id 1
+        mock.respond(2, Payload::SendOk { offset: 8 });
+        node.handle_message(client_send(2, &key))?;
+        let sent = wait_for_sent(&mock, 4);
+
+        assert_eq!(2, sent.iter().filter(|msg| msg.dest == "n1").count());
+        let replies: Vec<_> = sent
+            .iter()
+            .filter(|msg| msg.dest == "c1")
+            .map(|msg| (msg.body.in_reply_to, &msg.body.payload))
+            .collect();
+        assert_eq!(
+            vec![
+                (Some(1), &Payload::SendOk { offset: 7 }),
+                (Some(2), &Payload::SendOk { offset: 8 }),
+            ],
+            replies
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_forward_is_retried() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+
+        mock.respond(0, Payload::error(ErrorCode::Crash, "down".into()));
+        mock.respond(1, Payload::SendOk { offset: 3 });
+        node.handle_message(client_send(1, &key))?;
+        wait_for_sent(&mock, 1);
+
+        // the failed forward is forgotten, so the client's retry is forwarded again
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while node.sends.lock().get(&("c1".into(), 1)).is_some() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        node.handle_message(client_send(1, &key))?;
+
+        let sent = wait_for_sent(&mock, 3);
+        assert_eq!(2, sent.iter().filter(|msg| msg.dest == "n1").count());
+        assert_eq!(Payload::SendOk { offset: 3 }, sent[2].body.payload);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shed_send_is_retried() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+
+        // a queue without room sheds the send, failing it
+        let (full, _rx) = WorkerQueue::with_capacity(0, QueuePolicy::Shed);
+        let worker = mem::replace(&mut node.send_worker, full);
+        node.handle_message(client_send(1, &key))?;
+
+        let sent = mock.sent();
+        let Payload::Error(error) = &sent[0].body.payload else {
+            panic!("expected an error, got {:?}", sent[0].body.payload);
+        };
+        assert_eq!(usize::from(ErrorCode::TemporarilyUnavailable), error.code);
+
+        // the shed send was forgotten, so the client's retry is forwarded
+        node.send_worker = worker;
+        mock.respond(1, Payload::SendOk { offset: 4 });
+        node.handle_message(client_send(1, &key))?;
+
+        let sent = wait_for_sent(&mock, 3);
+        assert_eq!("n1", sent[1].dest);
+        assert_eq!(Payload::SendOk { offset: 4 }, sent[2].body.payload);
+        assert_eq!(Some(1), sent[2].body.in_reply_to);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lost_forward_times_out() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2));
+        let key = keys_owned_by("n1", &node_ids(2), 1).remove(0);
+
+        // n1 never answers the first forward
+        node.handle_message(client_send(1, &key))?;
+        wait_for_sent(&mock, 1);
+        thread::sleep(REMOTE_TIMEOUT * 2);
+
+        // the worker gave up on it, so the client's retry is forwarded again
+        mock.respond(1, Payload::SendOk { offset: 2 });
+        node.handle_message(client_send(1, &key))?;
+
+        let sent = wait_for_sent(&mock, 3);
+        assert_eq!(2, sent.iter().filter(|msg| msg.dest == "n1").count());
+        assert_eq!(Payload::SendOk { offset: 2 }, sent[2].body.payload);
+        assert_eq!(1, mock.network().metrics().rpc_timeouts);
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_partitioned_send() -> Try {
+        let mock = MockNetwork::new();
+        let partitioner = Box::new(RangePartitioner::new(["m"]));
+        let mut node = KafkaNode::new(mock.network(), "n0".into(), node_ids(2), partitioner, false);
+
+        let send = |msg_id, key: &str| {
+            let body = BodyBuilder::new(Payload::Send {
+                key: key.to_string(),
+                msg: 10,
+            })
+            .msg_id(msg_id)
+            .build();
+            Message::new("c1", "n0", body)
+        };
+
+        // keys before "m" are ours, the rest are forwarded to n1
+        node.handle_message(send(1, "apple"))?;
+        node.handle_message(send(2, "zebra"))?;
+
+        let sent = wait_for_sent(&mock, 2);
+        assert_eq!("c1", sent[0].dest);
+        assert_eq!(Some(1), sent[0].body.in_reply_to);
+        assert_eq!("n1", sent[1].dest);
+        assert!(node.logs.contains_key("apple"));
+        assert!(!node.logs.contains_key("zebra"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_queue_sheds() -> Try {
+        let (queue, rx) = WorkerQueue::with_capacity(2, QueuePolicy::Shed);
+        for job in 0..2 {
+            assert_eq!(Pushed::Queued, queue.push(job)?);
+        }
+
+        // the third job was dropped rather than waiting for room
+        assert_eq!(Pushed::Shed(2), queue.push(2)?);
+        assert_eq!(vec![0, 1], rx.try_iter().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_queue_blocks() -> Try {
+        let (queue, rx) = WorkerQueue::with_capacity(2, QueuePolicy::Block);
+        let pusher = thread::spawn(move || -> Try {
+            for job in 0..3 {
+                queue.push(job)?;
+            }
+            Ok(())
+        });
+
+        // the third push waits until the worker takes a job
+        thread::sleep(Duration::from_millis(50));
+        assert!(!pusher.is_finished());
+        assert_eq!(Ok(0), rx.recv());
+        pusher.join().unwrap()?;
+        assert_eq!(vec![1, 2], rx.try_iter().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_policy() {
+        let mock = MockNetwork::new();
+        let node = KafkaNode::from_init(mock.network(), "n0".into(), node_ids(2))
+            .with_queue_policy(QueuePolicy::Block);
+        assert_eq!(QueuePolicy::Block, node.send_worker.policy);
+        assert_eq!(QueuePolicy::Block, node.list_committed_worker.policy);
+    }
+
+    #[test]
+    fn test_send_dedup_evicts_least_recent() {
+        let mut sends = SendDedup::default();
+        let send = |msg_id| ("c1".to_string(), msg_id);
+
+        let appended = SendState::Appended;
+
+        sends.insert(send(0), appended(0));
+        for msg_id in 1..SEND_DEDUP_CAPACITY {
+            sends.insert(send(msg_id), appended(msg_id));
+        }
+
+        // using the oldest send keeps it, evicting the next oldest instead
+        assert_eq!(Some(appended(0)), sends.get(&send(0)));
+        sends.insert(send(SEND_DEDUP_CAPACITY), appended(SEND_DEDUP_CAPACITY));
+        assert_eq!(Some(appended(0)), sends.get(&send(0)));
+        assert_eq!(None, sends.get(&send(1)));
+        assert_eq!(SEND_DEDUP_CAPACITY, sends.sends.len());
+
+        sends.remove(&send(0));
+        assert_eq!(None, sends.get(&send(0)));
+        assert_eq!(SEND_DEDUP_CAPACITY - 1, sends.recency.len());
+    }
+}
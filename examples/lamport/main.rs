@@ -0,0 +1,357 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use gossip::{Gossip, Replicator};
+use maelbreaker::{
+    clock::{LamportClock, Timestamp},
+    network::Network,
+    node::Node,
+    payload,
+    runtime::Runtime,
+    types::{BodyBuilder, Message, Try},
+};
+use serde::{Deserialize, Serialize};
+
+// peers never change, so queues are never dropped with `retain`
+#[allow(dead_code)]
+#[path = "../broadcast/gossip.rs"]
+mod gossip;
+
+/*
+
+implementation: totally ordered broadcast
+
+    each broadcast is stamped with a lamport timestamp, (counter, node id).
+    timestamps are totally ordered, so delivering messages in timestamp order
+    gives every node the same log.
+
+    a message can only be delivered once no other node can still send one with
+    a smaller timestamp. every round, each node sends every other node all of
+    its messages that node hasn't acknowledged, along with its clock. once we
+    receive that, we hold every message the sender stamped up to its clock,
+    and anything it stamps later is stamped past it.
+    so a message is delivered once every other node's clock has reached its counter.
+
+    this needs every node to hear from every other node directly,
+    so the topology is ignored and messages are never relayed.
+*/
+
+payload!(
+    enum Payload {
+        Broadcast {
+            message: usize,
+        },
+        BroadcastOk,
+        Read,
+        ReadOk {
+            messages: Vec<usize>,
+        },
+        Topology {
+            topology: HashMap<String, Vec<String>>,
+        },
+        TopologyOk,
+        // every unacknowledged message by the sender's seq for it,
+        // sent every round even if empty to share the sender's clock
+        Replicate {
+            messages: BTreeMap<usize, Stamped>,
+            clock: u64,
+        },
+        ReplicateOk {
+            seqs: Vec<usize>,
+        },
+    }
+);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Stamped {
+    timestamp: Timestamp,
+    message: usize,
+}
+
+/// How often the runtime ticks, checking whether a round of replication is due
+const TICK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Prefix of the env vars overriding the `Gossip` defaults, such as `LAMPORT_INTERVAL_MS`.
+/// Every round sends all unacknowledged messages at once, so the max batch is unused.
+const GOSSIP_VAR_PREFIX: &str = "LAMPORT";
+
+#[derive(Debug)]
+struct LamportNode {
+    id: String,
+    /// every other node
+    peers: Vec<String>,
+    net: Network<Payload>,
+    clock: LamportClock,
+    replicator: Replicator<Stamped>,
+    /// received messages waiting on other nodes' clocks
+    pending: BTreeMap<Timestamp, usize>,
+    /// delivered messages, in timestamp order
+    log: Vec<Stamped>,
+    /// the latest clock received from each peer
+    peer_clocks: HashMap<String, u64>,
+    gossip: Gossip,
+    next_round: Instant,
+}
+
+impl LamportNode {
+    fn handle_broadcast(&mut self, request: Message<Payload>) -> Try {
+        let Payload::Broadcast { message } = request.body.payload else {
+            bail!("expected broadcast");
+        };
+
+        let stamped = Stamped {
+            timestamp: self.clock.stamp(&self.id),
+            message,
+        };
+        self.receive(stamped.clone());
+        self.replicator.queue(&self.peers, stamped, None);
+        self.deliver();
+
+        self.net.reply(request, Payload::BroadcastOk)
+    }
+
+    fn handle_read(&self, request: Message<Payload>) -> Try {
+        let messages = self.log.iter().map(|stamped| stamped.message).collect();
+        self.net.reply(request, Payload::ReadOk { messages })
+    }
+
+    fn handle_replicate(&mut self, request: Message<Payload>) -> Try {
+        let Payload::Replicate { messages, clock } = &request.body.payload else {
+            bail!("expected replicate");
+        };
+
+        self.clock.observe(*clock);
+        for stamped in messages.values() {
+            self.receive(stamped.clone());
+        }
+
+        // replicates can arrive out of order, an older one doesn't move the clock back
+        let peer_clock = self.peer_clocks.entry(request.src.clone()).or_default();
+        *peer_clock = (*peer_clock).max(*clock);
+        self.deliver();
+
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let seqs = messages.keys().copied().collect();
+        self.net.reply(request, Payload::ReplicateOk { seqs })
+    }
+
+    fn handle_replicate_ok(&mut self, request: Message<Payload>) -> Try {
+        let Payload::ReplicateOk { seqs } = &request.body.payload else {
+            bail!("expected replicate_ok");
+        };
+
+        self.replicator.ack(&request.src, seqs)
+    }
+
+    /// Holds a message until it can be delivered, ignoring retries of ones we have
+    fn receive(&mut self, stamped: Stamped) {
+        let delivered = self
+            .log
+            .last()
+            .is_some_and(|last| stamped.timestamp <= last.timestamp);
+
+        if !delivered {
+            self.pending.insert(stamped.timestamp, stamped.message);
+        }
+    }
+
+    /// Delivers pending messages in timestamp order,
+    /// up to the first one a peer may still send an earlier message than
+    fn deliver(&mut self) {
+        while let Some((timestamp, _)) = self.pending.first_key_value() {
+            let stable = self.peers.iter().all(|peer| {
+                self.peer_clocks
+                    .get(peer)
+                    .is_some_and(|clock| *clock >= timestamp.counter)
+            });
+
+            if !stable {
+                break;
+            }
+
+            let Some((timestamp, message)) = self.pending.pop_first() else {
+                break;
+            };
+            self.log.push(Stamped { timestamp, message });
+        }
+    }
+
+    fn replicate(&self, network: &Network<Payload>) -> Try {
+        let mut batches: HashMap<String, BTreeMap<usize, Stamped>> = self
+            .replicator
+            .batches(&self.peers, usize::MAX)
+            .into_iter()
+            .collect();
+
+        for peer in &self.peers {
+            let replicate = Payload::Replicate {
+                messages: batches.remove(peer).unwrap_or_default(),
+                clock: self.clock.now(),
+            };
+
+            network
+                .send(Message::new(
+                    &self.id,
+                    peer,
+                    BodyBuilder::new(replicate).build(),
+                ))
+                .context("failed to send replicate")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Node<Payload> for LamportNode {
+    fn from_init(network: Network<Payload>, node_id: String, node_ids: Vec<String>) -> Self {
+        let peers: Vec<String> = node_ids.into_iter().filter(|id| id != &node_id).collect();
+        let gossip = Gossip::from_env(GOSSIP_VAR_PREFIX);
+
+        Self {
+            id: node_id,
+            peers,
+            net: network,
+            clock: LamportClock::new(),
+            replicator: Replicator::default(),
+            pending: BTreeMap::new(),
+            log: Vec::new(),
+            peer_clocks: HashMap::new(),
+            gossip,
+            next_round: gossip.next_round(),
+        }
+    }
+
+    fn handle_message(&mut self, msg: Message<Payload>) -> Try {
+        match &msg.body.payload {
+            Payload::Broadcast { .. } => self.handle_broadcast(msg)?,
+            Payload::Read => self.handle_read(msg)?,
+            Payload::Topology { .. } => self.net.reply(msg, Payload::TopologyOk)?,
+            Payload::Replicate { .. } => self.handle_replicate(msg)?,
+            Payload::ReplicateOk { .. } => self.handle_replicate_ok(msg)?,
+            _ => {}
+        };
+
+        Ok(())
+    }
+
+    fn tick(&mut self, network: &Network<Payload>) -> Try {
+        if Instant::now() < self.next_round {
+            return Ok(());
+        }
+
+        self.next_round = self.gossip.next_round();
+        self.replicate(network)
+    }
+}
+
+fn main() -> Try {
+    Runtime::<Payload, LamportNode>::new()
+        .with_tick(TICK_INTERVAL)
+        .start()
+}
+
+#[cfg(test)]
+mod tests {
+    use maelbreaker::testing::MockNetwork;
+
+    use super::*;
+
+    fn cluster(size: usize) -> Vec<(LamportNode, MockNetwork<Payload>)> {
+        let node_ids: Vec<String> = (0..size).map(|i| format!("n{i}")).collect();
+        node_ids
+            .iter()
+            .map(|id| {
+                let mock = MockNetwork::new();
+                let node = LamportNode::from_init(mock.network(), id.clone(), node_ids.clone());
+                (node, mock)
+            })
+            .collect()
+    }
+
+    /// Has every node replicate, then delivers messages between nodes until none are left
+    fn round(cluster: &mut [(LamportNode, MockNetwork<Payload>)]) -> Try {
+        for (node, mock) in cluster.iter() {
+            node.replicate(&mock.network())?;
+        }
+
+        loop {
+            let sent: Vec<Message<Payload>> = cluster
+                .iter()
+                .flat_map(|(_, mock)| mock.take_sent())
+                .collect();
+            if sent.is_empty() {
+                return Ok(());
+            }
+
+            for msg in sent {
+                if let Some((node, _)) = cluster.iter_mut().find(|(node, _)| node.id == msg.dest) {
+                    node.handle_message(msg)?;
+                }
+            }
+        }
+    }
+
+    fn broadcast(message: usize) -> Message<Payload> {
+        let body = BodyBuilder::new(Payload::Broadcast { message })
+            .msg_id(1)
+            .build();
+        Message::new("c1", "n0", body)
+    }
+
+    fn log(node: &LamportNode) -> Vec<usize> {
+        node.log.iter().map(|stamped| stamped.message).collect()
+    }
+
+    #[test]
+    fn test_concurrent_broadcasts_delivered_in_same_order() -> Try {
+        let mut cluster = cluster(3);
+
+        // sent before either node hears of the other's, so both are stamped 1
+        cluster[1].0.handle_message(broadcast(10))?;
+        cluster[0].0.handle_message(broadcast(20))?;
+        assert!(cluster.iter().all(|(node, _)| node.log.is_empty()));
+
+        round(&mut cluster)?;
+        round(&mut cluster)?;
+
+        // ties are broken by node id, so n0's message comes first everywhere
+        for (node, _) in &cluster {
+            assert_eq!(vec![20, 10], log(node), "{}", node.id);
+        }
+
+        // retried replicates don't deliver a message twice
+        round(&mut cluster)?;
+        assert!(cluster.iter().all(|(node, _)| log(node) == vec![20, 10]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_waits_for_every_peer() -> Try {
+        let mut cluster = cluster(3);
+        cluster[0].0.handle_message(broadcast(1))?;
+        cluster[0].1.take_sent();
+
+        // n2 hasn't shared its clock, it could still send an earlier message
+        cluster[0].0.replicate(&cluster[0].1.network())?;
+        let to_n1 = cluster[0]
+            .1
+            .take_sent()
+            .into_iter()
+            .find(|msg| msg.dest == "n1")
+            .unwrap();
+        cluster[1].0.handle_message(to_n1)?;
+        assert!(cluster[1].0.log.is_empty());
+
+        // n2's first replicate carries the clock it had before hearing of the message
+        round(&mut cluster)?;
+        round(&mut cluster)?;
+        assert_eq!(vec![1], log(&cluster[1].0));
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc::{channel, Sender},
+    thread,
+};
+
+use anyhow::{anyhow, bail};
+use maelbreaker::{
+    error::ErrorCode,
+    network::Network,
+    node::Node,
+    partition::Ring,
+    payload,
+    runtime::Runtime,
+    types::{ErrorBody, Message, Try},
+};
+use serde_json::Value;
+
+/*
+
+implementation: single leader per key
+
+    each key is owned by exactly 1 node, picked with a consistent hashing ring
+        owner = ring(node_ids).owner(key)
+
+    the owner applies every operation on its keys one at a time, on the runtime thread,
+    so each key behaves like a single register and operations on it are linearizable.
+
+    a node receiving an operation for a key it doesn't own forwards it to the owner
+    from a background thread, and relays the owner's response to the client.
+    like kafka's send worker, forwarding in the background keeps the node serving
+    operations forwarded to it while it waits on another owner.
+    the owner never forwards, so forwarded operations are always answered.
+*/
+
+payload!(
+    errors,
+    enum Payload {
+        Read {
+            key: Value,
+        },
+        ReadOk {
+            value: Value,
+        },
+        Write {
+            key: Value,
+            value: Value,
+        },
+        WriteOk,
+        Cas {
+            key: Value,
+            from: Value,
+            to: Value,
+            #[serde(default)]
+            create_if_not_exists: bool,
+        },
+        CasOk,
+        Error(ErrorBody),
+    }
+);
+
+impl Payload {
+    /// The key an operation reads or writes
+    fn key(&self) -> Option<&Value> {
+        match self {
+            Payload::Read { key } | Payload::Write { key, .. } | Payload::Cas { key, .. } => {
+                Some(key)
+            }
+            _ => None,
+        }
+    }
+}
+
+struct LinKvNode {
+    node_id: String,
+    ring: Ring,
+    network: Network<Payload>,
+    // keys as their JSON text, so keys of any type can be stored
+    store: HashMap<String, Value>,
+    forward_worker: Sender<ForwardJob>,
+}
+
+struct ForwardJob {
+    client_request: Message<Payload>,
+    owner: String,
+}
+
+impl Node<Payload> for LinKvNode {
+    fn from_init(network: Network<Payload>, node_id: String, node_ids: Vec<String>) -> Self {
+        let forward_worker = LinKvNode::forward_worker(node_id.clone(), network.clone());
+
+        Self {
+            node_id,
+            ring: Ring::new(&node_ids),
+            network,
+            store: HashMap::new(),
+            forward_worker,
+        }
+    }
+
+    fn handle_message(&mut self, msg: Message<Payload>) -> Try {
+        let Some(key) = msg.body.payload.key() else {
+            return Ok(());
+        };
+
+        let key = key.to_string();
+        let owner = self.ring.owner(&key).to_string();
+        if owner != self.node_id {
+            // only clients should send us a key we don't own, else our hashing is busted
+            assert!(msg.src_id().is_client());
+
+            let job = ForwardJob {
+                client_request: msg,
+                owner,
+            };
+
+            return self
+                .forward_worker
+                .send(job)
+                .map_err(|_| anyhow!("forward worker disconnected"));
+        }
+
+        let reply = self.apply(key, &msg.body.payload)?;
+        self.network.reply(msg, reply)
+    }
+}
+
+impl LinKvNode {
+    /// Applies an operation on a key we own, returning the response
+    fn apply(&mut self, key: String, operation: &Payload) -> anyhow::Result<Payload> {
+        let missing = || {
+            Payload::Error(ErrorBody::new(
+                ErrorCode::KeyDoesNotExist,
+                format!("key {key} does not exist"),
+            ))
+        };
+
+        let response = match operation {
+            Payload::Read { .. } => match self.store.get(&key) {
+                Some(value) => Payload::ReadOk {
+                    value: value.clone(),
+                },
+                None => missing(),
+            },
+            Payload::Write { value, .. } => {
+                self.store.insert(key, value.clone());
+                Payload::WriteOk
+            }
+            Payload::Cas {
+                from,
+                to,
+                create_if_not_exists,
+                ..
+            } => match self.store.get(&key) {
+                Some(current) if current == from => {
+                    self.store.insert(key, to.clone());
+                    Payload::CasOk
+                }
+                Some(current) => Payload::Error(ErrorBody::new(
+                    ErrorCode::PreconditionFailed,
+                    format!("expected {from}, was {current}"),
+                )),
+                None if *create_if_not_exists => {
+                    self.store.insert(key, to.clone());
+                    Payload::CasOk
+                }
+                None => missing(),
+            },
+            other => bail!("expected read, write, or cas, got {other:?}"),
+        };
+
+        Ok(response)
+    }
+
+    fn forward_worker(node_id: String, network: Network<Payload>) -> Sender<ForwardJob> {
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            for job in rx {
+                let ForwardJob {
+                    client_request,
+                    owner,
+                } = job;
+
+                let fwd = client_request
+                    .clone()
+                    .forward(&node_id, owner, network.next_id());
+
+                let Ok(result) = network.rpc(fwd) else {
+                    eprintln!("failed to forward operation to owner");
+                    continue;
+                };
+
+                let Ok(result) = result.recv() else {
+                    eprintln!("failed to recv forwarded operation from owner");
+                    continue;
+                };
+
+                // the owner's response, including errors, is the client's response
+                network.reply(client_request, result.body.payload).unwrap();
+            }
+        });
+
+        tx
+    }
+}
+
+fn main() -> Try {
+    Runtime::<Payload, LinKvNode>::run()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use maelbreaker::{error::IsError, testing::MockNetwork, types::BodyBuilder};
+    use serde_json::json;
+
+    use super::*;
+
+    fn node_ids() -> Vec<String> {
+        (0..3).map(|i| format!("n{i}")).collect()
+    }
+
+    /// The first key owned by `owner`
+    fn key_owned_by(owner: &str) -> Value {
+        (0..)
+            .map(|i| json!(i))
+            .find(|key| Ring::new(&node_ids()).owner(&key.to_string()) == owner)
+            .unwrap()
+    }
+
+    fn request(msg_id: usize, payload: Payload) -> Message<Payload> {
+        Message::new("c1", "n0", BodyBuilder::new(payload).msg_id(msg_id).build())
+    }
+
+    #[test]
+    fn test_owned_operations() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = LinKvNode::from_init(mock.network(), "n0".into(), node_ids());
+        let key = key_owned_by("n0");
+
+        node.handle_message(request(1, Payload::Read { key: key.clone() }))?;
+        node.handle_message(request(
+            2,
+            Payload::Write {
+                key: key.clone(),
+                value: json!(1),
+            },
+        ))?;
+        node.handle_message(request(
+            3,
+            Payload::Cas {
+                key: key.clone(),
+                from: json!(2),
+                to: json!(3),
+                create_if_not_exists: false,
+            },
+        ))?;
+        node.handle_message(request(
+            4,
+            Payload::Cas {
+                key: key.clone(),
+                from: json!(1),
+                to: json!(3),
+                create_if_not_exists: false,
+            },
+        ))?;
+        node.handle_message(request(5, Payload::Read { key }))?;
+
+        let replies: Vec<Payload> = mock
+            .sent()
+            .into_iter()
+            .map(|msg| msg.body.payload)
+            .collect();
+        assert_eq!(Some(ErrorCode::KeyDoesNotExist), replies[0].error_code());
+        assert_eq!(Payload::WriteOk, replies[1]);
+        assert_eq!(Some(ErrorCode::PreconditionFailed), replies[2].error_code());
+        assert_eq!(Payload::CasOk, replies[3]);
+        assert_eq!(Payload::ReadOk { value: json!(3) }, replies[4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwards_to_owner() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = LinKvNode::from_init(mock.network(), "n0".into(), node_ids());
+        let key = key_owned_by("n2");
+
+        mock.respond(0, Payload::ReadOk { value: json!(7) });
+        node.handle_message(request(1, Payload::Read { key: key.clone() }))?;
+
+        // the forwarded read, then the owner's response relayed to the client
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while mock.sent().len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let sent = mock.sent();
+        assert_eq!(("n0", "n2"), (sent[0].src.as_str(), sent[0].dest.as_str()));
+        assert_eq!(Payload::Read { key }, sent[0].body.payload);
+        assert_eq!("c1", sent[1].dest);
+        assert_eq!(Some(1), sent[1].body.in_reply_to);
+        assert_eq!(Payload::ReadOk { value: json!(7) }, sent[1].body.payload);
+        Ok(())
+    }
+}
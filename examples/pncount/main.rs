@@ -0,0 +1,241 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::bail;
+use counter::Counter;
+use maelbreaker::{
+    kv::KvClient,
+    network::Network,
+    node::Node,
+    payload,
+    runtime::Runtime,
+    types::{ErrorBody, Message, Service, Try},
+};
+
+#[path = "../gcount/counter.rs"]
+mod counter;
+
+/*
+
+implementation: PN-counter
+
+    a PN-counter is a pair of grow-only counters, one for increments and one for decrements.
+    each node keeps its pair in seq-kv at `<node id>-inc` and `<node id>-dec`,
+    and is the only writer of its own keys, like gcount.
+
+    positive deltas are added to the increment counter, negative deltas to the decrement
+    counter, and a read sums every node's increments and subtracts every node's decrements.
+*/
+
+payload!(
+    enum Payload {
+        Add {
+            delta: i64,
+        },
+        AddOk,
+
+        // shared by challenge and seq-kv, only seq-kv reads have a key
+        Read {
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            key: Option<String>,
+        },
+        ReadOk {
+            value: i64,
+        },
+
+        Cas {
+            key: String,
+            from: usize,
+            to: usize,
+            create_if_not_exists: bool,
+        },
+        CasOk,
+
+        Error(ErrorBody),
+    }
+);
+
+/// How long a client read waits on seq-kv before answering from the cache,
+/// so reads stay responsive while seq-kv is partitioned away
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn increments_key(node_id: &str) -> String {
+    format!("{node_id}-inc")
+}
+
+fn decrements_key(node_id: &str) -> String {
+    format!("{node_id}-dec")
+}
+
+struct PnCountNode {
+    /// every node's increment and decrement keys
+    keys: Vec<String>,
+
+    /// last seen value for seq-kv keys
+    cache: HashMap<String, usize>,
+    network: Network<Payload>,
+    kv: KvClient<Payload>,
+    increments: Counter,
+    decrements: Counter,
+}
+
+impl Node<Payload> for PnCountNode {
+    fn from_init(network: Network<Payload>, id: String, ids: Vec<String>) -> Self {
+        eprintln!("initializing pncount node {id}");
+        let kv = KvClient::new(network.clone(), &id, Service::SeqKv);
+        let increments = Counter::start(kv.clone(), increments_key(&id));
+        let decrements = Counter::start(kv.clone(), decrements_key(&id));
+        let keys = ids
+            .iter()
+            .flat_map(|id| [increments_key(id), decrements_key(id)])
+            .collect();
+
+        Self {
+            keys,
+            cache: Default::default(),
+            network,
+            kv: kv.with_timeout(READ_TIMEOUT),
+            increments,
+            decrements,
+        }
+    }
+
+    fn handle_message(&mut self, msg: Message<Payload>) -> Try {
+        match &msg.body.payload {
+            Payload::Add { .. } => self.handle_add(msg),
+            Payload::Read { .. } => self.handle_read(msg),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PnCountNode {
+    fn handle_add(&self, msg: Message<Payload>) -> Try {
+        let Payload::Add { delta } = &msg.body.payload else {
+            bail!("expected add");
+        };
+
+        let counter = if delta.is_negative() {
+            &self.decrements
+        } else {
+            &self.increments
+        };
+        counter.add(delta.unsigned_abs() as usize);
+        self.network.reply(msg, Payload::AddOk)
+    }
+
+    fn handle_read(&mut self, msg: Message<Payload>) -> Try {
+        let mut value = 0;
+
+        // read db entry for each counter, or returned the cached value
+        let reads = self.kv.read_many(&self.keys);
+        for (key, read) in self.keys.iter().zip(reads) {
+            let read = match read {
+                Ok(read) => {
+                    self.cache.insert(key.clone(), read);
+                    read
+                }
+                Err(_) => *self.cache.entry(key.clone()).or_insert(0),
+            };
+
+            if key.ends_with("-dec") {
+                value -= read as i64;
+            } else {
+                value += read as i64;
+            }
+        }
+
+        self.network.reply(msg, Payload::ReadOk { value })
+    }
+}
+
+fn main() -> Try {
+    Runtime::<Payload, PnCountNode>::run()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{channel, Receiver},
+        thread,
+        time::Instant,
+    };
+
+    use maelbreaker::{error::ErrorCode, types::BodyBuilder};
+
+    use super::*;
+
+    /// A network whose seq-kv requests are answered from an in-memory store.
+    /// Every other message is passed to the returned receiver.
+    fn seq_kv() -> (Network<Payload>, Receiver<Message<Payload>>) {
+        let (network, outbound) = Network::new();
+        let (tx, rx) = channel();
+
+        let responder = network.clone();
+        thread::spawn(move || {
+            let mut store = HashMap::<String, usize>::new();
+            for msg in outbound {
+                if msg.dest != Service::SeqKv.as_str() {
+                    let _ = tx.send(msg);
+                    continue;
+                }
+
+                let missing =
+                    || Payload::Error(ErrorBody::new(ErrorCode::KeyDoesNotExist, "missing"));
+                let payload = match &msg.body.payload {
+                    Payload::Read { key: Some(key) } => match store.get(key) {
+                        Some(value) => Payload::ReadOk {
+                            value: *value as i64,
+                        },
+                        None => missing(),
+                    },
+                    Payload::Cas { key, from, to, .. } => match store.get(key) {
+                        Some(current) if current != from => Payload::Error(ErrorBody::new(
+                            ErrorCode::PreconditionFailed,
+                            format!("expected {from}, was {current}"),
+                        )),
+                        _ => {
+                            store.insert(key.clone(), *to);
+                            Payload::CasOk
+                        }
+                    },
+                    _ => continue,
+                };
+
+                responder.check_callback(msg.into_reply(payload));
+            }
+        });
+
+        (network, rx)
+    }
+
+    fn request(msg_id: usize, payload: Payload) -> Message<Payload> {
+        Message::new("c1", "n0", BodyBuilder::new(payload).msg_id(msg_id).build())
+    }
+
+    #[test]
+    fn test_signed_deltas() -> Try {
+        let (network, client) = seq_kv();
+        let mut node = PnCountNode::from_init(network, "n0".into(), vec!["n0".into(), "n1".into()]);
+
+        for (msg_id, delta) in [5, -3, 1].into_iter().enumerate() {
+            node.handle_message(request(msg_id, Payload::Add { delta }))?;
+            assert_eq!(Payload::AddOk, client.recv()?.body.payload);
+        }
+
+        // the workers apply deltas in the background
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut value = None;
+        while value != Some(3) && Instant::now() < deadline {
+            node.handle_message(request(10, Payload::Read { key: None }))?;
+            let Payload::ReadOk { value: read } = client.recv()?.body.payload else {
+                bail!("expected read_ok");
+            };
+
+            value = Some(read);
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(Some(3), value);
+        Ok(())
+    }
+}
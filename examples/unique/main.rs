@@ -6,7 +6,16 @@ use maelbreaker::{
     types::{Message, Try},
 };
 
-use anyhow::anyhow;
+/*
+
+implementation: node id, nonce, counter
+
+    ids are `<node id>-<nonce>-<counter>`.
+    the counter makes ids from one process unique, and the nonce, drawn at random
+    when the node starts, tells apart processes of the same node, since a node
+    restarted after a crash counts from zero again.
+    client msg_ids aren't used, different clients send the same ones.
+*/
 
 payload!(
     enum Payload {
@@ -18,24 +27,75 @@ payload!(
 struct UniqueNode {
     id: String,
     net: Network<Payload>,
+    nonce: u64,
+    counter: u64,
+}
+
+impl UniqueNode {
+    fn next_id(&mut self) -> String {
+        let id = format!("{}-{:x}-{}", self.id, self.nonce, self.counter);
+        self.counter += 1;
+        id
+    }
 }
 
 impl Node<Payload> for UniqueNode {
     fn from_init(net: Network<Payload>, id: String, _: Vec<String>) -> Self {
-        Self { id, net }
+        Self {
+            id,
+            net,
+            nonce: rand::random(),
+            counter: 0,
+        }
     }
 
     fn handle_message(&mut self, msg: Message<Payload>) -> Try {
-        let id = format!(
-            "{}-{}",
-            self.id,
-            msg.body.msg_id.ok_or(anyhow!("missing id"))?
-        );
-        let reply = msg.into_reply(Payload::GenerateOk { id });
-        self.net.send(reply)
+        let id = self.next_id();
+        self.net.reply(msg, Payload::GenerateOk { id })
     }
 }
 
 fn main() -> Try {
     Runtime::<Payload, UniqueNode>::run()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use maelbreaker::{testing::MockNetwork, types::BodyBuilder};
+
+    use super::*;
+
+    /// Generates `count` ids from a fresh instance of n1, for requests with msg_ids from 0
+    fn generate(count: usize) -> anyhow::Result<Vec<String>> {
+        let mock = MockNetwork::new();
+        let mut node = UniqueNode::from_init(mock.network(), "n1".into(), vec!["n1".into()]);
+
+        for msg_id in 0..count {
+            let generate = BodyBuilder::new(Payload::Generate).msg_id(msg_id).build();
+            node.handle_message(Message::new("c1", "n1", generate))?;
+        }
+
+        Ok(mock
+            .take_sent()
+            .into_iter()
+            .filter_map(|msg| match msg.body.payload {
+                Payload::GenerateOk { id } => Some(id),
+                _ => None,
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_restarted_node_ids_are_disjoint() -> Try {
+        // the same node before and after a restart, seeing the same msg_ids
+        let before: HashSet<String> = generate(100)?.into_iter().collect();
+        let after: HashSet<String> = generate(100)?.into_iter().collect();
+
+        assert_eq!(100, before.len());
+        assert_eq!(100, after.len());
+        assert!(before.is_disjoint(&after));
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Defines logical clocks, for ordering events across nodes without synchronized time
+
+use std::{cmp::Ordering, collections::HashMap};
+
+use serde::{Deserialize, Serialize};
+
+/// A Lamport timestamp. Timestamps order by counter, then by node id,
+/// so timestamps from different nodes are never equal and every node
+/// sorts the same set of timestamps into the same total order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub counter: u64,
+    pub node_id: String,
+}
+
+/// A Lamport clock. If one event happened before another,
+/// the first event's timestamp is less than the second's.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LamportClock {
+    counter: u64,
+}
+
+impl LamportClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The counter of the latest event, 0 if there has been none
+    pub fn now(&self) -> u64 {
+        self.counter
+    }
+
+    /// Advances the clock for a local event, such as sending a message,
+    /// returning the event's counter
+    pub fn tick(&mut self) -> u64 {
+        self.counter += 1;
+        self.counter
+    }
+
+    /// Ticks the clock, returning the event's timestamp as seen on `node_id`
+    pub fn stamp(&mut self, node_id: impl Into<String>) -> Timestamp {
+        Timestamp {
+            counter: self.tick(),
+            node_id: node_id.into(),
+        }
+    }
+
+    /// Advances the clock past a counter received from another node,
+    /// returning the counter of the receive event
+    pub fn observe(&mut self, counter: u64) -> u64 {
+        self.counter = self.counter.max(counter);
+        self.tick()
+    }
+}
+
+/// A vector clock, counting the events seen from each node.
+/// Unlike Lamport timestamps, vector clocks tell concurrent events apart
+/// from ones that happened before each other.
+///
+/// Serializes as a map from node id to counter, such as `{"n1":2,"n2":1}`.
+/// Nodes missing from the map have a counter of 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock {
+    counters: HashMap<String, usize>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of events seen from `node_id`
+    pub fn get(&self, node_id: &str) -> usize {
+        self.counters.get(node_id).copied().unwrap_or(0)
+    }
+
+    /// Counts a local event on `node_id`, returning its new counter
+    pub fn increment(&mut self, node_id: &str) -> usize {
+        let counter = self.counters.entry(node_id.to_string()).or_default();
+        *counter += 1;
+        *counter
+    }
+
+    /// Takes the larger counter for each node, after receiving `other`
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node_id, counter) in &other.counters {
+            let merged = self.counters.entry(node_id.clone()).or_default();
+            *merged = (*merged).max(*counter);
+        }
+    }
+
+    /// Compares the events two clocks have seen.
+    /// `Less` if this clock happened before `other`, `Greater` if after,
+    /// `Equal` if they have seen the same events, and None if they are concurrent.
+    pub fn happens_before(&self, other: &VectorClock) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for node_id in self.counters.keys().chain(other.counters.keys()) {
+            let node_ordering = self.get(node_id).cmp(&other.get(node_id));
+            if node_ordering == Ordering::Equal {
+                continue;
+            }
+
+            // ahead on one node and behind on another
+            if ordering != Ordering::Equal && ordering != node_ordering {
+                return None;
+            }
+            ordering = node_ordering;
+        }
+
+        Some(ordering)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_tick_and_observe() {
+        let mut clock = LamportClock::new();
+        assert_eq!(0, clock.now());
+        assert_eq!(1, clock.tick());
+
+        // receiving a later counter jumps past it, an earlier one still ticks
+        assert_eq!(6, clock.observe(5));
+        assert_eq!(7, clock.observe(2));
+        assert_eq!(7, clock.now());
+    }
+
+    #[test]
+    fn test_timestamps_are_totally_ordered() {
+        let mut n1 = LamportClock::new();
+        let mut n2 = LamportClock::new();
+
+        // concurrent events with the same counter are ordered by node id
+        let a = n2.stamp("n2");
+        let b = n1.stamp("n1");
+        assert_eq!(a.counter, b.counter);
+        assert!(b < a);
+
+        // an event after receiving a message is ordered after it
+        n1.observe(a.counter);
+        let c = n1.stamp("n1");
+        assert!(a < c);
+    }
+
+    fn vector(counters: &[(&str, usize)]) -> VectorClock {
+        let mut clock = VectorClock::new();
+        for (node_id, counter) in counters {
+            for _ in 0..*counter {
+                clock.increment(node_id);
+            }
+        }
+        clock
+    }
+
+    #[test]
+    fn test_vector_increment_and_merge() {
+        let mut clock = VectorClock::new();
+        assert_eq!(0, clock.get("n1"));
+        assert_eq!(1, clock.increment("n1"));
+        assert_eq!(2, clock.increment("n1"));
+
+        clock.merge(&vector(&[("n1", 1), ("n2", 3)]));
+        assert_eq!(vector(&[("n1", 2), ("n2", 3)]), clock);
+    }
+
+    #[test]
+    fn test_vector_happens_before() {
+        let a = vector(&[("n1", 1)]);
+        let b = vector(&[("n1", 1), ("n2", 1)]);
+        let c = vector(&[("n1", 2), ("n2", 1)]);
+
+        assert_eq!(Some(Ordering::Less), a.happens_before(&b));
+        assert_eq!(Some(Ordering::Greater), b.happens_before(&a));
+        assert_eq!(Some(Ordering::Less), a.happens_before(&c));
+        assert_eq!(Some(Ordering::Less), b.happens_before(&c));
+        assert_eq!(Some(Ordering::Equal), b.happens_before(&b.clone()));
+        assert_eq!(
+            Some(Ordering::Equal),
+            VectorClock::new().happens_before(&vector(&[("n1", 0)]))
+        );
+    }
+
+    #[test]
+    fn test_vector_concurrent() {
+        // each has seen an event the other hasn't
+        let a = vector(&[("n1", 2), ("n2", 1)]);
+        let b = vector(&[("n1", 1), ("n2", 2)]);
+        assert_eq!(None, a.happens_before(&b));
+        assert_eq!(None, b.happens_before(&a));
+
+        // disjoint nodes
+        let c = vector(&[("n1", 1)]);
+        let d = vector(&[("n2", 1)]);
+        assert_eq!(None, c.happens_before(&d));
+
+        // merging concurrent clocks gives one both happened before
+        let mut merged = a.clone();
+        merged.merge(&b);
+        assert_eq!(Some(Ordering::Less), a.happens_before(&merged));
+        assert_eq!(Some(Ordering::Less), b.happens_before(&merged));
+    }
+
+    #[test]
+    fn test_vector_serializes_as_map() -> serde_json::Result<()> {
+        let clock = vector(&[("n1", 2), ("n2", 1)]);
+        assert_eq!(json!({"n1": 2, "n2": 1}), serde_json::to_value(&clock)?);
+
+        let parsed: VectorClock = serde_json::from_value(json!({"n1": 2, "n2": 1}))?;
+        assert_eq!(clock, parsed);
+        Ok(())
+    }
+}
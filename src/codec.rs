@@ -0,0 +1,122 @@
+//! Defines how messages are serialized on the wire
+
+use std::io::{self, BufRead, Write};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::framing::{Framer, LineFramer};
+
+/// The serialization of inbound and outbound messages.
+/// Maelstrom only speaks JSON, other codecs are for nodes embedded
+/// over a custom transport, see `Runtime::run_with_codec`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Newline delimited JSON
+    #[default]
+    Json,
+    /// MessagePack, with each frame prefixed by its length like `LengthPrefixedFramer`
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+}
+
+impl Codec {
+    /// Serializes a message into a frame
+    pub fn encode<T: Serialize>(self, value: &T) -> anyhow::Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        self.encode_into(&mut frame, value)?;
+        Ok(frame)
+    }
+
+    /// Serializes a message onto the end of `frame`, reusing its allocation
+    pub fn encode_into<T: Serialize>(self, frame: &mut Vec<u8>, value: &T) -> anyhow::Result<()> {
+        match self {
+            Codec::Json => serde_json::to_writer(frame, value)?,
+            // named, so structs are maps like in JSON and flattened payloads line up
+            #[cfg(feature = "msgpack")]
+            Codec::MsgPack => rmp_serde::encode::write_named(frame, value)?,
+        }
+
+        Ok(())
+    }
+
+    /// Deserializes a message from a frame
+    pub fn decode<T: DeserializeOwned>(self, frame: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            Codec::Json => serde_json::from_slice(frame)?,
+            #[cfg(feature = "msgpack")]
+            Codec::MsgPack => rmp_serde::from_slice(frame)?,
+        })
+    }
+
+    /// Writes a frame delimited so the codec's `framer` can split it back out
+    pub fn write_frame(self, writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Codec::Json => {
+                writer.write_all(frame)?;
+                writer.write_all(b"\n")
+            }
+            #[cfg(feature = "msgpack")]
+            Codec::MsgPack => {
+                writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+                writer.write_all(frame)
+            }
+        }
+    }
+
+    /// Splits frames written with `write_frame` out of `reader`
+    pub fn framer(self, reader: impl BufRead + Send + 'static) -> Box<dyn Framer + Send> {
+        match self {
+            Codec::Json => Box::new(LineFramer::new(reader)),
+            #[cfg(feature = "msgpack")]
+            Codec::MsgPack => Box::new(crate::framing::LengthPrefixedFramer::new(reader)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::types::{BodyBuilder, Init, Message};
+
+    use super::*;
+
+    fn init() -> Message<Init> {
+        let init = Init::Init {
+            node_id: "n1".into(),
+            node_ids: vec!["n1".into(), "n2".into()],
+        };
+        Message::new("c1", "n1", BodyBuilder::new(init).msg_id(1).build())
+    }
+
+    /// Writes every message as a frame and reads them back
+    fn round_trip(codec: Codec, messages: &[Message<Init>]) -> anyhow::Result<Vec<Message<Init>>> {
+        let mut stream = Vec::new();
+        for message in messages {
+            codec.write_frame(&mut stream, &codec.encode(message)?)?;
+        }
+
+        let mut framer = codec.framer(Cursor::new(stream));
+        let mut decoded = Vec::new();
+        while let Some(frame) = framer.next_frame_bytes()? {
+            decoded.push(codec.decode(&frame)?);
+        }
+
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_json_round_trip() -> anyhow::Result<()> {
+        let messages = vec![init(), init().into_reply(Init::InitOk)];
+        assert_eq!(messages, round_trip(Codec::Json, &messages)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_round_trip() -> anyhow::Result<()> {
+        let messages = vec![init(), init().into_reply(Init::InitOk)];
+        assert_eq!(messages, round_trip(Codec::MsgPack, &messages)?);
+        Ok(())
+    }
+}
@@ -1,5 +1,9 @@
 //! https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
 
+use std::{error::Error, fmt::Display};
+
+use crate::types::{BodyBuilder, ErrorBody, Message};
+
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ErrorCode {
@@ -22,6 +26,129 @@ impl From<ErrorCode> for usize {
     }
 }
 
+/// Displays the error's name as written in the Maelstrom docs, such as `precondition-failed`
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ErrorCode::*;
+        let name = match self {
+            Timeout => "timeout",
+            NodeNotFound => "node-not-found",
+            NotSupported => "not-supported",
+            TemporarilyUnavailable => "temporarily-unavailable",
+            MalformedRequest => "malformed-request",
+            Crash => "crash",
+            Abort => "abort",
+            KeyDoesNotExist => "key-does-not-exist",
+            KeyAlreadyExists => "key-already-exists",
+            PreconditionFailed => "precondition-failed",
+            TxnConflict => "txn-conflict",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Error for ErrorCode {}
+
+/// Returned when converting a number that isn't a known Maelstrom error code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownErrorCode(pub usize);
+
+impl Display for UnknownErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown error code {}", self.0)
+    }
+}
+
+impl Error for UnknownErrorCode {}
+
+impl TryFrom<usize> for ErrorCode {
+    type Error = UnknownErrorCode;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        use ErrorCode::*;
+        Ok(match value {
+            0 => Timeout,
+            1 => NodeNotFound,
+            10 => NotSupported,
+            11 => TemporarilyUnavailable,
+            12 => MalformedRequest,
+            13 => Crash,
+            14 => Abort,
+            20 => KeyDoesNotExist,
+            21 => KeyAlreadyExists,
+            22 => PreconditionFailed,
+            30 => TxnConflict,
+            _ => return Err(UnknownErrorCode(value)),
+        })
+    }
+}
+
+/// Implemented by payloads that can carry a Maelstrom error,
+/// so error responses can be recognized without knowing the concrete payload.
+/// Services such as seq-kv reply with the same errors nodes do, so a node can
+/// detect failed service requests the same way, see `Network::rpc_checked`.
+/// `payload!(errors, ..)` implements it for payloads with an `Error(ErrorBody)` variant.
+pub trait IsError {
+    /// Returns the error code if this payload is an error
+    fn error_code(&self) -> Option<ErrorCode>;
+}
+
+/// Unknown codes are treated as not being errors
+impl IsError for ErrorBody {
+    fn error_code(&self) -> Option<ErrorCode> {
+        ErrorCode::try_from(self.code).ok()
+    }
+}
+
+/// Implemented by payloads that can represent a Maelstrom error,
+/// so the runtime can reply with an error on the node's behalf
+pub trait MaelstromError {
+    /// Constructs an error payload with the given code and text
+    fn error(code: ErrorCode, text: String) -> Self;
+}
+
+/// Builds an error reply to `request` without consuming it,
+/// addressed back to the request's source and in reply to its msg_id.
+/// See `Message::into_error` to reply with a request the node is done with.
+pub fn error_reply<P: MaelstromError>(
+    request: &Message<P>,
+    code: ErrorCode,
+    text: &str,
+) -> Message<P> {
+    let mut body = BodyBuilder::new(P::error(code, text.to_string()));
+    if let Some(msg_id) = request.body.msg_id {
+        body = body.in_reply_to(msg_id);
+    }
+
+    Message::new(&request.dest, &request.src, body.build())
+}
+
+/// Decides which errors a retry loop should retry.
+/// Transient errors are always retried. Indefinite errors like `Crash` may have been applied,
+/// so they are only retried when the operation is idempotent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub idempotent: bool,
+}
+
+impl RetryPolicy {
+    /// Policy for operations that are safe to apply more than once
+    pub fn idempotent() -> Self {
+        RetryPolicy { idempotent: true }
+    }
+
+    /// Returns true for errors that may succeed if the request is sent again unchanged
+    pub fn is_transient(code: ErrorCode) -> bool {
+        use ErrorCode::*;
+        matches!(code, Timeout | TemporarilyUnavailable | Abort | TxnConflict)
+    }
+
+    /// Returns true if a request that failed with `code` should be retried
+    pub fn should_retry(&self, code: ErrorCode) -> bool {
+        RetryPolicy::is_transient(code) || (self.idempotent && !is_definite(code))
+    }
+}
+
 // useless, I just love pattern matching :)
 pub fn is_definite(error: ErrorCode) -> bool {
     use ErrorCode::*;
@@ -31,10 +158,120 @@ pub fn is_definite(error: ErrorCode) -> bool {
 #[cfg(test)]
 mod tests {
 
+    use crate::{payload, types::ErrorBody};
+
     use super::*;
 
+    payload!(
+        enum KvPayload {
+            Read { key: String },
+            Error(ErrorBody),
+        }
+    );
+
+    impl MaelstromError for KvPayload {
+        fn error(code: ErrorCode, text: String) -> Self {
+            KvPayload::Error(ErrorBody::new(code, text))
+        }
+    }
+
+    #[test]
+    fn test_error_reply() {
+        let request = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(KvPayload::Read { key: "x".into() })
+                .msg_id(4)
+                .build(),
+        );
+
+        let reply = error_reply(&request, ErrorCode::KeyDoesNotExist, "no such key");
+        assert_eq!("n1", reply.src);
+        assert_eq!("c1", reply.dest);
+        assert_eq!(Some(4), reply.body.in_reply_to);
+        assert_eq!(None, reply.body.msg_id);
+        assert_eq!(
+            r#"{"type":"error","code":20,"text":"no such key"}"#,
+            serde_json::to_string(&reply.body.payload).unwrap()
+        );
+    }
+
     #[test]
     fn test_compare_usize() {
         assert_eq!(0, usize::from(ErrorCode::Timeout))
     }
+
+    #[test]
+    fn test_round_trip_usize() {
+        use ErrorCode::*;
+        for code in [
+            Timeout,
+            NodeNotFound,
+            NotSupported,
+            TemporarilyUnavailable,
+            MalformedRequest,
+            Crash,
+            Abort,
+            KeyDoesNotExist,
+            KeyAlreadyExists,
+            PreconditionFailed,
+            TxnConflict,
+        ] {
+            assert_eq!(Ok(code), ErrorCode::try_from(usize::from(code)));
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            "precondition-failed",
+            ErrorCode::PreconditionFailed.to_string()
+        );
+        assert_eq!("timeout", ErrorCode::Timeout.to_string());
+    }
+
+    #[test]
+    fn test_error_in_anyhow() {
+        let missing = || -> anyhow::Result<()> { Err(ErrorCode::KeyDoesNotExist)? };
+        let error = missing().unwrap_err();
+        assert_eq!(
+            Some(&ErrorCode::KeyDoesNotExist),
+            error.downcast_ref::<ErrorCode>()
+        );
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        use ErrorCode::*;
+        // code, transient, retried by default, retried when idempotent
+        let table = [
+            (Timeout, true, true, true),
+            (NodeNotFound, false, false, false),
+            (NotSupported, false, false, false),
+            (TemporarilyUnavailable, true, true, true),
+            (MalformedRequest, false, false, false),
+            (Crash, false, false, true),
+            (Abort, true, true, true),
+            (KeyDoesNotExist, false, false, false),
+            (KeyAlreadyExists, false, false, false),
+            (PreconditionFailed, false, false, false),
+            (TxnConflict, true, true, true),
+        ];
+
+        for (code, transient, retry, retry_idempotent) in table {
+            assert_eq!(transient, RetryPolicy::is_transient(code), "{code}");
+            assert_eq!(retry, RetryPolicy::default().should_retry(code), "{code}");
+            assert_eq!(
+                retry_idempotent,
+                RetryPolicy::idempotent().should_retry(code),
+                "{code}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_code() {
+        assert_eq!(Err(UnknownErrorCode(2)), ErrorCode::try_from(2));
+        assert_eq!(Err(UnknownErrorCode(1000)), ErrorCode::try_from(1000));
+    }
 }
@@ -0,0 +1,207 @@
+//! Defines how inbound messages are split out of a byte stream
+
+use std::io::{self, BufRead, ErrorKind, Read};
+
+/// Splits a stream of bytes into frames, each containing one serialized message
+pub trait Framer {
+    /// Reads the next frame, returning None once the stream is exhausted
+    fn next_frame(&mut self) -> io::Result<Option<String>>;
+
+    /// Reads the next frame without requiring it to be UTF-8, for binary codecs
+    fn next_frame_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.next_frame()?.map(String::into_bytes))
+    }
+}
+
+impl<F: Framer + ?Sized> Framer for Box<F> {
+    fn next_frame(&mut self) -> io::Result<Option<String>> {
+        (**self).next_frame()
+    }
+
+    fn next_frame_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        (**self).next_frame_bytes()
+    }
+}
+
+/// Newline delimited frames, as used by Maelstrom
+#[derive(Debug)]
+pub struct LineFramer<R> {
+    reader: R,
+}
+
+impl<R: BufRead> LineFramer<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+}
+
+impl<R: BufRead> Framer for LineFramer<R> {
+    fn next_frame(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some(line))
+    }
+}
+
+/// Newline delimited frames read in batches, each frame holding every complete line
+/// already buffered, up to `max`, still newline separated. Only reading what's buffered
+/// means a batch never waits on lines that haven't arrived yet.
+/// The first frame is only the first line, so the runtime reads init on its own.
+#[derive(Debug)]
+pub struct BatchedLineFramer<R> {
+    lines: LineFramer<R>,
+    max: usize,
+    started: bool,
+}
+
+impl<R: BufRead> BatchedLineFramer<R> {
+    pub fn new(reader: R, max: usize) -> Self {
+        Self {
+            lines: LineFramer::new(reader),
+            max: max.max(1),
+            started: false,
+        }
+    }
+}
+
+impl<R: BufRead> Framer for BatchedLineFramer<R> {
+    fn next_frame(&mut self) -> io::Result<Option<String>> {
+        match self.next_frame_bytes()? {
+            Some(frame) => String::from_utf8(frame)
+                .map(Some)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn next_frame_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !std::mem::replace(&mut self.started, true) {
+            return self.lines.next_frame_bytes();
+        }
+
+        // only blocks when nothing is buffered
+        let buffered = self.lines.reader.fill_buf()?;
+        let len: usize = buffered
+            .split_inclusive(|b| *b == b'\n')
+            .take(self.max)
+            .take_while(|line| line.ends_with(b"\n"))
+            .map(<[u8]>::len)
+            .sum();
+
+        if len == 0 {
+            // a line split across reads, or the end of the stream
+            return self.lines.next_frame_bytes();
+        }
+
+        // the last line's newline is dropped, like LineFramer's
+        let batch = buffered[..len - 1].to_vec();
+        self.lines.reader.consume(len);
+        Ok(Some(batch))
+    }
+}
+
+/// Frames prefixed with their length in bytes as a big-endian u32
+#[derive(Debug)]
+pub struct LengthPrefixedFramer<R> {
+    reader: R,
+}
+
+impl<R: Read> LengthPrefixedFramer<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    /// Encodes a frame with its length prefix
+    pub fn encode(frame: &str) -> Vec<u8> {
+        let mut encoded = (frame.len() as u32).to_be_bytes().to_vec();
+        encoded.extend_from_slice(frame.as_bytes());
+        encoded
+    }
+}
+
+impl<R: Read> Framer for LengthPrefixedFramer<R> {
+    fn next_frame(&mut self) -> io::Result<Option<String>> {
+        match self.next_frame_bytes()? {
+            Some(frame) => String::from_utf8(frame)
+                .map(Some)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn next_frame_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut len = [0; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+        self.reader.read_exact(&mut frame)?;
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_line_framer() -> io::Result<()> {
+        let mut framer = LineFramer::new(Cursor::new("{\"a\":1}\n{\"b\":2}\r\n{\"c\":3}"));
+
+        assert_eq!(Some("{\"a\":1}".to_string()), framer.next_frame()?);
+        assert_eq!(Some("{\"b\":2}".to_string()), framer.next_frame()?);
+        assert_eq!(Some("{\"c\":3}".to_string()), framer.next_frame()?);
+        assert_eq!(None, framer.next_frame()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_line_framer() -> io::Result<()> {
+        let mut framer = BatchedLineFramer::new(Cursor::new("a\nb\nc\nd\ne"), 2);
+
+        assert_eq!(Some("a".to_string()), framer.next_frame()?);
+        assert_eq!(Some("b\nc".to_string()), framer.next_frame()?);
+        assert_eq!(Some("d".to_string()), framer.next_frame()?);
+        assert_eq!(Some("e".to_string()), framer.next_frame()?);
+        assert_eq!(None, framer.next_frame()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_prefixed_framer() -> io::Result<()> {
+        let mut stream = LengthPrefixedFramer::<&[u8]>::encode("{\"a\":\n1}");
+        stream.extend(LengthPrefixedFramer::<&[u8]>::encode(""));
+        stream.extend(LengthPrefixedFramer::<&[u8]>::encode("{\"b\":2}"));
+
+        let mut framer = LengthPrefixedFramer::new(Cursor::new(stream));
+        assert_eq!(Some("{\"a\":\n1}".to_string()), framer.next_frame()?);
+        assert_eq!(Some(String::new()), framer.next_frame()?);
+        assert_eq!(Some("{\"b\":2}".to_string()), framer.next_frame()?);
+        assert_eq!(None, framer.next_frame()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_prefixed_truncated() {
+        let mut stream = LengthPrefixedFramer::<&[u8]>::encode("{\"a\":1}");
+        stream.truncate(6);
+
+        let mut framer = LengthPrefixedFramer::new(Cursor::new(stream));
+        assert!(framer.next_frame().is_err());
+    }
+}
@@ -0,0 +1,516 @@
+//! Defines a client for Maelstrom's key-value services
+//! https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    error::ErrorCode,
+    network::{Network, RpcHandle, RpcReceiver, RpcTimeout},
+    payload,
+    types::{BodyBuilder, ErrorBody, Message, Payload, Service, Try},
+};
+
+/// Sequentially consistent key-value service
+#[deprecated(note = "use `Service::SeqKv`")]
+pub const SEQ_KV: &str = "seq-kv";
+/// Linearizable key-value service
+#[deprecated(note = "use `Service::LinKv`")]
+pub const LIN_KV: &str = "lin-kv";
+/// Last-write-wins key-value service
+#[deprecated(note = "use `Service::LwwKv`")]
+pub const LWW_KV: &str = "lww-kv";
+
+payload!(
+    errors,
+    /// Payload for requests to and responses from a key-value service.
+    /// Services store any JSON value, so values are kept as `Value`.
+    pub enum Kv {
+        Read {
+            key: String,
+        },
+        ReadOk {
+            value: Value,
+        },
+        Write {
+            key: String,
+            value: Value,
+        },
+        WriteOk,
+        Cas {
+            key: String,
+            from: Value,
+            to: Value,
+            create_if_not_exists: bool,
+        },
+        CasOk,
+        Error(ErrorBody),
+    }
+);
+
+/// Client for a Maelstrom key-value service such as seq-kv, lin-kv, or lww-kv.
+/// Requests are sent on the node's network, so the node's payload must include
+/// variants with the same shape as the `Kv` variants it uses,
+/// with value fields able to hold the values it reads and writes.
+///
+/// Values are any serializable type, sent as JSON, so a cas compares
+/// the serialized form of `from` against the stored value.
+///
+/// Error replies from the service fail with the `ErrorCode` they carry,
+/// which can be recovered with `downcast_ref::<ErrorCode>()`.
+/// Requests wait for their response indefinitely unless a timeout is set with `with_timeout`.
+#[derive(Debug, Clone)]
+pub struct KvClient<P> {
+    network: Network<P>,
+    node_id: String,
+    service: Service,
+    timeout: Option<Duration>,
+}
+
+impl<P: Payload> KvClient<P> {
+    /// Constructs a client sending requests from `node_id` to `service`,
+    /// such as `Service::SeqKv`. Requests take their msg_ids from `network`.
+    pub fn new(network: Network<P>, node_id: impl Into<String>, service: Service) -> Self {
+        Self {
+            network,
+            node_id: node_id.into(),
+            service,
+            timeout: None,
+        }
+    }
+
+    /// Fails requests with `RpcTimeout` if the service doesn't respond within `timeout`.
+    /// A timed out write or cas may still have been applied.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The service this client sends requests to
+    pub fn service(&self) -> Service {
+        self.service
+    }
+
+    /// The network requests are sent on
+    pub fn network(&self) -> &Network<P> {
+        &self.network
+    }
+
+    /// Reads the value of `key`,
+    /// failing with `KeyDoesNotExist` if it has never been written
+    pub fn read<V: DeserializeOwned>(&self, key: impl Into<String>) -> anyhow::Result<V> {
+        read_value(self.call(Kv::Read { key: key.into() })?)
+    }
+
+    /// Reads each of `keys`, returning their results in the same order.
+    /// Every read is sent before waiting on any response, so they take
+    /// about one round trip in total rather than one each.
+    pub fn read_many<V: DeserializeOwned>(&self, keys: &[String]) -> Vec<anyhow::Result<V>> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let pending: Vec<_> = keys
+            .iter()
+            .map(|key| self.start(Kv::Read { key: key.clone() }))
+            .collect();
+
+        pending
+            .into_iter()
+            .map(|rpc| {
+                let (handle, rx) = rpc?;
+                let response = match deadline {
+                    Some(deadline) => self.wait_until(handle, rx, deadline)?,
+                    None => rx.recv()?,
+                };
+
+                read_value(convert(&response.body.payload)?)
+            })
+            .collect()
+    }
+
+    /// Unconditionally sets `key` to `value`
+    pub fn write<V: Serialize>(&self, key: impl Into<String>, value: V) -> Try {
+        let write = Kv::Write {
+            key: key.into(),
+            value: serde_json::to_value(value)?,
+        };
+
+        match self.call(write)? {
+            Kv::WriteOk => Ok(()),
+            Kv::Error(error) => Err(service_error("write", error)),
+            other => bail!("expected write_ok, got {other:?}"),
+        }
+    }
+
+    /// Sets `key` to `to` if its current value is `from`,
+    /// failing with `PreconditionFailed` if it is not.
+    /// A missing key is created with `to` if `create_if_not_exists` is set,
+    /// otherwise the cas fails with `KeyDoesNotExist`.
+    pub fn cas<V: Serialize>(
+        &self,
+        key: impl Into<String>,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+    ) -> Try {
+        let cas = Kv::Cas {
+            key: key.into(),
+            from: serde_json::to_value(from)?,
+            to: serde_json::to_value(to)?,
+            create_if_not_exists,
+        };
+
+        match self.call(cas)? {
+            Kv::CasOk => Ok(()),
+            Kv::Error(error) => Err(service_error("cas", error)),
+            other => bail!("expected cas_ok, got {other:?}"),
+        }
+    }
+
+    fn call(&self, request: Kv) -> anyhow::Result<Kv> {
+        let request = self.message(request)?;
+        let response = match self.timeout {
+            Some(timeout) => self.network.rpc_timeout(request, timeout)?,
+            None => self.network.rpc(request)?.recv()?,
+        };
+        convert(&response.body.payload)
+    }
+
+    /// Sends a request without waiting for its response,
+    /// returning the handle to cancel it and the receiver the response arrives on
+    fn start(&self, request: Kv) -> anyhow::Result<(RpcHandle<P>, RpcReceiver<P>)> {
+        let request = self.message(request)?;
+        self.network.rpc_cancellable(request)
+    }
+
+    /// Waits for the response to a started request until `deadline`, like `Network::rpc_timeout`
+    /// the callback is removed if none arrives so it isn't left pending
+    fn wait_until(
+        &self,
+        handle: RpcHandle<P>,
+        rx: RpcReceiver<P>,
+        deadline: Instant,
+    ) -> anyhow::Result<Message<P>> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if let Ok(response) = rx.recv_timeout(remaining) {
+            return Ok(response);
+        }
+
+        let msg_id = handle.msg_id();
+        handle.cancel();
+
+        // the response may have arrived between the timeout and cancelling the callback
+        match rx.try_recv() {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                self.network.counters().rpc_timeout();
+                Err(RpcTimeout { msg_id }.into())
+            }
+        }
+    }
+
+    fn message(&self, request: Kv) -> anyhow::Result<Message<P>> {
+        let msg_id = self.network.next_id();
+        let body = BodyBuilder::new(convert(&request)?).msg_id(msg_id).build();
+        Ok(Message::new(&self.node_id, self.service, body))
+    }
+}
+
+/// Takes the value from a read response
+fn read_value<V: DeserializeOwned>(response: Kv) -> anyhow::Result<V> {
+    match response {
+        Kv::ReadOk { value } => Ok(serde_json::from_value(value)?),
+        Kv::Error(error) => Err(service_error("read", error)),
+        other => bail!("expected read_ok, got {other:?}"),
+    }
+}
+
+/// Converts an error reply into its ErrorCode, with the text as context
+fn service_error(operation: &str, error: ErrorBody) -> anyhow::Error {
+    match ErrorCode::try_from(error.code) {
+        Ok(code) => anyhow::Error::new(code).context(format!("{operation} failed: {}", error.text)),
+        Err(_) => anyhow!("{operation} failed with {error}"),
+    }
+}
+
+/// Converts between payload types with the same serialized shape
+fn convert<A: Serialize, B: DeserializeOwned>(from: &A) -> anyhow::Result<B> {
+    Ok(serde_json::from_value(serde_json::to_value(from)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, thread};
+
+    use parking_lot::Mutex;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    payload!(
+        enum NodePayload {
+            Add {
+                delta: usize,
+            },
+            Read {
+                key: String,
+            },
+            ReadOk {
+                value: Value,
+            },
+            Write {
+                key: String,
+                value: Value,
+            },
+            WriteOk,
+            Cas {
+                key: String,
+                from: Value,
+                to: Value,
+                create_if_not_exists: bool,
+            },
+            CasOk,
+            Error(ErrorBody),
+        }
+    );
+
+    type Store = Arc<Mutex<HashMap<String, Value>>>;
+
+    /// Answers kv requests from `store` as `service`
+    fn kv_service(service: Service) -> (Network<NodePayload>, Store) {
+        let (network, outbound) = Network::new();
+        let store: Store = Arc::default();
+
+        let responder = network.clone();
+        let responder_store = store.clone();
+        thread::spawn(move || {
+            for msg in outbound {
+                assert_eq!(msg.dest, service.as_str());
+                let mut store = responder_store.lock();
+                let missing =
+                    || NodePayload::Error(ErrorBody::new(ErrorCode::KeyDoesNotExist, "missing"));
+
+                let payload = match &msg.body.payload {
+                    NodePayload::Read { key } => match store.get(key) {
+                        Some(value) => NodePayload::ReadOk {
+                            value: value.clone(),
+                        },
+                        None => missing(),
+                    },
+                    NodePayload::Write { key, value } => {
+                        store.insert(key.clone(), value.clone());
+                        NodePayload::WriteOk
+                    }
+                    NodePayload::Cas {
+                        key,
+                        from,
+                        to,
+                        create_if_not_exists,
+                    } => match store.get(key) {
+                        Some(current) if current == from => {
+                            store.insert(key.clone(), to.clone());
+                            NodePayload::CasOk
+                        }
+                        Some(current) => NodePayload::Error(ErrorBody::new(
+                            ErrorCode::PreconditionFailed,
+                            format!("expected {from}, was {current}"),
+                        )),
+                        None if *create_if_not_exists => {
+                            store.insert(key.clone(), to.clone());
+                            NodePayload::CasOk
+                        }
+                        None => missing(),
+                    },
+                    _ => continue,
+                };
+
+                let reply = msg.into_reply(payload);
+                assert_eq!(None, responder.check_callback(reply));
+            }
+        });
+
+        (network, store)
+    }
+
+    fn code(error: anyhow::Error) -> Option<ErrorCode> {
+        error.downcast_ref::<ErrorCode>().copied()
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_service_names() {
+        assert_eq!(Service::SeqKv.as_str(), SEQ_KV);
+        assert_eq!(Service::LinKv.as_str(), LIN_KV);
+        assert_eq!(Service::LwwKv.as_str(), LWW_KV);
+    }
+
+    #[test]
+    fn test_serialize_write() -> Try {
+        let write = Kv::Write {
+            key: "n1".into(),
+            value: json!(3),
+        };
+        assert_eq!(
+            serde_json::to_string(&write)?,
+            r#"{"type":"write","key":"n1","value":3}"#
+        );
+
+        let write_ok: Kv = serde_json::from_str(r#"{"type":"write_ok"}"#)?;
+        assert_eq!(write_ok, Kv::WriteOk);
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_write() -> Try {
+        let (network, store) = kv_service(Service::LwwKv);
+
+        let client = KvClient::new(network, "n1", Service::LwwKv);
+        client.write("n1", 5)?;
+        client.write("n1", 7)?;
+
+        assert_eq!(Some(&json!(7)), store.lock().get("n1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_read() -> Try {
+        let (network, store) = kv_service(Service::SeqKv);
+        store.lock().insert("n1".into(), json!(4));
+
+        let client = KvClient::new(network, "n1", Service::SeqKv);
+        assert_eq!(4, client.read::<usize>("n1")?);
+
+        let missing = client.read::<usize>("n2").unwrap_err();
+        assert_eq!(Some(ErrorCode::KeyDoesNotExist), code(missing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_cas() -> Try {
+        let (network, store) = kv_service(Service::LinKv);
+
+        let client = KvClient::new(network, "n1", Service::LinKv);
+        let missing = client.cas("n1", 0, 1, false).unwrap_err();
+        assert_eq!(Some(ErrorCode::KeyDoesNotExist), code(missing));
+
+        client.cas("n1", 0, 1, true)?;
+        client.cas("n1", 1, 3, false)?;
+
+        let stale = client.cas("n1", 1, 5, false).unwrap_err();
+        assert_eq!(Some(ErrorCode::PreconditionFailed), code(stale));
+        assert_eq!(Some(&json!(3)), store.lock().get("n1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_list_value() -> Try {
+        let (network, store) = kv_service(Service::LinKv);
+
+        let client = KvClient::new(network, "n1", Service::LinKv);
+        client.write("list", vec![1_i64, -2])?;
+        assert_eq!(vec![1_i64, -2], client.read::<Vec<i64>>("list")?);
+
+        client.cas("list", vec![1_i64, -2], vec![1, -2, 3], false)?;
+        assert_eq!(Some(&json!([1, -2, 3])), store.lock().get("list"));
+
+        let stale = client.cas("list", vec![1_i64], vec![4], false).unwrap_err();
+        assert_eq!(Some(ErrorCode::PreconditionFailed), code(stale));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_timeout() {
+        // nothing answers requests sent on this network
+        let (network, _outbound) = Network::<NodePayload>::new();
+
+        let client =
+            KvClient::new(network, "n1", Service::SeqKv).with_timeout(Duration::from_millis(10));
+        let timeout = client.read::<usize>("n1").unwrap_err();
+        assert_eq!(
+            Some(&RpcTimeout { msg_id: 0 }),
+            timeout.downcast_ref::<RpcTimeout>()
+        );
+    }
+
+    #[test]
+    fn test_client_read_many() {
+        let (network, outbound) = Network::<NodePayload>::new();
+
+        // only answers once every read is in flight, sequential reads would time out
+        let responder = network.clone();
+        thread::spawn(move || {
+            let reads: Vec<_> = outbound.iter().take(3).collect();
+            for msg in reads {
+                let payload = match &msg.body.payload {
+                    NodePayload::Read { key } if key == "n3" => {
+                        NodePayload::Error(ErrorBody::new(ErrorCode::KeyDoesNotExist, "missing"))
+                    }
+                    NodePayload::Read { key } => NodePayload::ReadOk {
+                        value: json!(key.len() * 10),
+                    },
+                    _ => continue,
+                };
+                responder.check_callback(msg.into_reply(payload));
+            }
+        });
+
+        let client =
+            KvClient::new(network, "n1", Service::SeqKv).with_timeout(Duration::from_secs(5));
+        let keys = ["n1".to_string(), "n22".to_string(), "n3".to_string()];
+        let mut reads = client.read_many::<usize>(&keys).into_iter();
+        assert_eq!(20, reads.next().unwrap().unwrap());
+        assert_eq!(30, reads.next().unwrap().unwrap());
+        let missing = reads.next().unwrap().unwrap_err();
+        assert_eq!(Some(ErrorCode::KeyDoesNotExist), code(missing));
+    }
+
+    #[test]
+    fn test_client_read_many_timeout() {
+        // nothing answers requests sent on this network
+        let (network, _outbound) = Network::<NodePayload>::new();
+
+        let client = KvClient::new(network.clone(), "n1", Service::SeqKv)
+            .with_timeout(Duration::from_millis(10));
+        let keys = ["n1".to_string(), "n2".to_string()];
+        let reads = client.read_many::<usize>(&keys);
+
+        let timeouts: Vec<_> = reads
+            .into_iter()
+            .map(|read| read.unwrap_err().downcast::<RpcTimeout>().unwrap())
+            .collect();
+        assert_eq!(
+            vec![RpcTimeout { msg_id: 0 }, RpcTimeout { msg_id: 1 }],
+            timeouts
+        );
+
+        // the timed out reads don't leave their callbacks behind
+        assert_eq!(0, network.pending_rpcs());
+        assert_eq!(2, network.metrics().rpc_timeouts);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        owner: String,
+        balance: i64,
+    }
+
+    #[test]
+    fn test_client_struct_value() -> Try {
+        let (network, store) = kv_service(Service::SeqKv);
+
+        let client = KvClient::new(network, "n1", Service::SeqKv);
+        let account = Account {
+            owner: "c1".into(),
+            balance: 10,
+        };
+        client.write("account", &account)?;
+        assert_eq!(account, client.read("account")?);
+        assert_eq!(
+            Some(&json!({"owner": "c1", "balance": 10})),
+            store.lock().get("account")
+        );
+        Ok(())
+    }
+}
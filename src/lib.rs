@@ -1,6 +1,19 @@
+pub mod clock;
+pub mod codec;
 pub mod error;
+pub mod framing;
+pub mod kv;
+pub mod log;
+pub mod metrics;
 pub mod network;
 pub mod node;
+pub mod partition;
 pub mod payload;
+pub mod peer;
+pub mod protocol;
+pub mod repair;
 pub mod runtime;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod transport;
 pub mod types;
@@ -0,0 +1,139 @@
+//! Leveled logging to stderr.
+//! The level is read once from the `MAELBREAKER_LOG` env var,
+//! one of `off`, `warn`, `info` or `debug`, and defaults to `info`.
+//! Lines are written as `[level][node_id] message`.
+
+use std::{
+    env,
+    fmt::{Arguments, Display},
+    sync::OnceLock,
+};
+
+const LOG_VAR: &str = "MAELBREAKER_LOG";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// Parses a level name, as used in `MAELBREAKER_LOG`
+    pub fn parse(name: &str) -> Option<Level> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Level::Off),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Level::Off => "off",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        };
+        write!(f, "{name}")
+    }
+}
+
+static LEVEL: OnceLock<Level> = OnceLock::new();
+static NODE_ID: OnceLock<String> = OnceLock::new();
+
+/// The configured level, read from the environment on first use
+pub fn level() -> Level {
+    *LEVEL.get_or_init(|| {
+        env::var(LOG_VAR)
+            .ok()
+            .and_then(|name| Level::parse(&name))
+            .unwrap_or(Level::Info)
+    })
+}
+
+/// Returns true if messages at `level` are written
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level <= self::level()
+}
+
+/// Sets the node id included in log lines, called by the runtime on init.
+/// Only the first node id in a process is kept.
+pub fn set_node_id(node_id: &str) {
+    let _ = NODE_ID.set(node_id.to_string());
+}
+
+fn format_line(level: Level, node_id: Option<&str>, args: Arguments) -> String {
+    format!("[{level}][{}] {args}", node_id.unwrap_or("-"))
+}
+
+#[doc(hidden)]
+pub fn write(level: Level, args: Arguments) {
+    eprintln!(
+        "{}",
+        format_line(level, NODE_ID.get().map(String::as_str), args)
+    );
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, format_args!($($arg)*));
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_debug {
+    ($($arg:tt)*) => { $crate::__log!($crate::log::Level::Debug, $($arg)*) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_info {
+    ($($arg:tt)*) => { $crate::__log!($crate::log::Level::Info, $($arg)*) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_warn {
+    ($($arg:tt)*) => { $crate::__log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+/// Logs at debug level, for per-message detail
+pub use __log_debug as debug;
+/// Logs at info level, for lifecycle events
+pub use __log_info as info;
+/// Logs at warn level, for failures the node recovers from
+pub use __log_warn as warn;
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(Some(Level::Off), Level::parse("off"));
+        assert_eq!(Some(Level::Debug), Level::parse(" DEBUG "));
+        assert_eq!(None, Level::parse("verbose"));
+        assert!(Level::Debug > Level::Info);
+    }
+
+    #[test]
+    fn test_format_line() {
+        let line = format_line(Level::Warn, Some("n1"), format_args!("rpc {} failed", 3));
+        assert_eq!("[warn][n1] rpc 3 failed", line);
+
+        let line = format_line(Level::Info, None, format_args!("starting"));
+        assert_eq!("[info][-] starting", line);
+    }
+}
@@ -0,0 +1,76 @@
+//! Defines Metrics, counts of what a node has done for profiling a run
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counts of what a node has done since it started,
+/// see `Network::metrics` and `RuntimeHandle::metrics`.
+/// Receiving far more than is sent points at a starved node, the reverse at a flooding one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// messages read from the input, including responses to rpcs
+    pub received: usize,
+    /// messages sent on the network, including replies and rpcs
+    pub sent: usize,
+    /// rpcs sent
+    pub rpcs: usize,
+    /// rpcs given up on before a response arrived
+    pub rpc_timeouts: usize,
+    /// messages the node failed to handle, including panics
+    pub handler_errors: usize,
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "received {}, sent {}, rpcs {}, rpc timeouts {}, handler errors {}",
+            self.received, self.sent, self.rpcs, self.rpc_timeouts, self.handler_errors
+        )
+    }
+}
+
+/// The counters behind `Metrics`, shared by a runtime and every clone of its network
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    received: AtomicUsize,
+    sent: AtomicUsize,
+    rpcs: AtomicUsize,
+    rpc_timeouts: AtomicUsize,
+    handler_errors: AtomicUsize,
+}
+
+impl Counters {
+    pub(crate) fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rpc(&self) {
+        self.rpcs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rpc_timeout(&self) {
+        self.rpc_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handler_error(&self) {
+        self.handler_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts so far, each read on its own so they may be slightly out of step
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            received: self.received.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            rpcs: self.rpcs.load(Ordering::Relaxed),
+            rpc_timeouts: self.rpc_timeouts.load(Ordering::Relaxed),
+            handler_errors: self.handler_errors.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,24 +1,261 @@
 //! Defines the Network struct and implementation
 use std::{
-    collections::HashMap,
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        HashMap,
+    },
+    error::Error,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    ops::Deref,
     sync::{
-        mpsc::{channel, Receiver, SendError, Sender},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{channel, sync_channel, Receiver, SendError, Sender, SyncSender, TrySendError},
+        Arc, OnceLock, Weak,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, bail};
+use anyhow::{anyhow, bail, Context};
 use parking_lot::Mutex;
 
-use crate::types::{Message, Payload, Rpc, Try};
+use crate::{
+    error::IsError,
+    log,
+    metrics::{Counters, Metrics},
+    types::{BodyBuilder, Message, Payload, Rpc, Try},
+};
+
+/// Identifies a pending RPC by the peer its response is expected from and its msg_id,
+/// so responses from different peers to the same msg_id can't be mixed up
+type CallbackKey = (String, usize);
+type Callbacks<P> = Arc<Mutex<HashMap<CallbackKey, Callback<P>>>>;
+
+/// A pending RPC, alive for as long as someone holds its receiver
+#[derive(Debug)]
+struct Callback<P> {
+    tx: Sender<Message<P>>,
+    receiver: Weak<()>,
+    /// src and dest of the request
+    src: String,
+    dest: String,
+    /// when the reaper gives up on a response, if there is one
+    deadline: Option<Instant>,
+}
+
+/// Receives the response to an RPC, derefs to the underlying Receiver.
+/// Dropping it before the response arrives lets `Network::sweep_callbacks`
+/// discard the pending callback.
+#[derive(Debug)]
+pub struct RpcReceiver<P> {
+    rx: Receiver<Message<P>>,
+    _alive: Arc<()>,
+}
+
+impl<P> Deref for RpcReceiver<P> {
+    type Target = Receiver<Message<P>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rx
+    }
+}
+
+/// Cancels a pending RPC, see `Network::rpc_cancellable`
+#[derive(Debug)]
+pub struct RpcHandle<P> {
+    callbacks: Weak<Mutex<HashMap<CallbackKey, Callback<P>>>>,
+    dest: String,
+    msg_id: usize,
+}
+
+impl<P> RpcHandle<P> {
+    /// The msg_id of the RPC this handle cancels
+    pub fn msg_id(&self) -> usize {
+        self.msg_id
+    }
+
+    /// Removes the callback for the RPC, so a response arriving later
+    /// is delivered to the node like any other message.
+    /// Returns false if the RPC was no longer pending.
+    pub fn cancel(self) -> bool {
+        let Some(callbacks) = self.callbacks.upgrade() else {
+            return false;
+        };
+
+        let removed = callbacks.lock().remove(&(self.dest, self.msg_id)).is_some();
+        removed
+    }
+}
+
+/// Returned when an RPC receives no response before its deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RpcTimeout {
+    pub msg_id: usize,
+}
+
+impl Display for RpcTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rpc {} timed out", self.msg_id)
+    }
+}
+
+impl Error for RpcTimeout {}
+
+/// Returned when a quorum RPC doesn't gather enough responses
+#[derive(Debug)]
+pub enum QuorumError<P> {
+    /// one of the RPCs could not be sent
+    Send(anyhow::Error),
+    /// fewer than `required` responses arrived before the timeout
+    Timeout {
+        responses: Vec<Message<P>>,
+        required: usize,
+    },
+}
+
+impl<P> Display for QuorumError<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuorumError::Send(e) => write!(f, "failed to send quorum rpc: {e}"),
+            QuorumError::Timeout {
+                responses,
+                required,
+            } => write!(f, "quorum not reached: {}/{required}", responses.len()),
+        }
+    }
+}
+
+impl<P: std::fmt::Debug> Error for QuorumError<P> {}
+
+/// How a network built with `Network::with_capacity` behaves when its outbound channel is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// send waits until the runtime has written enough messages to make room
+    Block,
+    /// send fails immediately with `WouldBlock`
+    Fail,
+}
+
+/// Returned when sending on a full network configured with `Backpressure::Fail`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WouldBlock;
+
+impl Display for WouldBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "outbound channel is full")
+    }
+}
+
+impl Error for WouldBlock {}
+
+/// Returned when sending on a network whose messages are no longer being written,
+/// such as once the runtime's output fails. The network stays closed, so the node
+/// should stop rather than retry, the runtime stops when a handler fails with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelClosed;
+
+impl Display for ChannelClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "outbound channel is closed")
+    }
+}
+
+impl Error for ChannelClosed {}
+
+/// The sending half of the channel between the network and the runtime
+#[derive(Debug, Clone)]
+enum Outbound<P> {
+    Unbounded(Sender<Message<P>>),
+    Bounded(SyncSender<Message<P>>, Backpressure),
+}
+
+/// Fire-and-forget messages sent recently, by destination and a hash of their payload
+#[derive(Debug)]
+struct Dedup {
+    window: Duration,
+    sent: Mutex<Sent>,
+}
+
+#[derive(Debug)]
+struct Sent {
+    at: HashMap<(String, u64), Instant>,
+    /// when expired entries were last swept
+    swept: Instant,
+}
+
+impl Dedup {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            sent: Mutex::new(Sent {
+                at: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Whether the same payload was sent to the same destination within the window,
+    /// recording the message as sent if it wasn't.
+    /// Only messages without a msg_id or in_reply_to are duplicates, so rpcs
+    /// and replies, which someone waits on, are always sent.
+    fn is_duplicate<P: Payload>(&self, msg: &Message<P>) -> bool {
+        if msg.body.msg_id.is_some() || msg.body.in_reply_to.is_some() {
+            return false;
+        }
+
+        let Ok(payload) = serde_json::to_vec(&msg.body.payload) else {
+            return false;
+        };
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let key = (msg.dest.clone(), hasher.finish());
+
+        // a send only checks its own entry's age, the rest are swept once a window
+        let now = Instant::now();
+        let mut sent = self.sent.lock();
+        if now.duration_since(sent.swept) >= self.window {
+            sent.at
+                .retain(|_, at| now.duration_since(*at) < self.window);
+            sent.swept = now;
+        }
 
-type Callbacks<P> = Arc<Mutex<HashMap<usize, Sender<Message<P>>>>>;
+        match sent.at.entry(key) {
+            Entry::Occupied(entry) if now.duration_since(*entry.get()) < self.window => true,
+            Entry::Occupied(mut entry) => {
+                entry.insert(now);
+                false
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                false
+            }
+        }
+    }
+}
 
 /// Network is an abstraction used by Node to communicate with clients, other nodes, and Maelstrom services
 #[derive(Debug, Clone)]
 pub struct Network<P> {
     callbacks: Callbacks<P>,
-    outbound: Sender<Message<P>>,
+    outbound: Outbound<P>,
+    depth: Arc<AtomicUsize>,
+    /// msg_id sequence shared by every clone of the network
+    ids: Arc<AtomicUsize>,
+    /// how long a callback lives before it is reaped, if there is a reaper
+    rpc_ttl: Option<Duration>,
+    /// id of the node the network belongs to, once known
+    node_id: Arc<OnceLock<String>>,
+    /// where messages the node sends to itself are delivered, while the runtime is running
+    inbound: Arc<Mutex<Option<Sender<Message<P>>>>>,
+    /// set once the node stops, so its background work stops too
+    shut_down: Arc<AtomicBool>,
+    /// shared with the runtime, which counts what it reads and handles
+    counters: Arc<Counters>,
+    /// drops repeated fire-and-forget messages, if enabled
+    dedup: Option<Arc<Dedup>>,
+    /// set once the outbound channel is found closed
+    closed: Arc<AtomicBool>,
 }
 
 impl<P: Payload> Network<P> {
@@ -26,20 +263,291 @@ impl<P: Payload> Network<P> {
     /// that will contain outbound messages sent by the Network.
     pub fn new() -> (Self, Receiver<Message<P>>) {
         let (tx, rx) = channel();
-        let network = Self {
+        (Network::from_outbound(Outbound::Unbounded(tx)), rx)
+    }
+
+    /// Constructs a new network like `new`, holding at most `capacity` outbound
+    /// messages that have not been received yet. Sending on a full network
+    /// blocks or fails depending on `backpressure`.
+    pub fn with_capacity(
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> (Self, Receiver<Message<P>>) {
+        let (tx, rx) = sync_channel(capacity);
+        (
+            Network::from_outbound(Outbound::Bounded(tx, backpressure)),
+            rx,
+        )
+    }
+
+    fn from_outbound(outbound: Outbound<P>) -> Self {
+        Self {
             callbacks: Callbacks::default(),
-            outbound: tx,
-        };
+            outbound,
+            depth: Arc::default(),
+            ids: Arc::default(),
+            rpc_ttl: None,
+            node_id: Arc::default(),
+            inbound: Arc::default(),
+            shut_down: Arc::default(),
+            counters: Arc::default(),
+            dedup: None,
+            closed: Arc::default(),
+        }
+    }
+
+    /// Constructs a new network like `new`, dropping a fire-and-forget message if the same
+    /// payload was sent to the same destination within `window`. Nodes that resend
+    /// idempotent messages until acknowledged, such as gossip, send less this way.
+    /// Messages with a msg_id or in_reply_to are never dropped.
+    pub fn with_dedup(window: Duration) -> (Self, Receiver<Message<P>>) {
+        let (network, rx) = Network::new();
+        (network.deduplicating(window), rx)
+    }
+
+    /// Drops repeated fire-and-forget messages, see `with_dedup`
+    pub(crate) fn deduplicating(mut self, window: Duration) -> Self {
+        self.dedup = Some(Arc::new(Dedup::new(window)));
+        self
+    }
+
+    /// Counts what the network does on `counters`, shared with the runtime
+    pub(crate) fn with_counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// Counts outbound messages on `depth`, which the runtime's output thread
+    /// counts down as it writes them
+    pub(crate) fn with_depth(mut self, depth: Arc<AtomicUsize>) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Constructs a new network like `new`, with a background thread that wakes every
+    /// `poll_interval` to reap RPCs that have gone `ttl` without a response.
+    /// The caller of a reaped RPC receives a response carrying `timeout` instead,
+    /// so workers blocked on `recv` unblock cleanly. This requires the payload to
+    /// have a variant that can represent a timeout.
+    /// The reaper stops once every clone of the network is dropped.
+    pub fn with_reaper(
+        poll_interval: Duration,
+        ttl: Duration,
+        timeout: P,
+    ) -> (Self, Receiver<Message<P>>) {
+        let (mut network, rx) = Network::new();
+        network.rpc_ttl = Some(ttl);
+
+        let callbacks = Arc::downgrade(&network.callbacks);
+        let counters = network.counters.clone();
+        thread::spawn(move || loop {
+            thread::sleep(poll_interval);
+            let Some(callbacks) = callbacks.upgrade() else {
+                break;
+            };
+
+            let now = Instant::now();
+            let mut callbacks = callbacks.lock();
+            let expired: Vec<CallbackKey> = callbacks
+                .iter()
+                .filter(|(_, callback)| callback.deadline.is_some_and(|deadline| deadline <= now))
+                .map(|(key, _)| key.clone())
+                .collect();
+
+            for key in expired {
+                let Some(callback) = callbacks.remove(&key) else {
+                    continue;
+                };
+
+                let (_, msg_id) = key;
+                log::warn!("reaped callback for rpc {msg_id}");
+                counters.rpc_timeout();
+                let response = Message::new(
+                    callback.dest,
+                    callback.src,
+                    BodyBuilder::new(timeout.clone())
+                        .in_reply_to(msg_id)
+                        .build(),
+                );
+                let _ = callback.tx.send(response);
+            }
+        });
 
         (network, rx)
     }
 
+    /// Calls `f` every `interval` on a background thread until the network is shut down
+    /// or every clone of it is dropped. Errors from `f` are logged and don't stop the timer.
+    /// A closure holding its own clone of the network keeps the timer alive
+    /// until the network is shut down.
+    pub fn every(&self, interval: Duration, mut f: impl FnMut() -> Try + Send + 'static) {
+        let callbacks = Arc::downgrade(&self.callbacks);
+        let shut_down = self.shut_down.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if callbacks.strong_count() == 0 || shut_down.load(Ordering::SeqCst) {
+                break;
+            }
+
+            if let Err(e) = f() {
+                log::warn!("timer failed: {e:#}");
+            }
+        });
+    }
+
+    /// Signals background work for the node to stop, for every clone of the network.
+    /// The runtime shuts the network down once the node stops handling input.
+    /// Timers started with `every` stop before their next call, and workers
+    /// looping on their own threads should stop once `is_shut_down` returns true.
+    pub fn shut_down(&self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the network has been shut down, see `shut_down`
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Counts of what the node has done so far. Messages received and handler errors
+    /// are counted by the runtime, so they stay zero on a network built outside of one.
+    pub fn metrics(&self) -> Metrics {
+        self.counters.snapshot()
+    }
+
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Sets the id of the node the network belongs to, for every clone of the network.
+    /// Once set, messages the node sends to itself are delivered straight back to it
+    /// rather than being written out. The runtime sets it on init, only the first id is kept.
+    pub fn set_id(&self, node_id: impl Into<String>) {
+        let _ = self.node_id.set(node_id.into());
+    }
+
+    /// The id of the node the network belongs to, if it has been set
+    pub fn id(&self) -> Option<&str> {
+        self.node_id.get().map(String::as_str)
+    }
+
+    /// Delivers messages the node sends to itself to `inbound`, until `close_inbound`
+    pub(crate) fn set_inbound(&self, inbound: Sender<Message<P>>) {
+        *self.inbound.lock() = Some(inbound);
+    }
+
+    /// Stops delivering messages the node sends to itself, called by the runtime
+    /// once input ends so the node's inbound channel can close
+    pub(crate) fn close_inbound(&self) {
+        self.inbound.lock().take();
+    }
+
+    /// Where to deliver a message the node sent to itself, if it did and input is open
+    fn loopback(&self, msg: &Message<P>) -> Option<Sender<Message<P>>> {
+        if self.id() != Some(msg.dest.as_str()) {
+            return None;
+        }
+
+        self.inbound.lock().clone()
+    }
+
     /// Try to send a message on the network,
-    /// fails if the channel is closed.
+    /// fails with `ChannelClosed` if the channel is closed, or with `WouldBlock` if it is full
+    /// and the network was built with `Backpressure::Fail`.
+    /// Messages to the network's own id are delivered back to the node, see `set_id`.
     pub fn send(&self, msg: Message<P>) -> Try {
-        self.outbound
-            .send(msg)
-            .map_err(|_| anyhow!("failed to send message"))
+        if let Some(inbound) = self.loopback(&msg) {
+            // a reply to one of our own rpcs completes it, like one from a peer
+            let Some(msg) = self.check_callback(msg) else {
+                return Ok(());
+            };
+
+            log::debug!("delivering message from {} to itself", msg.src);
+            return inbound
+                .send(msg)
+                .map_err(|_| anyhow!("failed to deliver message to self"));
+        }
+
+        if self.is_closed() {
+            return Err(ChannelClosed.into());
+        }
+
+        if self
+            .dedup
+            .as_ref()
+            .is_some_and(|dedup| dedup.is_duplicate(&msg))
+        {
+            log::debug!("dropping duplicate message to {}", msg.dest);
+            return Ok(());
+        }
+
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        let closed = || {
+            if !self.closed.swap(true, Ordering::SeqCst) {
+                log::warn!("outbound channel closed, messages can no longer be sent");
+            }
+            ChannelClosed.into()
+        };
+        let sent = match &self.outbound {
+            Outbound::Unbounded(tx) => tx.send(msg).map_err(|_| closed()),
+            Outbound::Bounded(tx, Backpressure::Block) => tx.send(msg).map_err(|_| closed()),
+            Outbound::Bounded(tx, Backpressure::Fail) => match tx.try_send(msg) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(WouldBlock.into()),
+                Err(TrySendError::Disconnected(_)) => Err(closed()),
+            },
+        };
+
+        match sent {
+            Ok(()) => self.counters.sent(),
+            Err(_) => {
+                self.depth.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        sent
+    }
+
+    /// Sends a fire-and-forget message with the same payload from `src` to each destination.
+    /// Stops at the first destination the message can't be sent to.
+    pub fn broadcast(&self, src: &str, dests: &[String], payload: P) -> Try {
+        let body = BodyBuilder::new(payload).build();
+        for dest in dests {
+            self.send(Message::new(src, dest, body.clone()))
+                .with_context(|| format!("failed to broadcast to {dest}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Broadcasts the payload from `src` to every other node in `node_ids`,
+    /// returning how many peers it was sent to.
+    pub fn send_peers(&self, src: &str, node_ids: &[String], payload: P) -> anyhow::Result<usize> {
+        let peers: Vec<String> = node_ids.iter().filter(|id| *id != src).cloned().collect();
+        self.broadcast(src, &peers, payload)?;
+        Ok(peers.len())
+    }
+
+    /// Whether sends fail with `ChannelClosed`, once a send found the outbound channel closed
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Number of messages sent on the network that have not yet been written out.
+    /// A growing depth means the node is producing messages faster than they can be written.
+    pub fn outbound_depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Number of RPCs still waiting for a response.
+    /// A count that only grows points at responses that never arrive.
+    pub fn pending_rpcs(&self) -> usize {
+        self.callbacks.lock().len()
+    }
+
+    /// Marks an outbound message as written, for test harnesses that drain the network
+    /// themselves. The runtime's output thread counts down its own handle on the depth.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn mark_written(&self) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
     }
 
     /// Sends a message on the network, returning a Receiver
@@ -47,20 +555,274 @@ impl<P: Payload> Network<P> {
     /// fails if the message cannot be sent, or if there is no msg_id
     /// on the outbound message.
     pub fn rpc(&self, msg: Message<P>) -> Rpc<P> {
-        let mut callbacks = self.callbacks.lock();
-
         let (tx, rx) = channel();
+        let alive = Arc::new(());
+        let key = self.register(&msg, tx, &alive)?;
+        if let Err(e) = self.send(msg) {
+            // nothing will answer an rpc that was never sent
+            self.remove_callbacks(&[key]);
+            return Err(e);
+        }
+
+        self.counters.rpc();
+        Ok(RpcReceiver { rx, _alive: alive })
+    }
+
+    /// Replies to a request with the given payload, consuming the request.
+    /// The reply's msg_id is taken from `next_id`.
+    pub fn reply(&self, request: Message<P>, payload: P) -> Try {
+        let msg_id = self.next_id();
+        self.send(request.into_reply_with_id(payload, Some(msg_id)))
+    }
+
+    /// Returns the next msg_id from a sequence shared by every clone of this network,
+    /// so threads sending on the same network never reuse an id.
+    pub fn next_id(&self) -> usize {
+        self.ids.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Sends the payload from `src` to `dest` as an RPC, stamped with `next_id`
+    pub fn rpc_auto(&self, src: impl Into<String>, dest: impl Into<String>, payload: P) -> Rpc<P> {
+        let body = BodyBuilder::new(payload).msg_id(self.next_id()).build();
+        self.rpc(Message::new(src, dest, body))
+    }
+
+    /// Sends a request for `payload` from `src` to `dest`, the same as `rpc_auto`
+    pub fn request(&self, src: impl Into<String>, dest: impl Into<String>, payload: P) -> Rpc<P> {
+        self.rpc_auto(src, dest, payload)
+    }
+
+    /// Sends a message on the network like `rpc`,
+    /// also returning a handle the caller can use to give up on the response.
+    pub fn rpc_cancellable(
+        &self,
+        msg: Message<P>,
+    ) -> anyhow::Result<(RpcHandle<P>, RpcReceiver<P>)> {
+        let msg_id = msg.body.msg_id.ok_or(anyhow!("rpc must have msg_id"))?;
+        let dest = msg.dest.clone();
+        let callback = self.rpc(msg)?;
+        let handle = RpcHandle {
+            callbacks: Arc::downgrade(&self.callbacks),
+            dest,
+            msg_id,
+        };
+
+        Ok((handle, callback))
+    }
+
+    /// Sends a message on the network and waits up to `timeout` for the response.
+    /// fails with `RpcTimeout` if no response arrives in time, in which case
+    /// the callback is removed so a late response is delivered to the node instead.
+    pub fn rpc_timeout(&self, msg: Message<P>, timeout: Duration) -> anyhow::Result<Message<P>> {
         let msg_id = msg.body.msg_id.ok_or(anyhow!("rpc must have msg_id"))?;
-        if callbacks.insert(msg_id, tx).is_some() {
-            bail!("duplicate message id use for rpc");
+        let dest = msg.dest.clone();
+        let callback = self.rpc(msg)?;
+
+        if let Ok(response) = callback.recv_timeout(timeout) {
+            return Ok(response);
+        }
+
+        self.callbacks.lock().remove(&(dest, msg_id));
+
+        // the response may have arrived between the timeout and removing the callback
+        match callback.try_recv() {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                self.counters.rpc_timeout();
+                Err(RpcTimeout { msg_id }.into())
+            }
+        }
+    }
+
+    /// Sends a message as an RPC and waits for the response.
+    /// Fails with the `ErrorCode` if the response is a Maelstrom error,
+    /// otherwise returns the value `extract` takes from the response.
+    pub fn rpc_checked<T>(
+        &self,
+        msg: Message<P>,
+        extract: impl FnOnce(&P) -> Option<T>,
+    ) -> anyhow::Result<T>
+    where
+        P: IsError,
+    {
+        let response = self.rpc(msg)?.recv()?;
+        let payload = &response.body.payload;
+        if let Some(code) = payload.error_code() {
+            return Err(code.into());
+        }
+
+        extract(payload).ok_or(anyhow!("unexpected response: {payload:?}"))
+    }
+
+    /// Sends a message as an RPC, retrying up to `attempts` times until a response arrives.
+    /// Each attempt waits up to `timeout` for a response, then sleeps `backoff * attempt`
+    /// before retrying. Retries are re-stamped with a fresh msg_id from `next_id`,
+    /// so a late response to an earlier attempt can't be mistaken for the latest one.
+    pub fn rpc_retry(
+        &self,
+        mut msg: Message<P>,
+        attempts: usize,
+        timeout: Duration,
+        backoff: Duration,
+    ) -> anyhow::Result<Message<P>> {
+        let mut last_error = anyhow!("rpc was not attempted");
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                thread::sleep(backoff * (attempt - 1) as u32);
+                msg.body.msg_id = Some(self.next_id());
+            }
+
+            match self.rpc_timeout(msg.clone(), timeout) {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    log::warn!("rpc attempt {attempt}/{attempts} failed: {e}");
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error.context(format!("rpc failed after {attempts} attempts")))
+    }
+
+    /// Sends an RPC built by `make_payload` to each destination, returning as soon as
+    /// a majority of the destinations reply with a message satisfying `predicate`.
+    /// Each RPC is stamped with a msg_id from `next_id`.
+    /// Callbacks for the remaining RPCs are removed once a majority is reached,
+    /// or `timeout` elapses without one.
+    pub fn rpc_broadcast_quorum(
+        &self,
+        src: &str,
+        dests: &[String],
+        mut make_payload: impl FnMut(&str) -> P,
+        predicate: impl Fn(&Message<P>) -> bool,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<Message<P>>> {
+        let majority = dests.len() / 2 + 1;
+        let msgs = dests
+            .iter()
+            .map(|dest| {
+                let body = BodyBuilder::new(make_payload(dest))
+                    .msg_id(self.next_id())
+                    .build();
+                Message::new(src, dest, body)
+            })
+            .collect();
+
+        // send errors keep their type, so callers can still downcast them
+        self.gather(msgs, majority, timeout, predicate)
+            .map_err(|e| match e {
+                QuorumError::Send(e) => e.context("failed to send quorum rpc"),
+                timeout => anyhow!("{timeout}"),
+            })
+    }
+
+    /// Sends each message as an RPC and collects responses until `required` have arrived.
+    /// If `timeout` elapses first, fails with `QuorumError::Timeout` containing the
+    /// responses that did arrive. Callbacks for any remaining RPCs are removed.
+    pub fn rpc_quorum(
+        &self,
+        msgs: Vec<Message<P>>,
+        required: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Message<P>>, QuorumError<P>> {
+        self.gather(msgs, required, timeout, |_| true)
+    }
+
+    fn gather(
+        &self,
+        msgs: Vec<Message<P>>,
+        required: usize,
+        timeout: Duration,
+        accept: impl Fn(&Message<P>) -> bool,
+    ) -> Result<Vec<Message<P>>, QuorumError<P>> {
+        let deadline = Instant::now() + timeout;
+
+        // every rpc shares one channel, so we receive responses in the order they arrive
+        let (tx, rx) = channel();
+        let alive = Arc::new(());
+        let mut pending = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            let sent = self
+                .register(&msg, tx.clone(), &alive)
+                .map(|key| pending.push(key))
+                .and_then(|_| self.send(msg));
+
+            if let Err(e) = sent {
+                self.remove_callbacks(&pending);
+                return Err(QuorumError::Send(e));
+            }
+            self.counters.rpc();
+        }
+        drop(tx);
+
+        let mut responses = Vec::with_capacity(required);
+        while responses.len() < required {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(response) = rx.recv_timeout(remaining) else {
+                break;
+            };
+
+            if accept(&response) {
+                responses.push(response);
+            }
+        }
+
+        self.remove_callbacks(&pending);
+
+        if responses.len() < required {
+            return Err(QuorumError::Timeout {
+                responses,
+                required,
+            });
         }
 
-        eprintln!("registered callback for RPC {msg_id}");
-        self.send(msg)?;
-        Ok(rx)
+        Ok(responses)
+    }
+
+    fn remove_callbacks(&self, keys: &[CallbackKey]) {
+        let mut callbacks = self.callbacks.lock();
+        for key in keys {
+            callbacks.remove(key);
+        }
+    }
+
+    /// Removes callbacks whose receiver has been dropped, returning how many were removed.
+    /// These would otherwise stay registered until a response arrives, which may be never.
+    pub fn sweep_callbacks(&self) -> usize {
+        let mut callbacks = self.callbacks.lock();
+        let before = callbacks.len();
+        callbacks.retain(|_, callback| callback.receiver.strong_count() > 0);
+        before - callbacks.len()
+    }
+
+    /// Registers a callback for the response to an outbound message,
+    /// which stays alive as long as `alive` does. Returns the callback's key.
+    fn register(
+        &self,
+        msg: &Message<P>,
+        tx: Sender<Message<P>>,
+        alive: &Arc<()>,
+    ) -> anyhow::Result<CallbackKey> {
+        let msg_id = msg.body.msg_id.ok_or(anyhow!("rpc must have msg_id"))?;
+        let key = (msg.dest.clone(), msg_id);
+        let mut callbacks = self.callbacks.lock();
+        let Entry::Vacant(entry) = callbacks.entry(key.clone()) else {
+            bail!("duplicate message id use for rpc to {}", msg.dest);
+        };
+
+        entry.insert(Callback {
+            tx,
+            receiver: Arc::downgrade(alive),
+            src: msg.src.clone(),
+            dest: msg.dest.clone(),
+            deadline: self.rpc_ttl.map(|ttl| Instant::now() + ttl),
+        });
+        log::debug!("registered callback for RPC {msg_id}");
+        Ok(key)
     }
 
-    /// Checks if an incoming message is a response to a previously sent RPC.
+    /// Checks if an incoming message is a response to a previously sent RPC,
+    /// one replying to its msg_id from the node the RPC was sent to.
     /// sends the message as a callback and returns None if so, else
     /// returns the message to the caller
     pub fn check_callback(&self, msg: Message<P>) -> Option<Message<P>> {
@@ -70,15 +832,16 @@ impl<P: Payload> Network<P> {
             return Some(msg);
         };
 
-        let Some(callback) = callbacks.remove(&replying_to) else {
+        let Some(callback) = callbacks.remove(&(msg.src.clone(), replying_to)) else {
             return Some(msg);
         };
 
-        if let Err(SendError(msg)) = callback.send(msg) {
+        // the callback has already been removed, so a dropped receiver doesn't leak it
+        if let Err(SendError(msg)) = callback.tx.send(msg) {
             return Some(msg);
         }
 
-        eprintln!("sent callback for rpc {replying_to}");
+        log::debug!("sent callback for rpc {replying_to}");
         None
     }
 }
@@ -86,7 +849,13 @@ impl<P: Payload> Network<P> {
 #[cfg(test)]
 mod tests {
 
-    use crate::{payload, types::Body};
+    use std::collections::HashSet;
+
+    use crate::{
+        error::ErrorCode,
+        payload,
+        types::{Body, ErrorBody},
+    };
 
     use super::*;
 
@@ -142,6 +911,82 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_send_to_self() -> Try {
+        let (network, outbound) = Network::new();
+        let (inbound_tx, inbound) = channel();
+        network.set_id("n1");
+        network.set_inbound(inbound_tx);
+
+        let ping = Message::new("n1", "n1", BodyBuilder::new(PingPong::Ping(0)).build());
+        network.send(ping.clone())?;
+        assert_eq!(ping, inbound.try_recv()?);
+
+        // our reply to our own rpc completes it
+        let response = network.rpc_auto("n1", "n1", PingPong::Ping(1))?;
+        let request = inbound.try_recv()?;
+        network.reply(request, PingPong::Pong(1))?;
+        assert_eq!(PingPong::Pong(1), response.try_recv()?.body.payload);
+        assert!(inbound.try_recv().is_err());
+        assert!(outbound.try_recv().is_err());
+        assert_eq!(0, network.outbound_depth());
+
+        // once input is closed, messages to ourselves are written out like any other
+        network.close_inbound();
+        network.send(ping.clone())?;
+        assert_eq!(ping, outbound.try_recv()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reply() -> Try {
+        let request = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(PingPong::Ping(0)).msg_id(3).build(),
+        );
+
+        let (network, outbound) = Network::new();
+        network.reply(request, PingPong::Pong(0))?;
+
+        let reply = outbound.recv()?;
+        assert_eq!("n1", reply.src);
+        assert_eq!("c1", reply.dest);
+        assert_eq!(Some(3), reply.body.in_reply_to);
+        assert_eq!(Some(0), reply.body.msg_id);
+        assert_eq!(PingPong::Pong(0), reply.body.payload);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reply_ids_from_node_counter() -> Try {
+        let (network, outbound) = Network::new();
+        let request = |src: &str| {
+            Message::new(
+                src,
+                "n1",
+                BodyBuilder::new(PingPong::Ping(0)).msg_id(3).build(),
+            )
+        };
+
+        // ids handed out for other messages aren't reused by replies
+        for _ in 0..5 {
+            network.next_id();
+        }
+        network.reply(request("c1"), PingPong::Pong(0))?;
+        network.reply(request("c2"), PingPong::Pong(0))?;
+
+        // replies to requests with the same msg_id still carry ids of their own,
+        // neither of which is derived from the request's
+        let ids: Vec<_> = outbound
+            .try_iter()
+            .map(|reply| (reply.body.in_reply_to, reply.body.msg_id))
+            .collect();
+        assert_eq!(vec![(Some(3), Some(5)), (Some(3), Some(6))], ids);
+        Ok(())
+    }
+
     #[test]
     fn test_rpc() -> Try {
         let msg = Message {
@@ -165,4 +1010,853 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_rpc_same_msg_id_to_different_peers() -> Try {
+        let (network, outbound) = Network::new();
+        let ping = |dest: &str| {
+            Message::new(
+                "n1",
+                dest,
+                BodyBuilder::new(PingPong::Ping(0)).msg_id(7).build(),
+            )
+        };
+
+        let from_n2 = network.rpc(ping("n2"))?;
+        let from_n3 = network.rpc(ping("n3"))?;
+        let (to_n2, to_n3) = (outbound.recv()?, outbound.recv()?);
+
+        // each response goes to the rpc sent to the peer that answered
+        assert_eq!(
+            None,
+            network.check_callback(to_n3.into_reply(PingPong::Pong(3)))
+        );
+        assert_eq!(
+            None,
+            network.check_callback(to_n2.into_reply(PingPong::Pong(2)))
+        );
+        assert_eq!(PingPong::Pong(2), from_n2.recv()?.body.payload);
+        assert_eq!(PingPong::Pong(3), from_n3.recv()?.body.payload);
+
+        // a reply from a peer the rpc wasn't sent to isn't a response
+        network.rpc(ping("n2"))?;
+        let stray = ping("n4").into_reply(PingPong::Pong(4));
+        assert_eq!(Some(stray.clone()), network.check_callback(stray));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_next_id_shared_by_clones() -> Try {
+        let (network, _outbound) = Network::<PingPong>::new();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let network = network.clone();
+                thread::spawn(move || (0..100).map(|_| network.next_id()).collect::<Vec<_>>())
+            })
+            .collect();
+
+        let mut ids = HashSet::new();
+        for worker in workers {
+            for id in worker.join().unwrap() {
+                assert!(ids.insert(id), "id {id} was handed out twice");
+            }
+        }
+        assert_eq!(400, network.next_id());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_cancellable() -> Try {
+        let (network, outbound) = Network::new();
+
+        let (handle, _callback) = network.rpc_cancellable(Message::new(
+            "n1",
+            "n2",
+            BodyBuilder::new(PingPong::Ping(0)).msg_id(4).build(),
+        ))?;
+        assert_eq!(4, handle.msg_id());
+        assert_eq!(1, network.pending_rpcs());
+
+        assert!(handle.cancel());
+        assert_eq!(0, network.pending_rpcs());
+
+        // the response is handed back rather than consumed by the cancelled rpc
+        let reply = outbound.recv()?.into_reply(PingPong::Pong(0));
+        assert_eq!(Some(reply.clone()), network.check_callback(reply));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_rpcs() -> Try {
+        let (network, outbound) = Network::new();
+
+        let _callbacks = (0..3)
+            .map(|i| network.rpc_auto("n1", "n2", PingPong::Ping(i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(3, network.pending_rpcs());
+
+        let reply = outbound.recv()?.into_reply(PingPong::Pong(0));
+        assert_eq!(None, network.check_callback(reply));
+        assert_eq!(2, network.pending_rpcs());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request() -> Try {
+        let (network, outbound) = Network::new();
+
+        let reserved = network.next_id();
+        let first = network.request("n1", "n2", PingPong::Ping(0))?;
+        let _second = network.request("n1", "n2", PingPong::Ping(1))?;
+
+        // each request is stamped with an id of its own from the node's counter
+        let ids: Vec<_> = outbound.try_iter().map(|msg| msg.body.msg_id).collect();
+        assert_eq!(vec![Some(reserved + 1), Some(reserved + 2)], ids);
+
+        let body = BodyBuilder::new(PingPong::Pong(0))
+            .in_reply_to(reserved + 1)
+            .build();
+        let response = Message::new("n2", "n1", body);
+        assert_eq!(None, network.check_callback(response));
+        assert_eq!(PingPong::Pong(0), first.recv()?.body.payload);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_auto() -> Try {
+        let (network, outbound) = Network::new();
+
+        let first = network.rpc_auto("n1", "n2", PingPong::Ping(0))?;
+        let second = network.rpc_auto("n1", "n2", PingPong::Ping(1))?;
+
+        let first_sent = outbound.recv()?;
+        let second_sent = outbound.recv()?;
+        assert_eq!(Some(0), first_sent.body.msg_id);
+        assert_eq!(Some(1), second_sent.body.msg_id);
+
+        // responses are routed by the stamped ids
+        assert_eq!(
+            None,
+            network.check_callback(second_sent.into_reply(PingPong::Pong(1)))
+        );
+        assert_eq!(
+            None,
+            network.check_callback(first_sent.into_reply(PingPong::Pong(0)))
+        );
+        assert_eq!(PingPong::Pong(0), first.recv()?.body.payload);
+        assert_eq!(PingPong::Pong(1), second.recv()?.body.payload);
+
+        Ok(())
+    }
+
+    // aliased so the derives don't collide with PingPong's
+    payload!(
+        CasDe,
+        CasSe,
+        enum CasPayload {
+            Cas { from: usize, to: usize },
+            CasOk,
+            Error { code: usize },
+        }
+    );
+
+    impl IsError for CasPayload {
+        fn error_code(&self) -> Option<ErrorCode> {
+            match self {
+                CasPayload::Error { code } => ErrorCode::try_from(*code).ok(),
+                _ => None,
+            }
+        }
+    }
+
+    fn cas_store(network: Network<CasPayload>, outbound: Receiver<Message<CasPayload>>) {
+        thread::spawn(move || {
+            let mut value = 0;
+            for msg in outbound {
+                let CasPayload::Cas { from, to } = msg.body.payload else {
+                    continue;
+                };
+
+                let payload = if from == value {
+                    value = to;
+                    CasPayload::CasOk
+                } else {
+                    CasPayload::Error { code: 22 }
+                };
+                network.check_callback(msg.into_reply(payload));
+            }
+        });
+    }
+
+    #[test]
+    fn test_rpc_checked() -> Try {
+        let (network, outbound) = Network::new();
+        cas_store(network.clone(), outbound);
+
+        let cas = |msg_id, from, to| {
+            Message::new(
+                "n1",
+                "seq-kv",
+                BodyBuilder::new(CasPayload::Cas { from, to })
+                    .msg_id(msg_id)
+                    .build(),
+            )
+        };
+        let cas_ok = |payload: &CasPayload| matches!(payload, CasPayload::CasOk).then_some(());
+
+        network.rpc_checked(cas(1, 0, 5), cas_ok)?;
+
+        let error = network.rpc_checked(cas(2, 0, 6), cas_ok).unwrap_err();
+        assert_eq!(
+            Some(&ErrorCode::PreconditionFailed),
+            error.downcast_ref::<ErrorCode>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_checked_derived_errors() -> Try {
+        payload!(
+            errors,
+            enum KvPayload {
+                Read { key: String },
+                ReadOk { value: usize },
+                Error(ErrorBody),
+            }
+        );
+
+        let (network, outbound) = Network::new();
+        let kv = network.clone();
+        thread::spawn(move || {
+            for msg in outbound {
+                let error = ErrorBody::new(ErrorCode::KeyDoesNotExist, "missing");
+                kv.check_callback(msg.into_reply(KvPayload::Error(error)));
+            }
+        });
+
+        let read = BodyBuilder::new(KvPayload::Read { key: "x".into() })
+            .msg_id(network.next_id())
+            .build();
+        let error = network
+            .rpc_checked(Message::new("n1", "seq-kv", read), |_| -> Option<usize> {
+                unreachable!("an error response isn't extracted")
+            })
+            .unwrap_err();
+        assert_eq!(
+            Some(&ErrorCode::KeyDoesNotExist),
+            error.downcast_ref::<ErrorCode>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep_callbacks() -> Try {
+        let (network, _outbound) = Network::new();
+
+        let mut receivers = Vec::new();
+        for msg_id in 0..100 {
+            let msg = Message {
+                src: "n1".into(),
+                dest: "n2".into(),
+                body: Body {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    payload: PingPong::Ping(msg_id),
+                },
+            };
+            receivers.push(network.rpc(msg)?);
+        }
+
+        // callbacks with live receivers are kept
+        assert_eq!(0, network.sweep_callbacks());
+        assert_eq!(100, network.callbacks.lock().len());
+
+        receivers.truncate(10);
+        assert_eq!(90, network.sweep_callbacks());
+        assert_eq!(10, network.callbacks.lock().len());
+
+        drop(receivers);
+        assert_eq!(10, network.sweep_callbacks());
+        assert!(network.callbacks.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_rpc_keeps_callback() -> Try {
+        let msg = Message {
+            src: "n1".into(),
+            dest: "n2".into(),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                payload: PingPong::Ping(0),
+            },
+        };
+
+        let (network, _outbound) = Network::new();
+        let response = network.rpc(msg.clone())?;
+        assert!(network.rpc(msg.clone()).is_err());
+
+        assert_eq!(
+            None,
+            network.check_callback(msg.into_reply(PingPong::Pong(0)))
+        );
+        assert_eq!(PingPong::Pong(0), response.recv()?.body.payload);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reaper() -> Try {
+        let msg = Message {
+            src: "n1".into(),
+            dest: "n2".into(),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                payload: PingPong::Ping(0),
+            },
+        };
+
+        let (network, _outbound) = Network::with_reaper(
+            Duration::from_millis(10),
+            Duration::from_millis(30),
+            PingPong::Pong(usize::MAX),
+        );
+
+        let start = Instant::now();
+        let response = network.rpc(msg)?.recv_timeout(Duration::from_secs(1))?;
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        assert_eq!("n2", response.src);
+        assert_eq!("n1", response.dest);
+        assert_eq!(Some(1), response.body.in_reply_to);
+        assert_eq!(PingPong::Pong(usize::MAX), response.body.payload);
+        assert!(network.callbacks.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_timeout() -> Try {
+        let msg = Message {
+            src: "n1".into(),
+            dest: "n2".into(),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                payload: PingPong::Ping(0),
+            },
+        };
+
+        let (network, _outbound) = Network::new();
+        let result = network.rpc_timeout(msg, Duration::from_millis(50));
+
+        let error = result.unwrap_err();
+        assert_eq!(
+            Some(&RpcTimeout { msg_id: 1 }),
+            error.downcast_ref::<RpcTimeout>()
+        );
+        assert!(network.callbacks.lock().is_empty());
+
+        let metrics = network.metrics();
+        assert_eq!(
+            (1, 1, 1),
+            (metrics.sent, metrics.rpcs, metrics.rpc_timeouts)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_timeout_response() -> Try {
+        let msg = Message {
+            src: "n1".into(),
+            dest: "n2".into(),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                payload: PingPong::Ping(0),
+            },
+        };
+
+        let (network, outbound) = Network::new();
+        let replica = network.clone();
+        thread::spawn(move || {
+            let request = outbound.recv().unwrap();
+            replica.check_callback(request.into_reply(PingPong::Pong(0)));
+        });
+
+        let response = network.rpc_timeout(msg, Duration::from_secs(1))?;
+        assert_eq!(PingPong::Pong(0), response.body.payload);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_retry() -> Try {
+        let (network, outbound) = Network::new();
+        let msg = Message {
+            src: "n1".into(),
+            dest: "n2".into(),
+            body: Body {
+                msg_id: Some(network.next_id()),
+                in_reply_to: None,
+                payload: PingPong::Ping(0),
+            },
+        };
+
+        // drop the first two attempts, reply to the third
+        let replica = network.clone();
+        let attempts = thread::spawn(move || {
+            let mut ids = Vec::new();
+            for request in outbound.iter().take(3) {
+                ids.push(request.body.msg_id);
+                if ids.len() == 3 {
+                    replica.check_callback(request.into_reply(PingPong::Pong(0)));
+                }
+            }
+            ids
+        });
+
+        let response =
+            network.rpc_retry(msg, 3, Duration::from_millis(30), Duration::from_millis(5))?;
+
+        assert_eq!(PingPong::Pong(0), response.body.payload);
+        assert_eq!(Some(2), response.body.in_reply_to);
+        assert_eq!(vec![Some(0), Some(1), Some(2)], attempts.join().unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_retry_exhausted() -> Try {
+        let msg = Message {
+            src: "n1".into(),
+            dest: "n2".into(),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                payload: PingPong::Ping(0),
+            },
+        };
+
+        let (network, _outbound) = Network::new();
+        let error = network
+            .rpc_retry(msg, 2, Duration::from_millis(10), Duration::from_millis(1))
+            .unwrap_err();
+
+        assert_eq!("rpc failed after 2 attempts", error.to_string());
+        assert!(error.downcast_ref::<RpcTimeout>().is_some());
+        assert!(network.callbacks.lock().is_empty());
+
+        Ok(())
+    }
+
+    fn pings(dests: &[&str]) -> Vec<Message<PingPong>> {
+        dests
+            .iter()
+            .enumerate()
+            .map(|(i, dest)| Message {
+                src: "n0".into(),
+                dest: dest.to_string(),
+                body: Body {
+                    msg_id: Some(i),
+                    in_reply_to: None,
+                    payload: PingPong::Ping(i),
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rpc_quorum() -> Try {
+        let (network, outbound) = Network::new();
+
+        // everyone but n3 replies
+        let replicas = network.clone();
+        thread::spawn(move || {
+            for msg in outbound {
+                let PingPong::Ping(i) = msg.body.payload else {
+                    continue;
+                };
+
+                if msg.dest != "n3" {
+                    replicas.check_callback(msg.into_reply(PingPong::Pong(i)));
+                }
+            }
+        });
+
+        let responses = network
+            .rpc_quorum(pings(&["n1", "n2", "n3"]), 2, Duration::from_secs(1))
+            .map_err(|e| anyhow!("{e}"))?;
+
+        let mut srcs: Vec<_> = responses.iter().map(|r| r.src.as_str()).collect();
+        srcs.sort();
+        assert_eq!(vec!["n1", "n2"], srcs);
+        assert!(network.callbacks.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_quorum_partial() -> Try {
+        let (network, outbound) = Network::new();
+
+        // only n1 replies
+        let replicas = network.clone();
+        thread::spawn(move || {
+            for msg in outbound {
+                if msg.dest == "n1" {
+                    replicas.check_callback(msg.into_reply(PingPong::Pong(0)));
+                }
+            }
+        });
+
+        let result = network.rpc_quorum(pings(&["n1", "n2", "n3"]), 2, Duration::from_millis(50));
+
+        let Err(QuorumError::Timeout {
+            responses,
+            required,
+        }) = result
+        else {
+            bail!("expected quorum timeout");
+        };
+
+        assert_eq!(2, required);
+        assert_eq!(1, responses.len());
+        assert_eq!("n1", responses[0].src);
+        assert!(network.callbacks.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_broadcast_quorum() -> Try {
+        let (network, outbound) = Network::new();
+
+        // n1, n2, and n4 accept, n3 rejects, and n5 never replies
+        let replicas = network.clone();
+        thread::spawn(move || {
+            for msg in outbound {
+                let payload = match msg.dest.as_str() {
+                    "n1" | "n2" | "n4" => PingPong::Pong(1),
+                    "n3" => PingPong::Pong(0),
+                    _ => continue,
+                };
+
+                replicas.check_callback(msg.into_reply(payload));
+            }
+        });
+
+        let dests: Vec<String> = (1..=5).map(|i| format!("n{i}")).collect();
+        let accepted = network.rpc_broadcast_quorum(
+            "n0",
+            &dests,
+            |_| PingPong::Ping(0),
+            |reply| reply.body.payload == PingPong::Pong(1),
+            Duration::from_secs(1),
+        )?;
+
+        assert_eq!(3, accepted.len());
+        assert!(accepted
+            .iter()
+            .all(|reply| reply.body.payload == PingPong::Pong(1)));
+        assert!(network.callbacks.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_broadcast_quorum_timeout() -> Try {
+        let (network, _outbound) = Network::new();
+
+        let dests: Vec<String> = (1..=3).map(|i| format!("n{i}")).collect();
+        let result = network.rpc_broadcast_quorum(
+            "n0",
+            &dests,
+            |_| PingPong::Ping(0),
+            |_| true,
+            Duration::from_millis(50),
+        );
+
+        assert!(result.is_err());
+        assert!(network.callbacks.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_broadcast_quorum_send_error() -> Try {
+        let (network, outbound) = Network::new();
+        drop(outbound);
+
+        let dests: Vec<String> = (1..=3).map(|i| format!("n{i}")).collect();
+        let error = network
+            .rpc_broadcast_quorum(
+                "n0",
+                &dests,
+                |_| PingPong::Ping(0),
+                |_| true,
+                Duration::from_millis(50),
+            )
+            .unwrap_err();
+
+        assert!(error.downcast_ref::<ChannelClosed>().is_some());
+        assert!(network.callbacks.lock().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast() -> Try {
+        let (network, outbound) = Network::new();
+        let dests: Vec<String> = (1..=4).map(|i| format!("n{i}")).collect();
+
+        network.broadcast("n0", &dests, PingPong::Ping(7))?;
+
+        let sent: Vec<_> = outbound.try_iter().collect();
+        assert_eq!(4, sent.len());
+        for (msg, dest) in sent.iter().zip(&dests) {
+            assert_eq!("n0", msg.src);
+            assert_eq!(dest, &msg.dest);
+            assert_eq!(None, msg.body.msg_id);
+            assert_eq!(PingPong::Ping(7), msg.body.payload);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_peers() -> Try {
+        let (network, outbound) = Network::new();
+        let node_ids: Vec<String> = (1..=3).map(|i| format!("n{i}")).collect();
+
+        let sent = network.send_peers("n2", &node_ids, PingPong::Ping(7))?;
+        assert_eq!(2, sent);
+
+        let dests: Vec<_> = outbound.try_iter().map(|msg| msg.dest).collect();
+        assert_eq!(vec!["n1", "n3"], dests);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast_closed() {
+        let (network, outbound) = Network::new();
+        drop(outbound);
+
+        let error = network
+            .broadcast("n0", &["n1".into()], PingPong::Ping(7))
+            .unwrap_err();
+        assert!(error.to_string().contains("n1"));
+    }
+
+    #[test]
+    fn test_capacity_fail() -> Try {
+        let (network, outbound) = Network::with_capacity(1, Backpressure::Fail);
+        network.send(Message::new(
+            "n1",
+            "n2",
+            BodyBuilder::new(PingPong::Ping(0)).build(),
+        ))?;
+
+        let error = network
+            .send(Message::new(
+                "n1",
+                "n2",
+                BodyBuilder::new(PingPong::Ping(1)).build(),
+            ))
+            .unwrap_err();
+        assert_eq!(Some(&WouldBlock), error.downcast_ref::<WouldBlock>());
+        assert_eq!(1, network.outbound_depth());
+
+        // an rpc that couldn't be sent doesn't leave its callback behind
+        let ping = BodyBuilder::new(PingPong::Ping(2)).msg_id(2).build();
+        assert!(network.rpc(Message::new("n1", "n2", ping)).is_err());
+        assert_eq!(0, network.pending_rpcs());
+
+        // there is room again once the runtime receives the first message
+        outbound.recv()?;
+        network.send(Message::new(
+            "n1",
+            "n2",
+            BodyBuilder::new(PingPong::Ping(3)).build(),
+        ))?;
+        assert_eq!(PingPong::Ping(3), outbound.recv()?.body.payload);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_capacity_block() -> Try {
+        let (network, outbound) = Network::with_capacity(1, Backpressure::Block);
+        network.send(Message::new(
+            "n1",
+            "n2",
+            BodyBuilder::new(PingPong::Ping(0)).build(),
+        ))?;
+
+        let (done_tx, done_rx) = channel();
+        let sender = network.clone();
+        thread::spawn(move || {
+            let ping = Message::new("n1", "n2", BodyBuilder::new(PingPong::Ping(1)).build());
+            sender.send(ping).unwrap();
+            done_tx.send(()).unwrap();
+        });
+
+        // the second send waits for room
+        assert!(done_rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        assert_eq!(PingPong::Ping(0), outbound.recv()?.body.payload);
+        done_rx.recv_timeout(Duration::from_secs(1))?;
+        assert_eq!(PingPong::Ping(1), outbound.recv()?.body.payload);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_every() {
+        let (network, _outbound) = Network::<PingPong>::new();
+        let interval = Duration::from_millis(20);
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        network.every(interval, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            bail!("errors don't stop the timer")
+        });
+
+        thread::sleep(interval * 3 + interval / 2);
+        assert!(fired.load(Ordering::SeqCst) >= 2);
+
+        // the timer stops with the network
+        drop(network);
+        thread::sleep(interval * 2);
+        let stopped = fired.load(Ordering::SeqCst);
+        thread::sleep(interval * 2);
+        assert_eq!(stopped, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_dedup() -> Try {
+        payload!(
+            enum Gossip {
+                Replicate { seqs: Vec<usize> },
+            }
+        );
+
+        let (network, outbound) = Network::with_dedup(Duration::from_millis(100));
+        let replicate = |dest: &str, seqs: Vec<usize>| {
+            let body = BodyBuilder::new(Gossip::Replicate { seqs }).build();
+            Message::new("n1", dest, body)
+        };
+
+        network.send(replicate("n2", vec![1, 2]))?;
+        network.send(replicate("n2", vec![1, 2]))?;
+        network.send(replicate("n2", vec![1]))?;
+        network.send(replicate("n3", vec![1, 2]))?;
+        let sent: Vec<String> = outbound.try_iter().map(|msg| msg.dest).collect();
+        assert_eq!(vec!["n2", "n2", "n3"], sent);
+
+        // rpcs are waited on, so they are never dropped
+        let rpc = |msg_id| {
+            let body = BodyBuilder::new(Gossip::Replicate { seqs: vec![1, 2] })
+                .msg_id(msg_id)
+                .build();
+            Message::new("n1", "n2", body)
+        };
+        let _first = network.rpc(rpc(1))?;
+        let _second = network.rpc(rpc(2))?;
+        assert_eq!(2, outbound.try_iter().count());
+
+        // once the window passes, the message is sent again
+        thread::sleep(Duration::from_millis(150));
+        network.send(replicate("n2", vec![1, 2]))?;
+        assert_eq!(1, outbound.try_iter().count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_channel_closed() {
+        let (network, outbound) = Network::new();
+        drop(outbound);
+        assert!(!network.is_closed());
+
+        let ping = || Message::new("n1", "n2", BodyBuilder::new(PingPong::Ping(0)).build());
+        let error = network.send(ping()).unwrap_err();
+        assert!(error.is::<ChannelClosed>());
+        assert!(network.is_closed());
+
+        let body = BodyBuilder::new(PingPong::Ping(0)).msg_id(1).build();
+        let error = network.rpc(Message::new("n1", "n2", body)).unwrap_err();
+        assert!(error.is::<ChannelClosed>());
+        assert_eq!(0, network.pending_rpcs());
+
+        // the error survives context added by callers
+        let error = network
+            .broadcast("n1", &["n2".into()], PingPong::Ping(0))
+            .unwrap_err();
+        assert!(error.is::<ChannelClosed>());
+    }
+
+    #[test]
+    fn test_every_stops_on_shut_down() {
+        let (network, _outbound) = Network::<PingPong>::new();
+        let interval = Duration::from_millis(20);
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        network.every(interval, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        thread::sleep(interval * 2 + interval / 2);
+        assert!(!network.is_shut_down());
+        network.shut_down();
+        assert!(network.is_shut_down());
+
+        // the network is still alive, but a call already under way is the last one
+        thread::sleep(interval + interval / 2);
+        let stopped = fired.load(Ordering::SeqCst);
+        thread::sleep(interval * 2);
+        assert_eq!(stopped, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_outbound_depth() -> Try {
+        let msg = Message {
+            src: "c1".into(),
+            dest: "n1".into(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: None,
+                payload: PingPong::Ping(0),
+            },
+        };
+
+        let (network, outbound) = Network::new();
+        assert_eq!(0, network.outbound_depth());
+
+        // nothing is draining the outbound channel, so depth grows
+        for _ in 0..3 {
+            network.send(msg.clone())?;
+        }
+        assert_eq!(3, network.outbound_depth());
+
+        // taking a message off the channel doesn't write it, the runtime's output thread does
+        outbound.recv()?;
+        assert_eq!(3, network.outbound_depth());
+
+        Ok(())
+    }
 }
@@ -2,9 +2,23 @@
 
 use crate::{
     network::Network,
-    types::{Message, Try},
+    types::{Message, Payload, Try},
 };
 
+/// Whether a node acted on a message delivered to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handling {
+    Handled,
+    Ignored,
+}
+
+/// Handlers returning `Ok(())` are treated as having handled the message
+impl From<()> for Handling {
+    fn from(_: ()) -> Self {
+        Handling::Handled
+    }
+}
+
 /// Maelstrom node
 pub trait Node<Payload> {
     /// constructs a Node from the body of an init message.
@@ -12,6 +26,166 @@ pub trait Node<Payload> {
     /// The runtime is responsible for sending init_ok after this message returns.
     fn from_init(network: Network<Payload>, node_id: String, node_ids: Vec<String>) -> Self;
 
+    /// called once right after from_init returns, before the runtime sends init_ok.
+    /// Use it for side effects such as starting background workers, which can then
+    /// borrow the constructed node. An error stops the runtime. Does nothing by default.
+    fn on_init(&mut self) -> Try {
+        Ok(())
+    }
+
     /// handles inbound messages to this node from clients or other nodes.
     fn handle_message(&mut self, msg: Message<Payload>) -> Try;
+
+    /// handles inbound messages, reporting whether the message was handled or ignored.
+    /// The runtime delivers messages through this method and logs ignored messages.
+    /// Defaults to handle_message, override it to report messages the node doesn't expect.
+    fn try_handle_message(&mut self, msg: Message<Payload>) -> anyhow::Result<Handling> {
+        self.handle_message(msg).map(Handling::from)
+    }
+
+    /// handles replies that arrive with no pending RPC waiting for them,
+    /// such as responses to an RPC that already timed out or was swept.
+    /// The runtime delivers these here instead of handle_message. Does nothing by default.
+    fn handle_orphan_reply(&mut self, _msg: Message<Payload>) -> Try {
+        Ok(())
+    }
+
+    /// called once when the runtime stops delivering input, after EOI or when stdin closes.
+    /// Use it to flush state before the node exits. Does nothing by default.
+    fn on_shutdown(&mut self) -> Try {
+        Ok(())
+    }
+
+    /// called periodically when the runtime is configured with `Runtime::with_tick`.
+    /// Ticks are delivered between messages, never concurrently with handle_message.
+    fn tick(&mut self, _network: &Network<Payload>) -> Try {
+        Ok(())
+    }
+}
+
+/// Maelstrom node that handles several messages at once, see `Runtime::run_concurrent`.
+/// Messages are delivered from a pool of threads through a shared reference,
+/// so the node is responsible for synchronizing its own state.
+pub trait ConcurrentNode<Payload>: Send + Sync + 'static {
+    /// constructs a Node from the body of an init message, like `Node::from_init`.
+    fn from_init(network: Network<Payload>, node_id: String, node_ids: Vec<String>) -> Self;
+
+    /// handles inbound messages to this node, possibly concurrently with other messages.
+    fn handle_message(&self, msg: Message<Payload>) -> Try;
+}
+
+/// Maelstrom node that returns the messages it sends instead of sending them,
+/// so it doesn't need to hold on to a network. Run it with the `Replying` adapter,
+/// as in `Runtime::<P, Replying<N, P>>::run()`.
+pub trait ReplyingNode<Payload> {
+    /// constructs a Node from the body of an init message, like `Node::from_init`.
+    fn from_init(node_id: String, node_ids: Vec<String>) -> Self;
+
+    /// handles inbound messages to this node, returning the messages to send in response.
+    fn handle(&mut self, msg: Message<Payload>) -> anyhow::Result<Vec<Message<Payload>>>;
+}
+
+/// Runs a `ReplyingNode` as a `Node`, sending the messages it returns in order
+#[derive(Debug)]
+pub struct Replying<N, P> {
+    node: N,
+    network: Network<P>,
+}
+
+impl<N, P> Replying<N, P> {
+    /// The node being run
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+}
+
+impl<N, P> Node<P> for Replying<N, P>
+where
+    N: ReplyingNode<P>,
+    P: Payload,
+{
+    fn from_init(network: Network<P>, node_id: String, node_ids: Vec<String>) -> Self {
+        Replying {
+            node: N::from_init(node_id, node_ids),
+            network,
+        }
+    }
+
+    fn handle_message(&mut self, msg: Message<P>) -> Try {
+        for outbound in self.node.handle(msg)? {
+            self.network.send(outbound)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{payload, testing::MockNetwork, types::BodyBuilder};
+
+    use super::*;
+
+    payload!(
+        enum EchoPayload {
+            Echo { echo: String },
+            EchoOk { echo: String },
+        }
+    );
+
+    /// Replies to an echo twice
+    struct DoubleEchoNode;
+
+    impl ReplyingNode<EchoPayload> for DoubleEchoNode {
+        fn from_init(_: String, _: Vec<String>) -> Self {
+            DoubleEchoNode
+        }
+
+        fn handle(
+            &mut self,
+            msg: Message<EchoPayload>,
+        ) -> anyhow::Result<Vec<Message<EchoPayload>>> {
+            let EchoPayload::Echo { echo } = &msg.body.payload else {
+                return Ok(Vec::new());
+            };
+
+            let reply = msg
+                .clone()
+                .into_reply(EchoPayload::EchoOk { echo: echo.clone() });
+            Ok(vec![reply.clone(), reply])
+        }
+    }
+
+    #[test]
+    fn test_replying() -> Try {
+        let mock = MockNetwork::new();
+        let mut node: Replying<DoubleEchoNode, EchoPayload> =
+            Node::from_init(mock.network(), "n1".into(), vec!["n1".into()]);
+
+        let echo = BodyBuilder::new(EchoPayload::Echo {
+            echo: "ding-dong!".into(),
+        })
+        .msg_id(1)
+        .build();
+        node.handle_message(Message::new("c1", "n1", echo))?;
+
+        let sent = mock.take_sent();
+        assert_eq!(2, sent.len());
+        for reply in sent {
+            assert_eq!("c1", reply.dest);
+            assert_eq!(Some(1), reply.body.in_reply_to);
+            assert_eq!(
+                EchoPayload::EchoOk {
+                    echo: "ding-dong!".into()
+                },
+                reply.body.payload
+            );
+        }
+
+        // messages it doesn't respond to send nothing
+        let echo_ok = BodyBuilder::new(EchoPayload::EchoOk { echo: "".into() }).build();
+        node.handle_message(Message::new("c1", "n1", echo_ok))?;
+        assert!(mock.take_sent().is_empty());
+        Ok(())
+    }
 }
@@ -0,0 +1,247 @@
+//! Defines Partitioner, for deciding which node owns a key,
+//! and Ring, for partitioning keys across nodes with consistent hashing
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+};
+
+/// Number of points each node is placed at on a ring built with `Ring::new`
+pub const DEFAULT_VIRTUAL_NODES: usize = 64;
+
+/// Decides which node owns a key.
+/// Ownership must depend only on the key and node ids,
+/// so every node using the same partitioner agrees on the owner of each key.
+pub trait Partitioner: Send + Sync {
+    /// The node that owns `key`, one of `nodes`
+    ///
+    /// # Panics
+    /// if `nodes` is empty
+    fn owner(&self, key: &str, nodes: &[String]) -> String;
+}
+
+/// Assigns a key to the node at its hash modulo the number of nodes.
+/// Keys spread evenly, but adding or removing a node remaps almost every key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HashPartitioner;
+
+impl Partitioner for HashPartitioner {
+    fn owner(&self, key: &str, nodes: &[String]) -> String {
+        assert!(!nodes.is_empty(), "no nodes to partition across");
+        nodes[hash(&key) as usize % nodes.len()].clone()
+    }
+}
+
+/// Assigns contiguous ranges of keys to nodes, in the order nodes are given.
+/// The first node owns keys before the first bound, the second keys from the first bound
+/// up to the second, and so on, with the last node owning any keys past its range.
+/// Keys compare as strings, so `"10"` comes before `"9"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangePartitioner {
+    bounds: Vec<String>,
+}
+
+impl RangePartitioner {
+    /// Constructs a partitioner splitting keys at `bounds`, in any order
+    pub fn new(bounds: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let mut bounds: Vec<String> = bounds.into_iter().map(Into::into).collect();
+        bounds.sort();
+        RangePartitioner { bounds }
+    }
+}
+
+impl Partitioner for RangePartitioner {
+    fn owner(&self, key: &str, nodes: &[String]) -> String {
+        assert!(!nodes.is_empty(), "no nodes to partition across");
+        let range = self.bounds.partition_point(|bound| bound.as_str() <= key);
+        nodes[range.min(nodes.len() - 1)].clone()
+    }
+}
+
+/// A consistent hashing ring. Each node is placed at several points on the ring,
+/// and a key is owned by the first node at or after the key's hash.
+/// Adding or removing a node only moves the keys next to its points,
+/// about 1/N of them, instead of remapping almost every key.
+///
+/// Ownership depends only on the node ids, so every node building a ring
+/// from the same ids agrees on the owner of each key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ring {
+    points: BTreeMap<u64, String>,
+    virtual_nodes: usize,
+}
+
+impl Ring {
+    /// Constructs a ring placing each node at `DEFAULT_VIRTUAL_NODES` points
+    pub fn new(node_ids: &[String]) -> Self {
+        Ring::with_virtual_nodes(node_ids, DEFAULT_VIRTUAL_NODES)
+    }
+
+    /// Constructs a ring placing each node at `virtual_nodes` points.
+    /// More points spread keys more evenly, at the cost of a larger ring.
+    pub fn with_virtual_nodes(node_ids: &[String], virtual_nodes: usize) -> Self {
+        let mut ring = Ring {
+            points: BTreeMap::new(),
+            virtual_nodes,
+        };
+
+        for node_id in node_ids {
+            ring.add(node_id);
+        }
+
+        ring
+    }
+
+    /// Adds a node's points to the ring
+    pub fn add(&mut self, node_id: &str) {
+        for replica in 0..self.virtual_nodes {
+            self.points
+                .insert(hash(&(node_id, replica)), node_id.to_string());
+        }
+    }
+
+    /// Removes a node's points from the ring
+    pub fn remove(&mut self, node_id: &str) {
+        self.points.retain(|_, owner| owner != node_id);
+    }
+
+    /// Returns true if the ring has no nodes
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The node that owns `key`
+    ///
+    /// # Panics
+    /// if the ring has no nodes
+    pub fn owner(&self, key: &str) -> &str {
+        let hash = hash(&key);
+        let (_, owner) = self
+            .points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .expect("ring has no nodes");
+        owner
+    }
+}
+
+/// A ring is built from its own node ids, so `nodes` is ignored
+impl Partitioner for Ring {
+    fn owner(&self, key: &str, _nodes: &[String]) -> String {
+        Ring::owner(self, key).to_string()
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn node_ids(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("n{i}")).collect()
+    }
+
+    fn keys() -> Vec<String> {
+        (0..10_000).map(|i| format!("k{i}")).collect()
+    }
+
+    #[test]
+    fn test_owners_agree() {
+        let ring = Ring::new(&node_ids(5));
+        let other = Ring::new(&node_ids(5));
+
+        for key in keys() {
+            assert_eq!(ring.owner(&key), other.owner(&key));
+        }
+    }
+
+    #[test]
+    fn test_keys_spread() {
+        let ring = Ring::new(&node_ids(5));
+        let mut owned = HashMap::<&str, usize>::new();
+        let keys = keys();
+        for key in &keys {
+            *owned.entry(ring.owner(key)).or_default() += 1;
+        }
+
+        // every node owns a share of keys, none owns more than twice its share
+        assert_eq!(5, owned.len());
+        assert!(owned.values().all(|count| *count < 2 * keys.len() / 5));
+    }
+
+    #[test]
+    fn test_add_node_moves_few_keys() {
+        let mut ring = Ring::new(&node_ids(10));
+        let before: Vec<String> = keys().iter().map(|key| ring.owner(key).into()).collect();
+
+        ring.add("n10");
+        let mut moved = 0;
+        for (key, owner) in keys().iter().zip(before) {
+            if ring.owner(key) != owner {
+                // keys only move to the new node
+                assert_eq!("n10", ring.owner(key));
+                moved += 1;
+            }
+        }
+
+        // about 1/11 of keys move, a modulo partitioner would move about 10/11
+        assert!(moved > 0);
+        assert!(moved < 2 * keys().len() / 11, "moved {moved}");
+    }
+
+    #[test]
+    fn test_hash_partitioner() {
+        let nodes = node_ids(5);
+        let mut owned = HashMap::<String, usize>::new();
+        for key in keys() {
+            let owner = HashPartitioner.owner(&key, &nodes);
+            assert_eq!(owner, HashPartitioner.owner(&key, &nodes));
+            *owned.entry(owner).or_default() += 1;
+        }
+
+        assert_eq!(5, owned.len());
+        assert!(owned.values().all(|count| *count < 2 * keys().len() / 5));
+    }
+
+    #[test]
+    fn test_range_partitioner() {
+        let nodes = node_ids(3);
+        let partitioner = RangePartitioner::new(["m", "f"]);
+
+        assert_eq!("n0", partitioner.owner("a", &nodes));
+        assert_eq!("n1", partitioner.owner("f", &nodes));
+        assert_eq!("n1", partitioner.owner("lemon", &nodes));
+        assert_eq!("n2", partitioner.owner("m", &nodes));
+        assert_eq!("n2", partitioner.owner("zebra", &nodes));
+
+        // with fewer nodes than ranges, the last node takes the rest
+        assert_eq!("n1", partitioner.owner("zebra", &nodes[..2]));
+    }
+
+    #[test]
+    fn test_ring_partitioner() {
+        let nodes = node_ids(3);
+        let ring: Box<dyn Partitioner> = Box::new(Ring::new(&nodes));
+        for key in keys().iter().take(100) {
+            assert_eq!(Ring::new(&nodes).owner(key), ring.owner(key, &nodes));
+        }
+    }
+
+    #[test]
+    fn test_remove_node() {
+        let mut ring = Ring::new(&node_ids(2));
+        ring.remove("n0");
+        assert!(keys().iter().all(|key| ring.owner(key) == "n1"));
+
+        ring.remove("n1");
+        assert!(ring.is_empty());
+    }
+}
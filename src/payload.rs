@@ -1,16 +1,212 @@
 /// Helper macro to derive the necessary traits on an enum to implement Payload.
 /// also marks the enum with serde attributes to type-tag and rename as snake_case
+///
+/// Extra derives can be appended to the fixed set:
+/// `payload!(derives: [Hash, PartialOrd], enum Foo { .. })`
+///
+/// `payload!(no_eq, enum Foo { .. })` leaves out `PartialEq` and `Eq`,
+/// for payloads carrying floats.
+///
+/// `payload!(tag = "op", enum Foo { .. })` tags variants with `op` instead of `type`.
+///
+/// `payload!(errors, enum Foo { .. })` also implements `IsError` for an enum
+/// with an `Error(ErrorBody)` variant.
+///
+/// `payload!(include(Read, ReadOk<u64>, Error), enum Foo { .. })` adds variants shared
+/// across workloads ahead of the enum's own: `Read`, `ReadOk(types::ReadOk<T>)`
+/// and `Error(types::ErrorBody)`, any of which can be left out.
 #[macro_export]
 macro_rules! payload {
-    // add option to specifiy aliases if somehow this collides with your naming
-    ($de:ident, $se:ident, $i:item) => {
+    (@derive $de:ident, $se:ident, $tag:literal, [$($derive:path),*], $i:item) => {
         use serde::{Deserialize as $de, Serialize as $se};
 
-        #[derive(Debug, Clone, PartialEq, Eq, $de, $se)]
-        #[serde(tag = "type", rename_all = "snake_case")]
+        #[derive(Debug, Clone, $($derive,)* $de, $se)]
+        #[serde(tag = $tag, rename_all = "snake_case")]
         $i
     };
+    (derives: [$($derive:path),* $(,)?], $i:item) => {
+        payload!(@derive __DE, __SE, "type", [PartialEq, Eq, $($derive),*], $i);
+    };
+    (no_eq, $i:item) => {
+        payload!(@derive __DE, __SE, "type", [], $i);
+    };
+    (tag = $tag:literal, $i:item) => {
+        payload!(@derive __DE, __SE, $tag, [PartialEq, Eq], $i);
+    };
+    (errors, $(#[$meta:meta])* $vis:vis enum $name:ident $body:tt) => {
+        payload!($(#[$meta])* $vis enum $name $body);
+
+        impl $crate::error::IsError for $name {
+            fn error_code(&self) -> Option<$crate::error::ErrorCode> {
+                #[allow(unreachable_patterns)]
+                match self {
+                    $name::Error(error) => $crate::error::IsError::error_code(error),
+                    _ => None,
+                }
+            }
+        }
+    };
+    (include($($shared:tt)*), $(#[$meta:meta])* $vis:vis enum $name:ident { $($body:tt)* }) => {
+        payload!(@include [$($shared)*] [] $(#[$meta])* $vis enum $name { $($body)* });
+    };
+    (@include [Read $(, $($rest:tt)*)?] [$($variants:tt)*] $($item:tt)*) => {
+        payload!(@include [$($($rest)*)?] [$($variants)* Read,] $($item)*);
+    };
+    (@include [ReadOk<$t:ty> $(, $($rest:tt)*)?] [$($variants:tt)*] $($item:tt)*) => {
+        payload!(
+            @include [$($($rest)*)?] [$($variants)* ReadOk($crate::types::ReadOk<$t>),] $($item)*
+        );
+    };
+    (@include [Error $(, $($rest:tt)*)?] [$($variants:tt)*] $($item:tt)*) => {
+        payload!(@include [$($($rest)*)?] [$($variants)* Error($crate::types::ErrorBody),] $($item)*);
+    };
+    (@include [] [$($variants:tt)*] $(#[$meta:meta])* $vis:vis enum $name:ident { $($body:tt)* }) => {
+        payload!($(#[$meta])* $vis enum $name { $($variants)* $($body)* });
+    };
+    // add option to specifiy aliases if somehow this collides with your naming
+    ($de:ident, $se:ident, $i:item) => {
+        payload!(@derive $de, $se, "type", [PartialEq, Eq], $i);
+    };
     ($i:item) => {
         payload!(__DE, __SE, $i);
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    mod float {
+        payload!(
+            no_eq,
+            enum Metric {
+                Latency { millis: f64 },
+                LatencyOk,
+            }
+        );
+
+        #[test]
+        fn test_no_eq() {
+            let latency = Metric::Latency { millis: 1.5 };
+            let json = serde_json::to_string(&latency).unwrap();
+            assert_eq!(r#"{"type":"latency","millis":1.5}"#, json);
+
+            let Metric::Latency { millis } = serde_json::from_str(&json).unwrap() else {
+                panic!("expected latency");
+            };
+            assert_eq!(1.5, millis);
+        }
+    }
+
+    payload!(
+        derives: [Hash, PartialOrd, Ord],
+        enum Keyed {
+            Send { key: String, msg: usize },
+            SendOk { offset: usize },
+        }
+    );
+
+    mod op {
+        payload!(
+            tag = "op",
+            enum Txn {
+                Write { key: usize, value: usize },
+                WriteOk,
+            }
+        );
+
+        #[test]
+        fn test_custom_tag() {
+            let write = Txn::Write { key: 1, value: 2 };
+            let json = serde_json::to_string(&write).unwrap();
+            assert_eq!(r#"{"op":"write","key":1,"value":2}"#, json);
+            assert_eq!(write, serde_json::from_str(&json).unwrap());
+
+            let ok = serde_json::to_string(&Txn::WriteOk).unwrap();
+            assert_eq!(r#"{"op":"write_ok"}"#, ok);
+        }
+    }
+
+    mod errors {
+        use crate::{
+            error::{ErrorCode, IsError},
+            types::ErrorBody,
+        };
+
+        payload!(
+            errors,
+            /// Replies from a key-value service
+            pub enum KvReply {
+                ReadOk { value: usize },
+                Error(ErrorBody),
+            }
+        );
+
+        #[test]
+        fn test_errors() {
+            assert_eq!(None, KvReply::ReadOk { value: 1 }.error_code());
+
+            let missing = KvReply::Error(ErrorBody::new(ErrorCode::KeyDoesNotExist, "missing"));
+            assert_eq!(Some(ErrorCode::KeyDoesNotExist), missing.error_code());
+
+            let unknown: KvReply =
+                serde_json::from_str(r#"{"type":"error","code":1000,"text":"custom"}"#).unwrap();
+            assert_eq!(None, unknown.error_code());
+        }
+    }
+
+    mod include {
+        use crate::types::{ErrorBody, ReadOk};
+
+        payload!(
+            include(Read, ReadOk<Vec<u64>>, Error),
+            enum Counter {
+                Add { delta: u64 },
+                AddOk,
+            }
+        );
+
+        #[test]
+        fn test_include() {
+            let cases = [
+                (Counter::Read, r#"{"type":"read"}"#),
+                (
+                    Counter::ReadOk(ReadOk { value: vec![1, 2] }),
+                    r#"{"type":"read_ok","value":[1,2]}"#,
+                ),
+                (
+                    Counter::Error(ErrorBody {
+                        code: 20,
+                        text: "missing".into(),
+                    }),
+                    r#"{"type":"error","code":20,"text":"missing"}"#,
+                ),
+                (Counter::Add { delta: 3 }, r#"{"type":"add","delta":3}"#),
+                (Counter::AddOk, r#"{"type":"add_ok"}"#),
+            ];
+
+            for (payload, json) in cases {
+                assert_eq!(json, serde_json::to_string(&payload).unwrap());
+                assert_eq!(payload, serde_json::from_str(json).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_extra_derives() {
+        let send = Keyed::Send {
+            key: "k1".into(),
+            msg: 5,
+        };
+
+        let mut seen = HashSet::new();
+        assert!(seen.insert(send.clone()));
+        assert!(!seen.insert(send.clone()));
+        assert!(send < Keyed::SendOk { offset: 0 });
+
+        assert_eq!(
+            r#"{"type":"send","key":"k1","msg":5}"#,
+            serde_json::to_string(&send).unwrap()
+        );
+    }
+}
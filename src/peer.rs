@@ -0,0 +1,185 @@
+//! Defines Peer, a handle for repeatedly talking to a single destination
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use crate::{
+    network::Network,
+    types::{BodyBuilder, Message, Payload, Rpc},
+};
+
+/// Peer sends messages from one node to one destination,
+/// assigning msg_ids from the network's sequence, see `Network::next_id`.
+/// RPCs wait for their response indefinitely unless a timeout is set with `with_timeout`.
+#[derive(Debug, Clone)]
+pub struct Peer<P> {
+    network: Network<P>,
+    src: String,
+    dest: String,
+    timeout: Option<Duration>,
+}
+
+impl<P: Payload> Peer<P> {
+    pub fn new(network: Network<P>, src: impl Into<String>, dest: impl Into<String>) -> Self {
+        Self {
+            network,
+            src: src.into(),
+            dest: dest.into(),
+            timeout: None,
+        }
+    }
+
+    /// Fails `rpc_expect` with `RpcTimeout` if the destination doesn't respond within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The node this peer sends messages to
+    pub fn dest(&self) -> &str {
+        &self.dest
+    }
+
+    /// Sends the payload as an RPC with the next msg_id,
+    /// returning a Receiver that will contain the response.
+    pub fn rpc(&self, payload: P) -> Rpc<P> {
+        self.network.rpc(self.request(payload))
+    }
+
+    /// Sends the payload as an RPC and waits for the response, up to the peer's timeout,
+    /// returning the value `extract` takes from it.
+    /// Fails if the response is not one `extract` expects, such as an error reply.
+    pub fn rpc_expect<T>(
+        &self,
+        payload: P,
+        extract: impl FnOnce(&P) -> Option<T>,
+    ) -> anyhow::Result<T> {
+        let response = match self.timeout {
+            Some(timeout) => self.network.rpc_timeout(self.request(payload), timeout)?,
+            None => self.rpc(payload)?.recv()?,
+        };
+
+        let payload = &response.body.payload;
+        extract(payload)
+            .ok_or_else(|| anyhow!("unexpected response from {}: {payload:?}", self.dest))
+    }
+
+    fn request(&self, payload: P) -> Message<P> {
+        let body = BodyBuilder::new(payload)
+            .msg_id(self.network.next_id())
+            .build();
+        Message::new(&self.src, &self.dest, body)
+    }
+}
+
+impl<P: Payload> Network<P> {
+    /// Constructs a Peer for sending messages from `src` to `dest` on this network
+    pub fn peer(&self, src: impl Into<String>, dest: impl Into<String>) -> Peer<P> {
+        Peer::new(self.clone(), src, dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::Receiver, thread};
+
+    use crate::{network::RpcTimeout, payload, types::Try};
+
+    use super::*;
+
+    payload!(
+        enum KvPayload {
+            Read { key: String },
+            ReadOk { value: usize },
+            Error { code: usize, text: String },
+        }
+    );
+
+    fn responder(network: Network<KvPayload>, outbound: Receiver<Message<KvPayload>>) {
+        thread::spawn(move || {
+            for msg in outbound {
+                let KvPayload::Read { key } = &msg.body.payload else {
+                    continue;
+                };
+
+                let payload = match key.as_str() {
+                    "present" => KvPayload::ReadOk { value: 5 },
+                    _ => KvPayload::Error {
+                        code: 20,
+                        text: "key does not exist".into(),
+                    },
+                };
+
+                assert_eq!(None, network.check_callback(msg.into_reply(payload)));
+            }
+        });
+    }
+
+    fn read_ok(payload: &KvPayload) -> Option<usize> {
+        match payload {
+            KvPayload::ReadOk { value } => Some(*value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_rpc_expect() -> Try {
+        let (network, outbound) = Network::new();
+        responder(network.clone(), outbound);
+
+        let peer = network.peer("n1", "seq-kv");
+        let value = peer.rpc_expect(
+            KvPayload::Read {
+                key: "present".into(),
+            },
+            read_ok,
+        )?;
+        assert_eq!(5, value);
+
+        // ids are assigned from the network's sequence
+        let value = peer.rpc_expect(
+            KvPayload::Read {
+                key: "present".into(),
+            },
+            read_ok,
+        )?;
+        assert_eq!(5, value);
+        assert_eq!(2, network.next_id());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_expect_error_reply() {
+        let (network, outbound) = Network::new();
+        responder(network.clone(), outbound);
+
+        let peer = network.peer("n1", "seq-kv");
+        let result = peer.rpc_expect(
+            KvPayload::Read {
+                key: "missing".into(),
+            },
+            read_ok,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rpc_expect_timeout() {
+        // nothing ever responds
+        let (network, _outbound) = Network::<KvPayload>::new();
+        let peer = network
+            .peer("n1", "seq-kv")
+            .with_timeout(Duration::from_millis(10));
+
+        let error = peer
+            .rpc_expect(
+                KvPayload::Read {
+                    key: "present".into(),
+                },
+                read_ok,
+            )
+            .unwrap_err();
+        assert!(error.is::<RpcTimeout>());
+    }
+}
@@ -0,0 +1,158 @@
+//! Development checks for messages against Maelstrom protocol conventions
+//! https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md
+
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::{log, types::Message};
+
+/// A way in which a message breaks protocol conventions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// the payload does not serialize with a `type` field
+    MissingType,
+    /// a request without a msg_id can't be replied to
+    RequestWithoutMsgId { kind: String },
+    /// a response must reference the msg_id of the request it answers
+    ResponseWithoutInReplyTo { kind: String },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::MissingType => write!(f, "payload has no type"),
+            Violation::RequestWithoutMsgId { kind } => {
+                write!(f, "request {kind} is missing msg_id")
+            }
+            Violation::ResponseWithoutInReplyTo { kind } => {
+                write!(f, "response {kind} is missing in_reply_to")
+            }
+        }
+    }
+}
+
+/// Returns the `type` tag a payload serializes with
+pub fn type_tag<P: Serialize>(payload: &P) -> Option<String> {
+    let value = serde_json::to_value(payload).ok()?;
+    value.get("type")?.as_str().map(str::to_string)
+}
+
+/// Returns true if a type tag names a response by convention,
+/// either `*_ok` or `error`
+pub fn is_response(kind: &str) -> bool {
+    kind.ends_with("_ok") || kind == "error"
+}
+
+/// Checks a message against protocol conventions for its type,
+/// logging and returning any violations.
+pub fn lint<P: Serialize>(msg: &Message<P>) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    match type_tag(&msg.body.payload) {
+        None => violations.push(Violation::MissingType),
+        Some(kind) if is_response(&kind) => {
+            if msg.body.in_reply_to.is_none() {
+                violations.push(Violation::ResponseWithoutInReplyTo { kind });
+            }
+        }
+        Some(kind) => {
+            if msg.body.msg_id.is_none() {
+                violations.push(Violation::RequestWithoutMsgId { kind });
+            }
+        }
+    }
+
+    for violation in &violations {
+        log::warn!("protocol lint: {violation} ({} -> {})", msg.src, msg.dest);
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{payload, types::BodyBuilder};
+
+    use super::*;
+
+    payload!(
+        enum EchoPayload {
+            Echo { echo: String },
+            EchoOk { echo: String },
+            Error { code: usize, text: String },
+        }
+    );
+
+    fn echo() -> EchoPayload {
+        EchoPayload::Echo {
+            echo: "hello".into(),
+        }
+    }
+
+    #[test]
+    fn test_valid_request_and_reply() {
+        let request = Message::new("c1", "n1", BodyBuilder::new(echo()).msg_id(1).build());
+        assert!(lint(&request).is_empty());
+
+        let reply = request.into_reply(EchoPayload::EchoOk {
+            echo: "hello".into(),
+        });
+        assert!(lint(&reply).is_empty());
+    }
+
+    #[test]
+    fn test_request_without_msg_id() {
+        let request = Message::new("c1", "n1", BodyBuilder::new(echo()).build());
+        assert_eq!(
+            lint(&request),
+            vec![Violation::RequestWithoutMsgId {
+                kind: "echo".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_reply_without_in_reply_to() {
+        let reply = Message::new(
+            "n1",
+            "c1",
+            BodyBuilder::new(EchoPayload::EchoOk {
+                echo: "hello".into(),
+            })
+            .msg_id(2)
+            .build(),
+        );
+        assert_eq!(
+            lint(&reply),
+            vec![Violation::ResponseWithoutInReplyTo {
+                kind: "echo_ok".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_error_without_in_reply_to() {
+        let error = Message::new(
+            "n1",
+            "c1",
+            BodyBuilder::new(EchoPayload::Error {
+                code: 10,
+                text: "not supported".into(),
+            })
+            .build(),
+        );
+        assert_eq!(
+            lint(&error),
+            vec![Violation::ResponseWithoutInReplyTo {
+                kind: "error".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_missing_type() {
+        let untyped = Message::new("n1", "c1", BodyBuilder::new(5).msg_id(1).build());
+        assert_eq!(lint(&untyped), vec![Violation::MissingType]);
+    }
+}
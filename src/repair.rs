@@ -0,0 +1,266 @@
+//! Defines ReadRepair, a helper for quorum reads that repair stale replicas
+
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+
+use crate::{
+    log,
+    network::Network,
+    types::{BodyBuilder, Message, Payload},
+};
+
+/// A value tagged with a version, higher versions are more up-to-date
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned<T> {
+    pub version: usize,
+    pub value: T,
+}
+
+/// Reads a value from a quorum of replicas, picks the most up-to-date response,
+/// and writes it back to any replica that responded with a stale version.
+/// This is the read phase of the ABD register.
+pub struct ReadRepair<P> {
+    network: Network<P>,
+    node_id: String,
+    replicas: Vec<String>,
+    timeout: Duration,
+}
+
+impl<P: Payload> ReadRepair<P> {
+    /// Constructs a ReadRepair that reads from `replicas` on behalf of `node_id`.
+    /// Each read (including write-back) must complete within `timeout`.
+    pub fn new(
+        network: Network<P>,
+        node_id: impl Into<String>,
+        replicas: Vec<String>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            network,
+            node_id: node_id.into(),
+            replicas,
+            timeout,
+        }
+    }
+
+    /// Number of responses required for a read to succeed
+    pub fn quorum(&self) -> usize {
+        self.replicas.len() / 2 + 1
+    }
+
+    /// Sends `read` to every replica and waits for a quorum of responses that `extract` accepts.
+    /// Replicas that responded with an older version are sent the payload built by `write`
+    /// for the newest value. Returns the newest value.
+    /// Every RPC is stamped with a msg_id from the network's `next_id`.
+    pub fn read<T>(
+        &self,
+        read: P,
+        extract: impl Fn(&P) -> Option<Versioned<T>>,
+        write: impl Fn(&Versioned<T>) -> P,
+    ) -> anyhow::Result<Versioned<T>> {
+        let deadline = Instant::now() + self.timeout;
+
+        let mut pending = Vec::with_capacity(self.replicas.len());
+        for replica in &self.replicas {
+            let body = BodyBuilder::new(read.clone())
+                .msg_id(self.network.next_id())
+                .build();
+            let (handle, callback) =
+                self.network
+                    .rpc_cancellable(Message::new(&self.node_id, replica, body))?;
+            pending.push((replica, handle, callback));
+        }
+
+        // callbacks we stop waiting on are cancelled, late responses go to the node as orphans
+        let mut responses = Vec::new();
+        for (replica, handle, callback) in pending {
+            if responses.len() >= self.quorum() {
+                handle.cancel();
+                continue;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(response) = callback.recv_timeout(remaining) else {
+                handle.cancel();
+                log::warn!("no read response from {replica}");
+                continue;
+            };
+
+            if let Some(versioned) = extract(&response.body.payload) {
+                responses.push((replica, versioned));
+            }
+        }
+
+        if responses.len() < self.quorum() {
+            bail!(
+                "read quorum not reached: {}/{}",
+                responses.len(),
+                self.quorum()
+            );
+        }
+
+        let newest = responses
+            .iter()
+            .map(|(_, versioned)| versioned.version)
+            .max()
+            .unwrap_or_default();
+        let (stale, mut current): (Vec<_>, Vec<_>) = responses
+            .into_iter()
+            .partition(|(_, versioned)| versioned.version < newest);
+        let (_, newest) = current.swap_remove(0);
+
+        // write back the newest value to stale replicas, and wait for them to ack
+        let mut repairs = Vec::with_capacity(stale.len());
+        for (replica, _) in stale {
+            log::info!("repairing stale replica {replica}");
+            let body = BodyBuilder::new(write(&newest))
+                .msg_id(self.network.next_id())
+                .build();
+            repairs.push(
+                self.network
+                    .rpc(Message::new(&self.node_id, replica, body))?,
+            );
+        }
+
+        for repair in repairs {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if repair.recv_timeout(remaining).is_err() {
+                bail!("failed to repair stale replica");
+            }
+        }
+
+        Ok(newest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, thread};
+
+    use parking_lot::Mutex;
+
+    use crate::{payload, types::Try};
+
+    use super::*;
+
+    payload!(
+        enum Register {
+            Read,
+            ReadOk { version: usize, value: usize },
+            Write { version: usize, value: usize },
+            WriteOk,
+        }
+    );
+
+    #[test]
+    fn test_repair_stale_replica() -> Try {
+        let (network, outbound) = Network::new();
+
+        // replica -> (version, value)
+        let store = Arc::new(Mutex::new(HashMap::from([
+            ("n2".to_string(), (2, 20)),
+            ("n3".to_string(), (1, 10)),
+            ("n4".to_string(), (2, 20)),
+        ])));
+
+        let replicas = network.clone();
+        let replica_store = store.clone();
+        thread::spawn(move || {
+            for msg in outbound {
+                let payload = match msg.body.payload {
+                    Register::Read => {
+                        let (version, value) = replica_store.lock()[&msg.dest];
+                        Register::ReadOk { version, value }
+                    }
+                    Register::Write { version, value } => {
+                        replica_store
+                            .lock()
+                            .insert(msg.dest.clone(), (version, value));
+                        Register::WriteOk
+                    }
+                    _ => continue,
+                };
+
+                // replies beyond the quorum may arrive after the read returns
+                replicas.check_callback(msg.into_reply(payload));
+            }
+        });
+
+        let repair = ReadRepair::new(
+            network,
+            "n1",
+            vec!["n3".into(), "n2".into(), "n4".into()],
+            Duration::from_secs(1),
+        );
+
+        let newest = repair.read(
+            Register::Read,
+            |payload| match payload {
+                Register::ReadOk { version, value } => Some(Versioned {
+                    version: *version,
+                    value: *value,
+                }),
+                _ => None,
+            },
+            |newest| Register::Write {
+                version: newest.version,
+                value: newest.value,
+            },
+        )?;
+
+        assert_eq!(
+            Versioned {
+                version: 2,
+                value: 20
+            },
+            newest
+        );
+        assert_eq!((2, 20), store.lock()["n3"]);
+
+        Ok(())
+    }
+    #[test]
+    fn test_cancels_callbacks_beyond_quorum() -> Try {
+        let (network, outbound) = Network::new();
+
+        // n4 never responds, the read doesn't need it
+        let replicas = network.clone();
+        thread::spawn(move || {
+            for msg in outbound {
+                if msg.dest == "n4" {
+                    continue;
+                }
+
+                let reply = msg.into_reply(Register::ReadOk {
+                    version: 1,
+                    value: 10,
+                });
+                replicas.check_callback(reply);
+            }
+        });
+
+        let repair = ReadRepair::new(
+            network.clone(),
+            "n1",
+            vec!["n2".into(), "n3".into(), "n4".into()],
+            Duration::from_secs(1),
+        );
+
+        let newest = repair.read(
+            Register::Read,
+            |payload| match payload {
+                Register::ReadOk { version, value } => Some(Versioned {
+                    version: *version,
+                    value: *value,
+                }),
+                _ => None,
+            },
+            |_| unreachable!("no replica is stale"),
+        )?;
+
+        assert_eq!(10, newest.value);
+        assert_eq!(0, network.pending_rpcs());
+        Ok(())
+    }
+}
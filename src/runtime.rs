@@ -1,90 +1,383 @@
 //! Defines the runtime for a Maelstrom node
 
 use std::{
-    io::{stdin, stdout, BufRead, Write},
-    sync::mpsc::{channel, Receiver, Sender},
+    any::Any,
+    io::{BufRead, Write},
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Weak,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-const EOI: &str = "EOI";
+const EOI: &[u8] = b"EOI";
+
+/// How long the runtime waits for the init message by default
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most written frames kept for reuse, bounding what's held on to after a burst
+const FRAME_POOL_CAPACITY: usize = 64;
 
 use anyhow::bail;
+use parking_lot::Mutex;
 
 use crate::{
-    network::Network,
-    node::Node,
-    types::{Init, Message, Payload, Try},
+    codec::Codec,
+    error::{ErrorCode, MaelstromError},
+    framing::{BatchedLineFramer, Framer},
+    log,
+    metrics::{Counters, Metrics},
+    network::{Backpressure, Network},
+    node::{ConcurrentNode, Handling, Node},
+    transport::{Split, StdioTransport, Transport},
+    types::{BodyBuilder, Init, Message, Payload, Try},
 };
 
-pub struct Runtime<P, N>(std::marker::PhantomData<P>, std::marker::PhantomData<N>);
-impl<P, N> Runtime<P, N>
-where
-    P: Payload,
-    N: Node<P>,
-{
-    /// Run a node using stdin/stdout.
-    /// This is the standard entrypoint for use with Maelstrom.
-    pub fn run() -> Try {
+/// Runs a node, talking to Maelstrom over a `Transport`, stdin/stdout by default
+pub struct Runtime<P, N, T = StdioTransport> {
+    transport: T,
+    init_timeout: Duration,
+    tick: Option<Duration>,
+    outbound_capacity: Option<(usize, Backpressure)>,
+    /// window repeated fire-and-forget messages are dropped within, if enabled
+    outbound_dedup: Option<Duration>,
+    recover_panics: bool,
+    /// constructs error replies for failed handlers, if enabled
+    error_reply: Option<fn(ErrorCode, String) -> P>,
+    codec: Codec,
+    /// most inbound messages read at a time, if reads are batched
+    input_batch: Option<usize>,
+    frames: FramePool,
+    /// shared with the node's network and any handle to the runtime
+    counters: Arc<Counters>,
+    /// frames queued for the output thread and not yet written,
+    /// shared with the node's network so it can report its outbound depth
+    depth: Arc<AtomicUsize>,
+    // the node is constructed on the runtime's thread, so the runtime is Send regardless
+    _types: PhantomData<fn() -> (P, N)>,
+}
+
+/// Frames handed back by the output thread once written,
+/// so serializing outbound messages reuses their allocations
+#[derive(Debug, Clone)]
+struct FramePool {
+    frames: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// most frames kept, none are reused with 0
+    capacity: usize,
+}
+
+impl Default for FramePool {
+    fn default() -> Self {
+        Self::with_capacity(FRAME_POOL_CAPACITY)
+    }
+}
+
+impl FramePool {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            frames: Arc::default(),
+            capacity,
+        }
+    }
+
+    /// An empty frame, allocated only if none are waiting to be reused
+    fn take(&self) -> Vec<u8> {
+        // the capacity serde_json starts with, most messages fit without growing
+        self.frames
+            .lock()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(128))
+    }
+
+    fn give(&self, mut frame: Vec<u8>) {
+        frame.clear();
+        let mut frames = self.frames.lock();
+        if frames.len() < self.capacity {
+            frames.push(frame);
+        }
+    }
+}
+
+/// A handle to a runtime started on background threads with `spawn`
+pub struct RuntimeHandle {
+    inbound: Sender<Vec<u8>>,
+    runtime: JoinHandle<Try>,
+    counters: Arc<Counters>,
+    /// frames not yet written by the output thread
+    depth: Arc<AtomicUsize>,
+    output: JoinHandle<()>,
+}
+
+impl RuntimeHandle {
+    /// Stops the node the same way the end of input does,
+    /// it finishes the message it is handling and calls `Node::on_shutdown`.
+    /// Does nothing if the runtime already stopped.
+    pub fn shutdown(&self) {
+        let _ = self.inbound.send(EOI.to_vec());
+    }
+
+    /// Counts of what the node has done so far, all zero until it is initialized
+    pub fn metrics(&self) -> Metrics {
+        self.counters.snapshot()
+    }
+
+    /// Waits for the runtime to stop, at the end of input or after `shutdown`,
+    /// and for the messages it sent to be written out
+    pub fn join(self) -> Try {
+        // our sender would keep the runtime waiting for input after the input ends
+        drop(self.inbound);
+        let result = self.runtime.join();
+
+        // the output thread outlives the runtime, a failed write stops it early
+        while self.depth.load(Ordering::SeqCst) > 0 && !self.output.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        match result {
+            Ok(result) => result,
+            Err(panic) => bail!("runtime panicked: {}", panic_message(&*panic)),
+        }
+    }
+}
+
+impl<P, N, T: Default> Default for Runtime<P, N, T> {
+    fn default() -> Self {
+        Self {
+            transport: T::default(),
+            init_timeout: INIT_TIMEOUT,
+            tick: None,
+            outbound_capacity: None,
+            outbound_dedup: None,
+            recover_panics: false,
+            error_reply: None,
+            codec: Codec::default(),
+            input_batch: None,
+            frames: FramePool::default(),
+            counters: Arc::default(),
+            depth: Arc::default(),
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<P: Payload, N> Runtime<P, N> {
+    /// Constructs a runtime with the default configuration,
+    /// use `start` to run it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<P: Payload, N, T: Transport> Runtime<P, N, T> {
+    /// Talks over `transport` rather than stdin/stdout, keeping the rest of the configuration
+    pub fn with_transport<U: Transport>(self, transport: U) -> Runtime<P, N, U> {
+        Runtime {
+            transport,
+            init_timeout: self.init_timeout,
+            tick: self.tick,
+            outbound_capacity: self.outbound_capacity,
+            outbound_dedup: self.outbound_dedup,
+            recover_panics: self.recover_panics,
+            error_reply: self.error_reply,
+            codec: self.codec,
+            input_batch: self.input_batch,
+            frames: self.frames,
+            counters: self.counters,
+            depth: self.depth,
+            _types: PhantomData,
+        }
+    }
+
+    /// Fails if the init message doesn't arrive within `timeout`, 5s by default
+    pub fn with_init_timeout(mut self, timeout: Duration) -> Self {
+        self.init_timeout = timeout;
+        self
+    }
+
+    /// Calls `Node::tick` every `interval` between message deliveries
+    pub fn with_tick(mut self, interval: Duration) -> Self {
+        self.tick = Some(interval);
+        self
+    }
+
+    /// Limits the node to `capacity` outbound messages waiting to be written,
+    /// see `Network::with_capacity`
+    pub fn with_outbound_capacity(mut self, capacity: usize, backpressure: Backpressure) -> Self {
+        self.outbound_capacity = Some((capacity, backpressure));
+        self
+    }
+
+    /// Drops fire-and-forget messages the node repeats within `window`,
+    /// see `Network::with_dedup`
+    pub fn with_outbound_dedup(mut self, window: Duration) -> Self {
+        self.outbound_dedup = Some(window);
+        self
+    }
+
+    /// Catches panics while the node handles a message, logging them with the message
+    /// and continuing with the next one. By default a panic stops the node.
+    pub fn with_panic_recovery(mut self) -> Self {
+        self.recover_panics = true;
+        self
+    }
+
+    /// Replies with a `Crash` error when the node fails to handle a request,
+    /// rather than stopping the node. Messages without a msg_id can't be replied to,
+    /// so failing to handle them still stops the node.
+    pub fn with_error_replies(mut self) -> Self
+    where
+        P: MaelstromError,
+    {
+        self.error_reply = Some(P::error);
+        self
+    }
+
+    /// Serializes messages with `codec` rather than JSON.
+    /// `start_with_io` and `spawn_with_io` also frame input the way the codec writes it.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Reads up to `max` messages from the input at a time, passing each batch
+    /// to the node's thread at once rather than message by message.
+    /// Only messages already buffered are batched, so none wait on the next read.
+    /// Applies to newline delimited JSON input, not to `start_framed` or other codecs.
+    pub fn with_batched_input(mut self, max: usize) -> Self {
+        self.input_batch = Some(max);
+        self
+    }
+
+    /// The most messages read at a time, if reads are batched
+    fn input_batch(&self) -> Option<usize> {
+        self.input_batch.filter(|_| self.codec == Codec::Json)
+    }
+
+    /// Splits the transport into the input thread's framer and the output thread's writer
+    fn split_transport(&self) -> anyhow::Result<Split> {
+        self.transport.clone().split(self.codec, self.input_batch())
+    }
+
+    /// Starts the input and output threads, then hands their channels to `run`
+    /// on a thread of its own
+    fn spawn_io(
+        self,
+        mut framer: impl Framer + Send + 'static,
+        mut writer: impl Write + Send + 'static,
+        handle_signals: bool,
+        run: impl FnOnce(Self, Sender<Vec<u8>>, Receiver<Vec<u8>>) -> Try + Send + 'static,
+    ) -> anyhow::Result<RuntimeHandle>
+    where
+        N: 'static,
+    {
         let (stdin_tx, stdin_rx) = channel();
-        let (stdout_tx, stdout_rx) = channel();
+        let (stdout_tx, stdout_rx) = channel::<Vec<u8>>();
 
-        // stdin thread: decouples stdin reads from node message processing
-        thread::spawn(move || {
-            let stdin = stdin().lock().lines();
+        // input thread: decouples inbound reads from node message processing.
+        // it holds the only strong handle to its sender, so input closes at EOF
+        let input_tx = Arc::new(stdin_tx.clone());
 
-            for line in stdin {
-                let line = line.unwrap();
-                stdin_tx.send(line).unwrap();
-            }
-        });
+        // maelstrom stops nodes with signals, which we treat like the end of input
+        if handle_signals {
+            forward_signals(Arc::downgrade(&input_tx))?;
+        }
 
-        // stdout thread: decouples stdout writes from node message processing
         thread::spawn(move || {
-            let mut stdout = stdout().lock();
+            while let Some(frame) = framer.next_frame_bytes().unwrap() {
+                input_tx.send(frame).unwrap();
+            }
+        });
 
-            for message in stdout_rx {
-                writeln!(&mut stdout, "{message}").unwrap();
+        // output thread: decouples writes from node message processing
+        let codec = self.codec;
+        let frames = self.frames.clone();
+        let depth = self.depth.clone();
+        let output = thread::spawn(move || {
+            for frame in stdout_rx {
+                codec.write_frame(&mut writer, &frame).unwrap();
+                // a buffered writer would otherwise hold the reply until the next one
+                writer.flush().unwrap();
+                depth.fetch_sub(1, Ordering::SeqCst);
+                frames.give(frame);
             }
         });
 
         // we give the node a Sender so it can pass outbound messages to stdout
         // and a receiver so it can pull inbound messages from stdin
-        eprintln!("Starting runtime...\nWaiting for init message");
-        Runtime::<P, N>::run_internal(stdout_tx, stdin_rx)?;
-        Ok(())
+        log::info!("Starting runtime, waiting for init message");
+        let counters = self.counters.clone();
+        let depth = self.depth.clone();
+        let runtime = thread::spawn(move || run(self, stdout_tx, stdin_rx));
+
+        Ok(RuntimeHandle {
+            inbound: stdin_tx,
+            runtime,
+            counters,
+            depth,
+            output,
+        })
     }
 
-    fn run_internal(tx: Sender<String>, rx: Receiver<String>) -> Try {
-        let init = &rx.recv()?;
-        eprintln!("Got init: {init}");
-        let init: Message<Init> = serde_json::from_str(init)?;
+    /// Waits for the init message and constructs the node with `from_init`,
+    /// then starts writing init_ok and the node's outbound messages to `tx`.
+    fn initialize<M>(
+        &self,
+        tx: Sender<Vec<u8>>,
+        rx: &Receiver<Vec<u8>>,
+        from_init: impl FnOnce(Network<P>, String, Vec<String>) -> anyhow::Result<M>,
+    ) -> anyhow::Result<(Network<P>, M)> {
+        let init = &match rx.recv_timeout(self.init_timeout) {
+            Ok(init) => init,
+            Err(RecvTimeoutError::Timeout) => {
+                bail!("no init received within {:?}", self.init_timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => bail!("input closed before init"),
+        };
+        log::info!("Got init: {}", String::from_utf8_lossy(init));
+        let init: Message<Init> = self.codec.decode(init)?;
         let Init::Init { node_id, node_ids } = &init.body.payload else {
             bail!("expected init as first message");
         };
+        log::set_node_id(node_id);
 
         // the network is how the node communicates with the runtime
-        let (network, node_receiver) = Network::new();
-        let node = N::from_init(network.clone(), node_id.clone(), node_ids.clone());
+        let (network, node_receiver) = match self.outbound_capacity {
+            Some((capacity, backpressure)) => Network::with_capacity(capacity, backpressure),
+            None => Network::new(),
+        };
+        let mut network = network
+            .with_counters(self.counters.clone())
+            .with_depth(self.depth.clone());
+        if let Some(window) = self.outbound_dedup {
+            network = network.deduplicating(window);
+        }
+        network.set_id(node_id);
+        let node = from_init(network.clone(), node_id.clone(), node_ids.clone())?;
 
-        // we are using a msg_id here that might be used by the node,
-        // which is against protocol, but maelstrom doesn't seem to mind
         let reply = init.into_reply(Init::InitOk);
 
-        eprintln!("Starting outbound processing and sending init_ok");
-        Runtime::<P, N>::process_output(reply, tx, node_receiver);
-
-        eprintln!("Starting inbound processing");
-        if let Err(e) = Runtime::process_input(rx, network, node) {
-            eprintln!("failed to process input: {e:#?}");
-        }
-
-        eprintln!("Shutting down...");
-        Ok(())
+        log::info!("Starting outbound processing and sending init_ok");
+        Self::process_output(
+            self.codec,
+            self.frames.clone(),
+            reply,
+            tx,
+            self.depth.clone(),
+            node_receiver,
+        );
+        Ok((network, node))
     }
 
     fn process_output(
+        codec: Codec,
+        frames: FramePool,
         reply: Message<Init>,
-        tx: Sender<String>,
+        tx: Sender<Vec<u8>>,
+        depth: Arc<AtomicUsize>,
         node_receiver: Receiver<Message<P>>,
     ) -> JoinHandle<Try> {
         // output thread: decouples node sending outbound messages from
@@ -92,60 +385,504 @@ where
         // even if it isn't receiving any.
         thread::spawn::<_, Try>(move || {
             // send the init_ok
-            let mut json = serde_json::to_string(&reply)?;
-            eprintln!("Writing init_ok: {json}");
-            tx.send(json)?;
+            let frame = codec.encode(&reply)?;
+            log::debug!("Writing init_ok: {}", String::from_utf8_lossy(&frame));
+            depth.fetch_add(1, Ordering::SeqCst);
+            tx.send(frame)?;
 
-            // reply to other messages
+            // reply to other messages, serialized into frames the output thread is done with
             loop {
                 let outbound = node_receiver.recv()?;
-                json = serde_json::to_string(&outbound)?;
-                eprintln!("Writing outbound message: {json}");
-                tx.send(json)?;
+                let mut frame = frames.take();
+                codec.encode_into(&mut frame, &outbound)?;
+                log::debug!(
+                    "Writing outbound message: {}",
+                    String::from_utf8_lossy(&frame)
+                );
+                // the network counted the message when it was sent,
+                // the output thread counts it down once it's written
+                tx.send(frame)?;
             }
         })
     }
 
-    fn process_input(rx: Receiver<String>, network: Network<P>, mut node: N) -> Try {
+    /// Parses inbound messages until EOI, returning a Receiver
+    /// for the ones that aren't responses to pending rpcs.
+    /// `batched` frames hold several newline separated messages.
+    /// Repeated inits are answered on `output` rather than delivered, counted on `depth`.
+    fn route_callbacks(
+        codec: Codec,
+        batched: bool,
+        rx: Receiver<Vec<u8>>,
+        output: Sender<Vec<u8>>,
+        depth: Arc<AtomicUsize>,
+        network: Network<P>,
+    ) -> Receiver<Message<P>> {
         let (json_tx, json_rx) = channel();
+        network.set_inbound(json_tx.clone());
 
         // callback thread: allows us to process input and check for pending
         // rpc callbacks even if the node is still handling a message.
         thread::spawn(move || {
-            for line in rx {
-                if line == EOI {
-                    eprintln!("Got EOI");
+            'input: for frames in rx {
+                for frame in frames.split(|b| batched && *b == b'\n') {
+                    if frame == EOI {
+                        log::info!("Got EOI");
 
-                    break;
-                }
+                        break 'input;
+                    }
 
-                eprintln!("Got message: {line}");
-                let message: Message<P> = serde_json::from_str(&line).unwrap();
+                    let line = String::from_utf8_lossy(frame);
+                    log::debug!("Got message: {line}");
+                    let message: Message<P> = match codec.decode(frame) {
+                        Ok(message) => {
+                            network.counters().received();
+                            message
+                        }
+                        Err(_) if answer_repeated_init(codec, frame, &output, &depth) => continue,
+                        Err(e) => {
+                            log::warn!("Skipping malformed message ({e}): {line}");
+                            continue;
+                        }
+                    };
 
-                // we try checking for pending callbacks for the message, if not,
-                // check_callback returns ownership of the message so that we may deliver
-                // it to the node as a regular message rather than an RPC response
-                if let Some(message) = network.check_callback(message) {
-                    json_tx.send(message).unwrap();
+                    // we try checking for pending callbacks for the message, if not,
+                    // check_callback returns ownership of the message so that we may deliver
+                    // it to the node as a regular message rather than an RPC response
+                    if let Some(message) = network.check_callback(message) {
+                        json_tx.send(message).unwrap();
+                    }
                 }
             }
+
+            // the node can't send to itself once input ends, so its inbound channel closes
+            network.close_inbound();
         });
 
-        for message in json_rx {
-            node.handle_message(message)?;
+        json_rx
+    }
+}
+
+impl<P, N> Runtime<P, N>
+where
+    P: Payload,
+    N: Node<P> + 'static,
+{
+    /// Run a node using stdin/stdout.
+    /// This is the standard entrypoint for use with Maelstrom.
+    pub fn run() -> Try {
+        Runtime::<P, N>::new().start()
+    }
+
+    /// Run a node reading inbound messages from `framer` and writing to stdout.
+    pub fn run_framed(framer: impl Framer + Send + 'static) -> Try {
+        Runtime::<P, N>::new().start_framed(framer)
+    }
+
+    /// Run a node using stdin/stdout, reading up to `max` messages at a time.
+    /// See `with_batched_input`.
+    pub fn run_batched(max: usize) -> Try {
+        Runtime::<P, N>::new().with_batched_input(max).start()
+    }
+
+    /// Run a node using stdin/stdout, recovering from panics in the node's handler.
+    /// See `with_panic_recovery`.
+    pub fn run_resilient() -> Try {
+        Runtime::<P, N>::new().with_panic_recovery().start()
+    }
+
+    /// Run a node reading newline delimited messages from `reader` and writing to `writer`,
+    /// for embedding a node over a transport other than stdin/stdout.
+    pub fn run_with_io(
+        reader: impl BufRead + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> Try {
+        Runtime::<P, N>::new().start_with_io(reader, writer)
+    }
+
+    /// Run a node reading messages serialized with `codec` from `reader`
+    /// and writing to `writer`. See `with_codec`.
+    pub fn run_with_codec(
+        codec: Codec,
+        reader: impl BufRead + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> Try {
+        Runtime::<P, N>::new()
+            .with_codec(codec)
+            .start_with_io(reader, writer)
+    }
+
+    /// Run a node reading and writing newline delimited messages over `transport`,
+    /// such as a `TcpTransport`, to run it outside of Maelstrom.
+    pub fn run_with_transport(transport: impl Transport) -> Try {
+        Runtime::<P, N>::new().start_with_transport(transport)
+    }
+}
+
+impl<P, N, T> Runtime<P, N, T>
+where
+    P: Payload,
+    N: Node<P> + 'static,
+    T: Transport,
+{
+    /// Run the configured runtime over its transport, stdin/stdout by default.
+    pub fn start(self) -> Try {
+        self.spawn()?.join()
+    }
+
+    /// Run the configured runtime reading inbound messages from `framer`
+    /// and writing to its transport.
+    pub fn start_framed(mut self, framer: impl Framer + Send + 'static) -> Try {
+        // the framer's frames are single messages
+        self.input_batch = None;
+        let (_, writer) = self.split_transport()?;
+        self.spawn_io(framer, writer, T::STOPPED_BY_SIGNALS, Self::run_internal)?
+            .join()
+    }
+
+    /// Start the configured runtime over its transport on background threads,
+    /// for embedding a node in a larger program that keeps running alongside it.
+    pub fn spawn(self) -> anyhow::Result<RuntimeHandle> {
+        let (framer, writer) = self.split_transport()?;
+        self.spawn_io(framer, writer, T::STOPPED_BY_SIGNALS, Self::run_internal)
+    }
+
+    /// Start the configured runtime on background threads, reading newline delimited
+    /// messages from `reader` and writing to `writer`. See `spawn` and `start_with_io`.
+    pub fn spawn_with_io(
+        self,
+        reader: impl BufRead + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> anyhow::Result<RuntimeHandle> {
+        let framer: Box<dyn Framer + Send> = match self.input_batch() {
+            Some(max) => Box::new(BatchedLineFramer::new(reader, max)),
+            None => self.codec.framer(reader),
+        };
+        self.spawn_io(framer, writer, false, Self::run_internal)
+    }
+
+    /// Run the configured runtime reading newline delimited messages from `reader`
+    /// and writing to `writer`, returning once the node's messages are written.
+    /// Unlike `start`, this doesn't handle process signals.
+    pub fn start_with_io(
+        self,
+        reader: impl BufRead + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> Try {
+        self.spawn_with_io(reader, writer)?.join()
+    }
+
+    /// Run the configured runtime over `transport`, see `with_transport`
+    pub fn start_with_transport(self, transport: impl Transport) -> Try {
+        self.with_transport(transport).start()
+    }
+
+    /// Start the configured runtime over `transport` on background threads.
+    /// See `spawn` and `with_transport`.
+    pub fn spawn_with_transport(self, transport: impl Transport) -> anyhow::Result<RuntimeHandle> {
+        self.with_transport(transport).spawn()
+    }
+
+    fn run_internal(self, tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) -> Try {
+        let output = tx.clone();
+        let (network, node) = self.initialize(tx, &rx, |network, node_id, node_ids| {
+            let mut node = N::from_init(network, node_id, node_ids);
+            node.on_init()?;
+            Ok(node)
+        })?;
+
+        log::info!("Starting inbound processing");
+        if let Err(e) = self.process_input(rx, output, network.clone(), node) {
+            log::warn!("failed to process input: {e:#?}");
+        }
+
+        log::info!("Shutting down...");
+        network.shut_down();
+        log::info!("Metrics: {}", network.metrics());
+        Ok(())
+    }
+
+    fn process_input(
+        &self,
+        rx: Receiver<Vec<u8>>,
+        output: Sender<Vec<u8>>,
+        network: Network<P>,
+        mut node: N,
+    ) -> Try {
+        let batched = self.input_batch().is_some();
+        let json_rx = Self::route_callbacks(
+            self.codec,
+            batched,
+            rx,
+            output,
+            self.depth.clone(),
+            network.clone(),
+        );
+
+        // ticks are delivered on this thread between messages,
+        // so they never run concurrently with handle_message
+        let mut next_tick = self.tick.map(|interval| Instant::now() + interval);
+        loop {
+            // nothing the node sends can be written anymore, so we stop like at the end of input
+            if network.is_closed() {
+                log::info!("Outbound channel closed, shutting down");
+                break;
+            }
+
+            if let (Some(interval), Some(deadline)) = (self.tick, next_tick) {
+                if Instant::now() >= deadline {
+                    match node.tick(&network) {
+                        Err(_) if network.is_closed() => continue,
+                        result => result?,
+                    }
+                    next_tick = Some(Instant::now() + interval);
+                }
+            }
+
+            let message = match next_tick {
+                Some(deadline) => {
+                    match json_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(message) => message,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match json_rx.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                },
+            };
+
+            // replies to pending rpcs were already routed by the callback thread
+            if let Some(in_reply_to) = message.body.in_reply_to {
+                log::debug!("Got orphan reply to {in_reply_to} from {}", message.src);
+                match node.handle_orphan_reply(message) {
+                    Err(_) if network.is_closed() => continue,
+                    result => result?,
+                }
+                continue;
+            }
+
+            let src = message.src.clone();
+            let dest = message.dest.clone();
+            let msg_id = message.body.msg_id;
+            let result = if self.recover_panics {
+                match panic::catch_unwind(AssertUnwindSafe(|| node.try_handle_message(message))) {
+                    Ok(result) => result,
+                    Err(panic) => {
+                        self.counters.handler_error();
+                        log::warn!(
+                            "Handler panicked on message {msg_id:?} from {src} to {dest}: {}",
+                            panic_message(&*panic)
+                        );
+                        continue;
+                    }
+                }
+            } else {
+                node.try_handle_message(message)
+            };
+
+            if result.is_err() {
+                self.counters.handler_error();
+            }
+
+            let handling = match (result, self.error_reply, msg_id) {
+                (Ok(handling), _, _) => handling,
+                (Err(_), _, _) if network.is_closed() => continue,
+                (Err(e), Some(error), Some(msg_id)) => {
+                    log::warn!("Failed to handle message {msg_id} from {src}: {e:#}");
+                    let body = BodyBuilder::new(error(ErrorCode::Crash, format!("{e:#}")))
+                        .in_reply_to(msg_id)
+                        .build();
+                    match network.send(Message::new(dest, src, body)) {
+                        Err(_) if network.is_closed() => continue,
+                        result => result?,
+                    }
+                    continue;
+                }
+                (Err(e), _, _) => return Err(e),
+            };
+
+            if let Handling::Ignored = handling {
+                log::debug!("Ignored message {msg_id:?} from {src}");
+            }
         }
 
-        eprintln!("done processing input");
+        log::info!("done processing input");
+        node.on_shutdown()
+    }
+}
+
+impl<P, N> Runtime<P, N>
+where
+    P: Payload,
+    N: ConcurrentNode<P>,
+{
+    /// Run a node using stdin/stdout, handling up to `pool_size` messages at a time.
+    /// See `ConcurrentNode`.
+    pub fn run_concurrent(pool_size: usize) -> Try {
+        Runtime::<P, N>::new().start_concurrent(pool_size)
+    }
+}
+
+impl<P, N, T> Runtime<P, N, T>
+where
+    P: Payload,
+    N: ConcurrentNode<P>,
+    T: Transport,
+{
+    /// Run the configured runtime over its transport,
+    /// handling up to `pool_size` messages at a time.
+    /// Ticks, panic recovery and error replies don't apply to concurrent nodes.
+    pub fn start_concurrent(self, pool_size: usize) -> Try {
+        let (framer, writer) = self.split_transport()?;
+        self.spawn_io(
+            framer,
+            writer,
+            T::STOPPED_BY_SIGNALS,
+            move |runtime, tx, rx| runtime.run_concurrent_internal(pool_size, tx, rx),
+        )?
+        .join()
+    }
+
+    fn run_concurrent_internal(
+        self,
+        pool_size: usize,
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+    ) -> Try {
+        let output = tx.clone();
+        let (network, node) = self.initialize(tx, &rx, |network, node_id, node_ids| {
+            Ok(N::from_init(network, node_id, node_ids))
+        })?;
+        let node = Arc::new(node);
+
+        log::info!("Starting inbound processing with {pool_size} workers");
+        let batched = self.input_batch().is_some();
+        let inbound = Self::route_callbacks(
+            self.codec,
+            batched,
+            rx,
+            output,
+            self.depth.clone(),
+            network.clone(),
+        );
+        let inbound = Arc::new(Mutex::new(inbound));
+        let workers: Vec<_> = (0..pool_size)
+            .map(|_| {
+                let inbound = inbound.clone();
+                let node = node.clone();
+                let network = network.clone();
+                thread::spawn(move || loop {
+                    // the lock is only held while waiting for the next message
+                    let Ok(message) = inbound.lock().recv() else {
+                        break;
+                    };
+
+                    let src = message.src.clone();
+                    let msg_id = message.body.msg_id;
+                    if let Err(e) = node.handle_message(message) {
+                        network.counters().handler_error();
+                        log::warn!("Failed to handle message {msg_id:?} from {src}: {e:#}");
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            if worker.join().is_err() {
+                log::warn!("worker panicked");
+            }
+        }
+
+        log::info!("Shutting down...");
+        network.shut_down();
+        log::info!("Metrics: {}", network.metrics());
         Ok(())
     }
 }
 
+/// Replies init_ok to a repeated init, returning whether `frame` was one.
+/// The node was constructed from the first init, so it isn't constructed again.
+fn answer_repeated_init(
+    codec: Codec,
+    frame: &[u8],
+    output: &Sender<Vec<u8>>,
+    depth: &AtomicUsize,
+) -> bool {
+    let Ok(init) = codec.decode::<Message<Init>>(frame) else {
+        return false;
+    };
+    let Init::Init { .. } = init.body.payload else {
+        return false;
+    };
+
+    log::info!("Got repeated init, replying init_ok again");
+    match codec.encode(&init.into_reply(Init::InitOk)) {
+        Ok(init_ok) => {
+            depth.fetch_add(1, Ordering::SeqCst);
+            let _ = output.send(init_ok);
+        }
+        Err(e) => log::warn!("failed to encode init_ok: {e}"),
+    }
+
+    true
+}
+
+/// Extracts the message a panic was raised with, if it has one
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Sends EOI on `inbound` when the process receives SIGTERM or SIGINT,
+/// so the node shuts down the same way it does at the end of input.
+/// Only a weak handle is kept, so the input still closes once its reader ends.
+/// The first runtime started in a process installs the handler, later ones register with it.
+#[cfg(unix)]
+fn forward_signals(inbound: Weak<Sender<Vec<u8>>>) -> Try {
+    use signal_hook::{
+        consts::{SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+
+    static INPUTS: Mutex<Vec<Weak<Sender<Vec<u8>>>>> = parking_lot::const_mutex(Vec::new());
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+    if !INSTALLED.swap(true, Ordering::SeqCst) {
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                log::info!("Got signal {signal}, shutting down");
+                // inputs that already closed are dropped
+                INPUTS.lock().retain(|input| {
+                    input
+                        .upgrade()
+                        .is_some_and(|input| input.send(EOI.to_vec()).is_ok())
+                });
+            }
+        });
+    }
+
+    INPUTS.lock().push(inbound);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn forward_signals(_inbound: Weak<Sender<Vec<u8>>>) -> Try {
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
-    use std::{sync::mpsc::Sender, thread::JoinHandle};
+    use std::{io::BufReader, sync::atomic::AtomicUsize, thread::JoinHandle};
 
-    use crate::{payload, types::BodyBuilder};
+    use crate::{framing::LineFramer, payload, types::BodyBuilder};
 
     use super::*;
 
@@ -153,9 +890,19 @@ mod tests {
         enum EchoPayload {
             Echo { echo: String },
             EchoOk { echo: String },
+            Error { code: usize, text: String },
         }
     );
 
+    impl MaelstromError for EchoPayload {
+        fn error(code: ErrorCode, text: String) -> Self {
+            EchoPayload::Error {
+                code: code.into(),
+                text,
+            }
+        }
+    }
+
     struct EchoNode {
         network: Network<EchoPayload>,
         seq: usize,
@@ -180,6 +927,1561 @@ mod tests {
         }
     }
 
+    /// Echoes the number of times it was initialized
+    struct InitCountNode {
+        network: Network<EchoPayload>,
+        inits: usize,
+    }
+
+    impl Node<EchoPayload> for InitCountNode {
+        fn from_init(network: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            InitCountNode { network, inits: 0 }
+        }
+
+        fn on_init(&mut self) -> Try {
+            self.inits += 1;
+            Ok(())
+        }
+
+        fn handle_message(&mut self, msg: Message<EchoPayload>) -> Try {
+            let echo = self.inits.to_string();
+            self.network.reply(msg, EchoPayload::EchoOk { echo })
+        }
+    }
+
+    /// Calls of the timer `HeartbeatNode` starts, counted across the whole test run
+    static HEARTBEATS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Sends a heartbeat to c0 every 10ms from a timer started on init
+    struct HeartbeatNode;
+
+    impl Node<EchoPayload> for HeartbeatNode {
+        fn from_init(network: Network<EchoPayload>, node_id: String, _: Vec<String>) -> Self {
+            // the timer holds the network, so only shutting it down stops the timer
+            let timer_network = network.clone();
+            network.every(Duration::from_millis(10), move || {
+                HEARTBEATS.fetch_add(1, Ordering::SeqCst);
+                let heartbeat = BodyBuilder::new(EchoPayload::Echo {
+                    echo: "heartbeat".into(),
+                })
+                .build();
+                timer_network.send(Message::new(&node_id, "c0", heartbeat))
+            });
+
+            HeartbeatNode
+        }
+
+        fn handle_message(&mut self, _: Message<EchoPayload>) -> Try {
+            Ok(())
+        }
+    }
+
+    /// Nodes `CountedNode` has constructed, counted across the whole test run
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+    struct CountedNode;
+
+    impl Node<EchoPayload> for CountedNode {
+        fn from_init(_: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            CONSTRUCTED.fetch_add(1, Ordering::SeqCst);
+            CountedNode
+        }
+
+        fn handle_message(&mut self, msg: Message<EchoPayload>) -> Try {
+            bail!("unexpected message {msg:?}")
+        }
+    }
+
+    /// Echoes messages from c1 and ignores everyone else
+    struct PickyNode {
+        network: Network<EchoPayload>,
+        ignored: usize,
+    }
+
+    impl Node<EchoPayload> for PickyNode {
+        fn from_init(network: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            PickyNode {
+                network,
+                ignored: 0,
+            }
+        }
+
+        fn handle_message(&mut self, msg: Message<EchoPayload>) -> Try {
+            self.try_handle_message(msg).map(|_| ())
+        }
+
+        fn try_handle_message(&mut self, msg: Message<EchoPayload>) -> anyhow::Result<Handling> {
+            let EchoPayload::Echo { echo } = &msg.body.payload else {
+                bail!("expected echo");
+            };
+
+            if msg.src != "c1" {
+                self.ignored += 1;
+                return Ok(Handling::Ignored);
+            }
+
+            let echo = format!("{echo} (ignored {})", self.ignored);
+            self.network
+                .send(msg.into_reply(EchoPayload::EchoOk { echo }))?;
+            Ok(Handling::Handled)
+        }
+    }
+
+    /// Passes echoes from clients through itself before replying
+    struct LoopbackNode {
+        network: Network<EchoPayload>,
+        pending: Option<Message<EchoPayload>>,
+    }
+
+    impl Node<EchoPayload> for LoopbackNode {
+        fn from_init(network: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            LoopbackNode {
+                network,
+                pending: None,
+            }
+        }
+
+        fn handle_message(&mut self, msg: Message<EchoPayload>) -> Try {
+            let EchoPayload::Echo { echo } = &msg.body.payload else {
+                bail!("expected echo");
+            };
+
+            let echo = echo.clone();
+            if msg.src_id().is_client() {
+                let loopback = BodyBuilder::new(EchoPayload::Echo { echo }).build();
+                self.network.send(Message::new("n1", "n1", loopback))?;
+                self.pending = Some(msg);
+                return Ok(());
+            }
+
+            let Some(request) = self.pending.take() else {
+                bail!("no pending echo");
+            };
+            let echo = format!("{echo} from {}", msg.src);
+            self.network.reply(request, EchoPayload::EchoOk { echo })
+        }
+    }
+
+    #[test]
+    fn test_send_to_self() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        thread::spawn(move || {
+            Runtime::<EchoPayload, LoopbackNode>::new()
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(1)
+            .build(),
+        );
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        let echo = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(EchoPayload::Echo {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(2)
+            .build(),
+        );
+        stdin_tx.send(serde_json::to_vec(&echo)?)?;
+
+        // the message to itself never reaches the output, only the reply does
+        let reply: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(
+            reply.body.payload,
+            EchoPayload::EchoOk {
+                echo: "ding-dong! from n1".into()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_init() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        thread::spawn(move || {
+            Runtime::<EchoPayload, InitCountNode>::new()
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        for msg_id in [4, 5] {
+            let echo = Message::new(
+                "c2",
+                "n1",
+                BodyBuilder::new(EchoPayload::Echo {
+                    echo: "ding-dong!".into(),
+                })
+                .msg_id(msg_id)
+                .build(),
+            );
+            stdin_tx.send(serde_json::to_vec(&echo)?)?;
+
+            let reply: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+            assert_eq!(EchoPayload::EchoOk { echo: "1".into() }, reply.body.payload);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shutdown_stops_timers() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        let runtime = thread::spawn(move || {
+            Runtime::<EchoPayload, HeartbeatNode>::new().run_internal(stdout_tx, stdin_rx)
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        let heartbeat: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!("c0", heartbeat.dest);
+
+        stdin_tx.send(EOI.to_vec())?;
+        runtime.join().unwrap()?;
+
+        // a call already under way when the runtime stopped is the last one
+        thread::sleep(Duration::from_millis(20));
+        let stopped = HEARTBEATS.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(stopped, HEARTBEATS.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeated_init() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        let runtime = thread::spawn(move || {
+            Runtime::<EchoPayload, CountedNode>::new().run_internal(stdout_tx, stdin_rx)
+        });
+
+        for msg_id in [3, 4] {
+            let init = Message::new(
+                "c2",
+                "n1",
+                BodyBuilder::new(Init::Init {
+                    node_id: "n1".into(),
+                    node_ids: vec!["n1".into()],
+                })
+                .msg_id(msg_id)
+                .build(),
+            );
+            stdin_tx.send(serde_json::to_vec(&init)?)?;
+
+            let init_ok: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+            assert_eq!(Init::InitOk, init_ok.body.payload);
+            assert_eq!(Some(msg_id), init_ok.body.in_reply_to);
+        }
+
+        // the repeat is answered by the runtime, the node is only constructed once
+        stdin_tx.send(EOI.to_vec())?;
+        runtime.join().unwrap()?;
+        assert_eq!(1, CONSTRUCTED.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignored_message() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        thread::spawn(move || {
+            Runtime::<EchoPayload, PickyNode>::new()
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        for src in ["c2", "c1"] {
+            let echo = Message::new(
+                src,
+                "n1",
+                BodyBuilder::new(EchoPayload::Echo {
+                    echo: "ding-dong!".into(),
+                })
+                .msg_id(4)
+                .build(),
+            );
+            stdin_tx.send(serde_json::to_vec(&echo)?)?;
+        }
+
+        // the runtime keeps delivering after an ignored message
+        let reply: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(
+            reply.body.payload,
+            EchoPayload::EchoOk {
+                echo: "ding-dong! (ignored 1)".into()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignored_message_log() -> Try {
+        // the level is read once per process, so the test runs in a process per level
+        for (level, logged) in [("debug", true), ("info", false)] {
+            let output = std::process::Command::new(std::env::current_exe()?)
+                .args([
+                    "--exact",
+                    "runtime::tests::test_ignored_message",
+                    "--nocapture",
+                ])
+                .env("MAELBREAKER_LOG", level)
+                .output()?;
+            assert!(output.status.success());
+
+            let stderr = String::from_utf8(output.stderr)?;
+            assert_eq!(
+                logged,
+                stderr.contains("[debug][n1] Ignored message Some(4) from c2"),
+                "{level}: {stderr}"
+            );
+            // info lines are written at either level
+            assert!(stderr.contains("[info][n1] Starting outbound processing"));
+        }
+        Ok(())
+    }
+
+    /// Echoes messages, and reports orphan replies to c1
+    struct OrphanNode {
+        network: Network<EchoPayload>,
+    }
+
+    impl Node<EchoPayload> for OrphanNode {
+        fn from_init(network: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            OrphanNode { network }
+        }
+
+        fn handle_message(&mut self, msg: Message<EchoPayload>) -> Try {
+            let EchoPayload::Echo { echo } = &msg.body.payload else {
+                bail!("expected echo");
+            };
+
+            let echo = echo.clone();
+            self.network.reply(msg, EchoPayload::EchoOk { echo })
+        }
+
+        fn handle_orphan_reply(&mut self, msg: Message<EchoPayload>) -> Try {
+            let orphan = Message::new(
+                "n1",
+                "c1",
+                BodyBuilder::new(EchoPayload::Echo {
+                    echo: format!("orphan {:?}", msg.body.in_reply_to),
+                })
+                .build(),
+            );
+            self.network.send(orphan)
+        }
+    }
+
+    #[test]
+    fn test_orphan_reply() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        thread::spawn(move || {
+            Runtime::<EchoPayload, OrphanNode>::new()
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        // a reply to an rpc the node never made, followed by a regular request
+        let late = Message::new(
+            "n2",
+            "n1",
+            BodyBuilder::new(EchoPayload::EchoOk {
+                echo: "late".into(),
+            })
+            .msg_id(8)
+            .in_reply_to(7)
+            .build(),
+        );
+        stdin_tx.send(serde_json::to_vec(&late)?)?;
+
+        let echo = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(EchoPayload::Echo {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(4)
+            .build(),
+        );
+        stdin_tx.send(serde_json::to_vec(&echo)?)?;
+
+        let orphan: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!(
+            orphan.body.payload,
+            EchoPayload::Echo {
+                echo: "orphan Some(7)".into()
+            }
+        );
+
+        let reply: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!(
+            reply.body.payload,
+            EchoPayload::EchoOk {
+                echo: "ding-dong!".into()
+            }
+        );
+        Ok(())
+    }
+
+    /// Echoes messages, panicking on "poison"
+    struct PoisonNode {
+        network: Network<EchoPayload>,
+    }
+
+    impl Node<EchoPayload> for PoisonNode {
+        fn from_init(network: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            PoisonNode { network }
+        }
+
+        fn handle_message(&mut self, msg: Message<EchoPayload>) -> Try {
+            let EchoPayload::Echo { echo } = &msg.body.payload else {
+                bail!("expected echo");
+            };
+
+            if echo == "poison" {
+                panic!("poisoned");
+            }
+
+            let echo = echo.clone();
+            self.network.reply(msg, EchoPayload::EchoOk { echo })
+        }
+    }
+
+    #[test]
+    fn test_panic_recovery() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        thread::spawn(move || {
+            Runtime::<EchoPayload, PoisonNode>::new()
+                .with_panic_recovery()
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        for (msg_id, echo) in [(4, "poison"), (5, "ding-dong!")] {
+            let echo = Message::new(
+                "c1",
+                "n1",
+                BodyBuilder::new(EchoPayload::Echo { echo: echo.into() })
+                    .msg_id(msg_id)
+                    .build(),
+            );
+            stdin_tx.send(serde_json::to_vec(&echo)?)?;
+        }
+
+        // the node keeps handling messages after the poison message
+        let reply: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!(Some(5), reply.body.in_reply_to);
+        assert_eq!(
+            reply.body.payload,
+            EchoPayload::EchoOk {
+                echo: "ding-dong!".into()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_replies() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        thread::spawn(move || {
+            Runtime::<EchoPayload, EchoNode>::new()
+                .with_error_replies()
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        // EchoNode fails on anything but an echo
+        let unexpected = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(EchoPayload::EchoOk {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(4)
+            .build(),
+        );
+        stdin_tx.send(serde_json::to_vec(&unexpected)?)?;
+
+        let reply: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!("c1", reply.dest);
+        assert_eq!(Some(4), reply.body.in_reply_to);
+        assert_eq!(
+            reply.body.payload,
+            EchoPayload::Error {
+                code: 13,
+                text: "expected echo".into()
+            }
+        );
+
+        // and keeps running afterwards
+        let echo = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(EchoPayload::Echo {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(5)
+            .build(),
+        );
+        stdin_tx.send(serde_json::to_vec(&echo)?)?;
+
+        let reply: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!(Some(5), reply.body.in_reply_to);
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame_pool() {
+        let frames = FramePool::default();
+        let mut frame = frames.take();
+        frame.extend_from_slice(b"{}");
+        let capacity = frame.capacity();
+
+        // returned frames are reused empty, without a new allocation
+        frames.give(frame);
+        let frame = frames.take();
+        assert!(frame.is_empty());
+        assert_eq!(capacity, frame.capacity());
+
+        for _ in 0..2 * FRAME_POOL_CAPACITY {
+            frames.give(Vec::new());
+        }
+        assert_eq!(FRAME_POOL_CAPACITY, frames.frames.lock().len());
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let panic = panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!("static", panic_message(&*panic));
+
+        let panic = panic::catch_unwind(|| panic!("formatted {}", 5)).unwrap_err();
+        assert_eq!("formatted 5", panic_message(&*panic));
+    }
+
+    #[test]
+    fn test_handling_from_unit() {
+        assert_eq!(Handling::Handled, Handling::from(()));
+    }
+
+    struct TickNode;
+
+    impl Node<EchoPayload> for TickNode {
+        fn from_init(_: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            TickNode
+        }
+
+        fn handle_message(&mut self, _: Message<EchoPayload>) -> Try {
+            Ok(())
+        }
+
+        fn tick(&mut self, network: &Network<EchoPayload>) -> Try {
+            let tick = Message::new(
+                "n1",
+                "c1",
+                BodyBuilder::new(EchoPayload::Echo {
+                    echo: "tick".into(),
+                })
+                .build(),
+            );
+            network.send(tick)
+        }
+    }
+
+    #[test]
+    fn test_tick() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        thread::spawn(move || {
+            Runtime::<EchoPayload, TickNode>::new()
+                .with_tick(Duration::from_millis(20))
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        let start = Instant::now();
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        for _ in 0..3 {
+            let tick: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+            assert_eq!(
+                tick.body.payload,
+                EchoPayload::Echo {
+                    echo: "tick".into()
+                }
+            );
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        Ok(())
+    }
+
+    static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+    struct ShutdownNode;
+
+    impl Node<EchoPayload> for ShutdownNode {
+        fn from_init(_: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            ShutdownNode
+        }
+
+        fn handle_message(&mut self, _: Message<EchoPayload>) -> Try {
+            Ok(())
+        }
+
+        fn on_shutdown(&mut self) -> Try {
+            SHUT_DOWN.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_on_shutdown() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        let runtime = thread::spawn(move || {
+            Runtime::<EchoPayload, ShutdownNode>::new()
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert!(!SHUT_DOWN.load(Ordering::SeqCst));
+
+        stdin_tx.send(EOI.to_vec())?;
+        runtime.join().unwrap();
+        assert!(SHUT_DOWN.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signal_shutdown() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+        let stdin_tx = Arc::new(stdin_tx);
+        forward_signals(Arc::downgrade(&stdin_tx))?;
+
+        let runtime = thread::spawn(move || {
+            Runtime::<EchoPayload, EchoNode>::new().run_internal(stdout_tx, stdin_rx)
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGTERM)?;
+        runtime.join().unwrap()
+    }
+
+    #[test]
+    fn test_eof_with_signal_forwarding() -> Try {
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+        let input = format!("{}\n", serde_json::to_string(&init)?);
+
+        // the signal handler only holds a weak handle, so EOF still ends the runtime
+        let (done_tx, done_rx) = channel();
+        thread::spawn(move || {
+            let framer = LineFramer::new(BufReader::new(std::io::Cursor::new(input)));
+            let result = Runtime::<EchoPayload, EchoNode>::new()
+                .spawn_io(framer, std::io::sink(), true, Runtime::run_internal)
+                .and_then(RuntimeHandle::join);
+            let _ = done_tx.send(result);
+        });
+
+        done_rx.recv_timeout(Duration::from_secs(5))?
+    }
+
+    /// Forwards everything written to it over a channel
+    struct ChannelWriter(Sender<Vec<u8>>);
+
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self.0.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_with_io() -> Try {
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+        let echo = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(EchoPayload::Echo {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(4)
+            .build(),
+        );
+        let input = format!(
+            "{}\n{}\n",
+            serde_json::to_string(&init)?,
+            serde_json::to_string(&echo)?
+        );
+
+        let (output_tx, output_rx) = channel();
+        Runtime::<EchoPayload, EchoNode>::run_with_io(
+            std::io::Cursor::new(input),
+            ChannelWriter(output_tx),
+        )?;
+
+        let output = String::from_utf8(output_rx.try_iter().flatten().collect())?;
+        let mut lines = output.lines();
+        let _: Message<Init> = serde_json::from_str(lines.next().unwrap())?;
+        let reply: Message<EchoPayload> = serde_json::from_str(lines.next().unwrap())?;
+        assert_eq!(
+            reply.body.payload,
+            EchoPayload::EchoOk {
+                echo: "ding-dong!".into()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_input() -> Try {
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+        let mut input = format!("{}\n", serde_json::to_string(&init)?);
+        for msg_id in 4..9 {
+            let echo = Message::new(
+                "c2",
+                "n1",
+                BodyBuilder::new(EchoPayload::Echo {
+                    echo: format!("echo {msg_id}"),
+                })
+                .msg_id(msg_id)
+                .build(),
+            );
+            input.push_str(&format!("{}\n", serde_json::to_string(&echo)?));
+        }
+
+        let (output_tx, output_rx) = channel();
+        Runtime::<EchoPayload, EchoNode>::new()
+            .with_batched_input(2)
+            .start_with_io(std::io::Cursor::new(input), ChannelWriter(output_tx))?;
+
+        // every message in every batch is delivered, in order
+        let output = String::from_utf8(output_rx.try_iter().flatten().collect())?;
+        let mut lines = output.lines();
+        let _: Message<Init> = serde_json::from_str(lines.next().unwrap())?;
+        for msg_id in 4..9 {
+            let reply: Message<EchoPayload> = serde_json::from_str(lines.next().unwrap())?;
+            assert_eq!(Some(msg_id), reply.body.in_reply_to);
+            assert_eq!(
+                EchoPayload::EchoOk {
+                    echo: format!("echo {msg_id}")
+                },
+                reply.body.payload
+            );
+        }
+        Ok(())
+    }
+
+    /// Counts the lines written to it
+    struct LineCounter(Arc<AtomicUsize>);
+
+    impl Write for LineCounter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let lines = buf.iter().filter(|b| **b == b'\n').count();
+            self.0.fetch_add(lines, Ordering::SeqCst);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Counts allocations across the test binary, for measuring the output path's
+    struct CountingAllocator;
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// How long a run took, and how many allocations it made
+    struct Flood {
+        elapsed: Duration,
+        allocations: usize,
+    }
+
+    /// An init line followed by `count` echo lines
+    fn flood_lines(count: usize) -> anyhow::Result<Vec<Vec<u8>>> {
+        let init = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(0)
+            .build(),
+        );
+        let mut lines = vec![format!("{}\n", serde_json::to_string(&init)?).into_bytes()];
+        for msg_id in 1..=count {
+            let echo = Message::new(
+                "c1",
+                "n1",
+                BodyBuilder::new(EchoPayload::Echo {
+                    echo: "ding-dong!".into(),
+                })
+                .msg_id(msg_id)
+                .build(),
+            );
+            lines.push(format!("{}\n", serde_json::to_string(&echo)?).into_bytes());
+        }
+
+        Ok(lines)
+    }
+
+    /// Has `runtime` init and answer `count` echoes read from memory
+    fn echo_flood(runtime: Runtime<EchoPayload, EchoNode>, count: usize) -> anyhow::Result<Flood> {
+        let input = flood_lines(count)?.concat();
+        let written = Arc::new(AtomicUsize::new(0));
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        runtime.start_with_io(std::io::Cursor::new(input), LineCounter(written.clone()))?;
+        assert_eq!(count + 1, written.load(Ordering::SeqCst));
+
+        Ok(Flood {
+            elapsed: start.elapsed(),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        })
+    }
+
+    /// Like `echo_flood`, but sends each echo only once the last one was answered,
+    /// so the output thread always keeps up
+    fn echo_paced(runtime: Runtime<EchoPayload, EchoNode>, count: usize) -> anyhow::Result<Flood> {
+        let lines = flood_lines(count)?;
+        let (input_tx, input_rx) = channel();
+        let written = Arc::new(AtomicUsize::new(0));
+
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let handle = runtime.spawn_with_io(
+            BufReader::new(ChannelReader(input_rx)),
+            LineCounter(written.clone()),
+        )?;
+        for (sent, line) in lines.into_iter().enumerate() {
+            input_tx.send(line)?;
+            while written.load(Ordering::SeqCst) <= sent {
+                thread::yield_now();
+            }
+        }
+        drop(input_tx);
+        handle.join()?;
+
+        Ok(Flood {
+            elapsed: start.elapsed(),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        })
+    }
+
+    #[test]
+    #[ignore = "measurement, run with `cargo test --release -- --ignored --nocapture`"]
+    fn measure_batched_input() -> Try {
+        const COUNT: usize = 200_000;
+        for round in 0..3 {
+            let per_line = echo_flood(Runtime::new(), COUNT)?.elapsed;
+            let batched = echo_flood(Runtime::new().with_batched_input(64), COUNT)?.elapsed;
+            println!("round {round}, {COUNT} echoes: per line {per_line:?}, batched {batched:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "measurement, run with `cargo test --release -- --ignored --nocapture`"]
+    fn measure_frame_pool() -> Try {
+        const COUNT: usize = 20_000;
+        let unpooled = || {
+            let mut runtime = Runtime::new();
+            runtime.frames = FramePool::with_capacity(0);
+            runtime
+        };
+
+        for round in 0..3 {
+            for (name, pooled) in [("without pool", false), ("with pool", true)] {
+                let runtime = || match pooled {
+                    true => Runtime::new(),
+                    false => unpooled(),
+                };
+                let floods = [
+                    ("paced", echo_paced(runtime(), COUNT)?),
+                    ("unpaced", echo_flood(runtime(), COUNT)?),
+                ];
+                for (pacing, flood) in floods {
+                    let per_message = flood.allocations as f64 / COUNT as f64;
+                    println!(
+                        "round {round}, {COUNT} echoes {pacing} {name}: {:?}, {per_message:.2} allocations per message",
+                        flood.elapsed
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_run_with_msgpack() -> Try {
+        use crate::framing::LengthPrefixedFramer;
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+        let echo = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(EchoPayload::Echo {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(4)
+            .build(),
+        );
+
+        let codec = Codec::MsgPack;
+        let mut input = Vec::new();
+        codec.write_frame(&mut input, &codec.encode(&init)?)?;
+        codec.write_frame(&mut input, &codec.encode(&echo)?)?;
+
+        let (output_tx, output_rx) = channel();
+        Runtime::<EchoPayload, EchoNode>::run_with_codec(
+            codec,
+            std::io::Cursor::new(input),
+            ChannelWriter(output_tx),
+        )?;
+
+        let output: Vec<u8> = output_rx.try_iter().flatten().collect();
+        let mut framer = LengthPrefixedFramer::new(output.as_slice());
+        let Some(frame) = framer.next_frame_bytes()? else {
+            bail!("expected init_ok");
+        };
+        let init_ok: Message<Init> = codec.decode(&frame)?;
+        assert_eq!(init_ok.body.payload, Init::InitOk);
+
+        let Some(frame) = framer.next_frame_bytes()? else {
+            bail!("expected echo_ok");
+        };
+        let reply: Message<EchoPayload> = codec.decode(&frame)?;
+        assert_eq!(reply.body.in_reply_to, Some(4));
+        assert_eq!(
+            reply.body.payload,
+            EchoPayload::EchoOk {
+                echo: "ding-dong!".into()
+            }
+        );
+        Ok(())
+    }
+
+    /// Reads whatever is sent over a channel, blocking until it is sent
+    struct ChannelReader(Receiver<Vec<u8>>);
+
+    impl std::io::Read for ChannelReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Ok(bytes) = self.0.recv() else {
+                return Ok(0);
+            };
+
+            // tests send short lines, smaller than the BufReader's buffer
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    #[test]
+    fn test_spawn_shutdown() -> Try {
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        // the input stays open, so only shutdown stops the runtime
+        let (input_tx, input_rx) = channel();
+        let (output_tx, output_rx) = channel();
+        let handle = Runtime::<EchoPayload, EchoNode>::new().spawn_with_io(
+            BufReader::new(ChannelReader(input_rx)),
+            ChannelWriter(output_tx),
+        )?;
+
+        input_tx.send(format!("{}\n", serde_json::to_string(&init)?).into_bytes())?;
+        let mut output = Vec::new();
+        while !output.contains(&b'\n') {
+            output.extend(output_rx.recv_timeout(Duration::from_secs(1))?);
+        }
+        let _: Message<Init> = serde_json::from_slice(&output)?;
+
+        handle.shutdown();
+        handle.join()?;
+        drop(input_tx);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() -> Try {
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+        let echo = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(EchoPayload::Echo {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(4)
+            .build(),
+        );
+        // EchoNode fails on anything but an echo
+        let unexpected = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(EchoPayload::EchoOk {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(5)
+            .build(),
+        );
+
+        let (input_tx, input_rx) = channel();
+        let (output_tx, output_rx) = channel();
+        let handle = Runtime::<EchoPayload, EchoNode>::new()
+            .with_error_replies()
+            .spawn_with_io(
+                BufReader::new(ChannelReader(input_rx)),
+                ChannelWriter(output_tx),
+            )?;
+        assert_eq!(Metrics::default(), handle.metrics());
+
+        for msg in [
+            serde_json::to_string(&init)?,
+            serde_json::to_string(&echo)?,
+            serde_json::to_string(&unexpected)?,
+        ] {
+            input_tx.send(format!("{msg}\n").into_bytes())?;
+        }
+
+        // init_ok, echo_ok and the error reply
+        let mut output = Vec::new();
+        while output.iter().filter(|b| **b == b'\n').count() < 3 {
+            output.extend(output_rx.recv_timeout(Duration::from_secs(1))?);
+        }
+
+        let metrics = handle.metrics();
+        assert_eq!(2, metrics.received);
+        assert_eq!(2, metrics.sent);
+        assert_eq!(1, metrics.handler_errors);
+        assert_eq!((0, 0), (metrics.rpcs, metrics.rpc_timeouts));
+
+        handle.shutdown();
+        handle.join()?;
+        drop(input_tx);
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_closed() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+        // nothing is written once the output is gone, so the node's sends fail
+        drop(stdout_rx);
+
+        let runtime = thread::spawn(move || {
+            Runtime::<EchoPayload, EchoNode>::new().run_internal(stdout_tx, stdin_rx)
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+
+        // echoes until one fails to send, which stops the runtime while input is still open
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !runtime.is_finished() && Instant::now() < deadline {
+            let echo = Message::new(
+                "c1",
+                "n1",
+                BodyBuilder::new(EchoPayload::Echo {
+                    echo: "ding-dong!".into(),
+                })
+                .msg_id(4)
+                .build(),
+            );
+            stdin_tx.send(serde_json::to_vec(&echo)?)?;
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(runtime.is_finished());
+        runtime.join().unwrap()?;
+        drop(stdin_tx);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_each_message() -> Try {
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        // the buffer is far larger than a reply, only a flush gets init_ok out of it
+        let (input_tx, input_rx) = channel();
+        let (output_tx, output_rx) = channel();
+        let handle = Runtime::<EchoPayload, EchoNode>::new().spawn_with_io(
+            BufReader::new(ChannelReader(input_rx)),
+            std::io::BufWriter::new(ChannelWriter(output_tx)),
+        )?;
+
+        input_tx.send(format!("{}\n", serde_json::to_string(&init)?).into_bytes())?;
+        let output = output_rx.recv_timeout(Duration::from_secs(1))?;
+        let init_ok: Message<Init> = serde_json::from_slice(&output)?;
+        assert_eq!(Some(3), init_ok.body.in_reply_to);
+
+        handle.shutdown();
+        handle.join()?;
+        drop(input_tx);
+        Ok(())
+    }
+
+    static OUTBOUND: Mutex<Option<Network<EchoPayload>>> = parking_lot::const_mutex(None);
+
+    /// Echoes, leaving its network where the test can read the outbound depth
+    struct DepthNode {
+        network: Network<EchoPayload>,
+    }
+
+    impl Node<EchoPayload> for DepthNode {
+        fn from_init(network: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            *OUTBOUND.lock() = Some(network.clone());
+            DepthNode { network }
+        }
+
+        fn handle_message(&mut self, msg: Message<EchoPayload>) -> Try {
+            let EchoPayload::Echo { echo } = msg.body.payload.clone() else {
+                bail!("unexpected message");
+            };
+            self.network.reply(msg, EchoPayload::EchoOk { echo })
+        }
+    }
+
+    /// Holds each flush until the test opens the gate
+    struct GatedWriter {
+        output: Sender<Vec<u8>>,
+        gate: Receiver<()>,
+    }
+
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self.output.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            let _ = self.gate.recv();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_outbound_depth_counts_down_once_written() -> Try {
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+        let echo = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(EchoPayload::Echo {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(4)
+            .build(),
+        );
+
+        let (input_tx, input_rx) = channel();
+        let (output_tx, output_rx) = channel();
+        let (gate_tx, gate_rx) = channel();
+        let handle = Runtime::<EchoPayload, DepthNode>::new().spawn_with_io(
+            BufReader::new(ChannelReader(input_rx)),
+            GatedWriter {
+                output: output_tx,
+                gate: gate_rx,
+            },
+        )?;
+
+        let mut output = Vec::new();
+        input_tx.send(format!("{}\n", serde_json::to_string(&init)?).into_bytes())?;
+        while !output.contains(&b'\n') {
+            output.extend(output_rx.recv_timeout(Duration::from_secs(1))?);
+        }
+        gate_tx.send(())?;
+
+        input_tx.send(format!("{}\n", serde_json::to_string(&echo)?).into_bytes())?;
+        while output.iter().filter(|b| **b == b'\n').count() < 2 {
+            output.extend(output_rx.recv_timeout(Duration::from_secs(1))?);
+        }
+        let network = OUTBOUND.lock().clone().unwrap();
+
+        // the output thread is holding the written echo_ok in its flush
+        assert_eq!(1, network.outbound_depth());
+
+        gate_tx.send(())?;
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while network.outbound_depth() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(0, network.outbound_depth());
+
+        handle.shutdown();
+        handle.join()?;
+        drop(input_tx);
+        Ok(())
+    }
+
+    /// Echoes messages, taking its time with "slow" ones
+    struct SlowEchoNode {
+        network: Network<EchoPayload>,
+    }
+
+    impl ConcurrentNode<EchoPayload> for SlowEchoNode {
+        fn from_init(network: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            SlowEchoNode { network }
+        }
+
+        fn handle_message(&self, msg: Message<EchoPayload>) -> Try {
+            let EchoPayload::Echo { echo } = &msg.body.payload else {
+                bail!("expected echo");
+            };
+
+            if echo == "slow" {
+                thread::sleep(Duration::from_millis(200));
+            }
+
+            let echo = echo.clone();
+            self.network.reply(msg, EchoPayload::EchoOk { echo })
+        }
+    }
+
+    #[test]
+    fn test_concurrent() -> Try {
+        let (stdout_tx, stdout_rx) = channel();
+        let (stdin_tx, stdin_rx) = channel();
+
+        thread::spawn(move || {
+            Runtime::<EchoPayload, SlowEchoNode>::new()
+                .run_concurrent_internal(2, stdout_tx, stdin_rx)
+                .unwrap();
+        });
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        stdin_tx.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&stdout_rx.recv()?)?;
+
+        for (msg_id, echo) in [(4, "slow"), (5, "fast")] {
+            let echo = Message::new(
+                "c1",
+                "n1",
+                BodyBuilder::new(EchoPayload::Echo { echo: echo.into() })
+                    .msg_id(msg_id)
+                    .build(),
+            );
+            stdin_tx.send(serde_json::to_vec(&echo)?)?;
+        }
+
+        // the fast echo isn't stuck behind the slow one
+        let first: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!(Some(5), first.body.in_reply_to);
+        let second: Message<EchoPayload> = serde_json::from_slice(&stdout_rx.recv()?)?;
+        assert_eq!(Some(4), second.body.in_reply_to);
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_message() -> Try {
+        let (_, input, output) = run_node();
+
+        let init = Message::new(
+            "c2",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(3)
+            .build(),
+        );
+
+        input.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&output.recv()?)?;
+
+        let echo = |msg_id| {
+            Message::new(
+                "c2",
+                "n1",
+                BodyBuilder::new(EchoPayload::Echo {
+                    echo: "ding-dong!".into(),
+                })
+                .msg_id(msg_id)
+                .build(),
+            )
+        };
+
+        input.send(serde_json::to_vec(&echo(4))?)?;
+        input.send(br#"{"src": "c2", "dest": "n1", "body": {"type": "ech"#.to_vec())?;
+        input.send(serde_json::to_vec(&echo(5))?)?;
+
+        for msg_id in [4, 5] {
+            let reply: Message<EchoPayload> = serde_json::from_slice(&output.recv()?)?;
+            assert_eq!(Some(msg_id), reply.body.in_reply_to);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_timeout() {
+        let (stdout_tx, _stdout_rx) = channel();
+        let (_stdin_tx, stdin_rx) = channel();
+
+        let error = Runtime::<EchoPayload, EchoNode>::new()
+            .with_init_timeout(Duration::from_millis(50))
+            .run_internal(stdout_tx, stdin_rx)
+            .unwrap_err();
+        assert_eq!("no init received within 50ms", error.to_string());
+    }
+
     #[test]
     fn test_basic_init() -> Try {
         let (_, input, output) = run_node();
@@ -195,8 +2497,8 @@ mod tests {
             .build(),
         );
 
-        input.send(serde_json::to_string(&init)?)?;
-        let _: Message<Init> = serde_json::from_str(&output.recv()?)?;
+        input.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&output.recv()?)?;
         Ok(())
     }
 
@@ -215,8 +2517,8 @@ mod tests {
             .build(),
         );
 
-        input.send(serde_json::to_string(&init)?)?;
-        let _: Message<Init> = serde_json::from_str(&output.recv()?)?;
+        input.send(serde_json::to_vec(&init)?)?;
+        let _: Message<Init> = serde_json::from_slice(&output.recv()?)?;
 
         let echo = Message::new(
             "c2",
@@ -228,17 +2530,19 @@ mod tests {
             .build(),
         );
 
-        input.send(serde_json::to_string(&echo)?)?;
-        let _: Message<EchoPayload> = serde_json::from_str(&output.recv()?)?;
+        input.send(serde_json::to_vec(&echo)?)?;
+        let _: Message<EchoPayload> = serde_json::from_slice(&output.recv()?)?;
         Ok(())
     }
 
-    fn run_node() -> (JoinHandle<()>, Sender<String>, Receiver<String>) {
+    fn run_node() -> (JoinHandle<()>, Sender<Vec<u8>>, Receiver<Vec<u8>>) {
         let (stdout_tx, stdout_rx) = channel();
         let (stdin_tx, stdin_rx) = channel();
 
         let runtime = thread::spawn(move || {
-            Runtime::<EchoPayload, EchoNode>::run_internal(stdout_tx, stdin_rx).unwrap();
+            Runtime::<EchoPayload, EchoNode>::new()
+                .run_internal(stdout_tx, stdin_rx)
+                .unwrap();
         });
 
         (runtime, stdin_tx, stdout_rx)
@@ -0,0 +1,696 @@
+//! Helpers for testing nodes without the runtime or Maelstrom,
+//! enabled by the `test-util` feature
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    network::Network,
+    node::Node,
+    types::{BodyBuilder, Message, Payload, Try},
+};
+
+/// A Network whose outbound messages are captured instead of written out.
+/// Pass `network()` to a node's `from_init`, call its handlers directly,
+/// then inspect what it sent with `sent`.
+///
+/// RPCs are answered with responses staged by `respond` for their msg_id.
+/// An RPC without a staged response is never answered.
+#[derive(Debug)]
+pub struct MockNetwork<P> {
+    network: Network<P>,
+    sent: Arc<Mutex<Vec<Message<P>>>>,
+    responses: Arc<Mutex<HashMap<usize, P>>>,
+}
+
+impl<P: Payload> MockNetwork<P> {
+    /// Constructs a mock network with a background thread capturing its messages.
+    /// The thread stops shortly after the mock is dropped.
+    pub fn new() -> Self {
+        let (network, outbound) = Network::new();
+        let mock = Self {
+            network,
+            sent: Arc::default(),
+            responses: Arc::default(),
+        };
+
+        mock.capture(outbound);
+        mock
+    }
+
+    fn capture(&self, outbound: Receiver<Message<P>>) {
+        let network = self.network.clone();
+        let sent = self.sent.clone();
+        let responses = self.responses.clone();
+
+        thread::spawn(move || loop {
+            let msg = match outbound.recv_timeout(Duration::from_millis(10)) {
+                Ok(msg) => msg,
+                // the thread holds the only other reference once the mock is dropped
+                Err(RecvTimeoutError::Timeout) if Arc::strong_count(&sent) == 1 => break,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            let response = msg
+                .body
+                .msg_id
+                .and_then(|msg_id| responses.lock().remove(&msg_id));
+
+            if let Some(payload) = response {
+                let reply = msg.clone().into_reply(payload);
+                network.check_callback(reply);
+            }
+
+            sent.lock().push(msg);
+            network.mark_written();
+        });
+    }
+
+    /// The network to hand to the node under test
+    pub fn network(&self) -> Network<P> {
+        self.network.clone()
+    }
+
+    /// Stages `payload` as the response to the RPC sent with `msg_id`
+    pub fn respond(&self, msg_id: usize, payload: P) {
+        self.responses.lock().insert(msg_id, payload);
+    }
+
+    /// Every message sent on the network so far, in the order they were sent
+    pub fn sent(&self) -> Vec<Message<P>> {
+        self.flush();
+        self.sent.lock().clone()
+    }
+
+    /// Removes and returns every message sent on the network so far
+    pub fn take_sent(&self) -> Vec<Message<P>> {
+        self.flush();
+        std::mem::take(&mut *self.sent.lock())
+    }
+
+    /// Waits until every message already sent has been captured
+    fn flush(&self) {
+        while self.network.outbound_depth() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl<P: Payload> Default for MockNetwork<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A node in a Cluster, with the network it sends on
+#[derive(Debug)]
+struct Member<P, N> {
+    node: N,
+    network: Network<P>,
+    outbound: Receiver<Message<P>>,
+}
+
+/// Several nodes wired together in-process, named `n0`, `n1`, ... like Maelstrom names them.
+/// Messages are delivered on the calling thread, one at a time, in the order they were sent:
+/// a message to a cluster node is passed to its pending RPC, its `handle_orphan_reply`
+/// if it's a reply no RPC is waiting on, or its `handle_message`,
+/// anything else, such as a reply to a client, is kept until taken with `take_inbox`.
+///
+/// Since delivery happens on the caller's thread, a handler that blocks waiting
+/// for an RPC to another node in the cluster never returns.
+#[derive(Debug)]
+pub struct Cluster<P, N> {
+    members: BTreeMap<String, Member<P, N>>,
+    pending: VecDeque<Message<P>>,
+    inboxes: HashMap<String, Vec<Message<P>>>,
+    client_ids: usize,
+}
+
+impl<P: Payload, N: Node<P>> Cluster<P, N> {
+    /// Constructs `size` nodes with `from_init`, then calls their `on_init`
+    pub fn new(size: usize) -> Self {
+        let node_ids: Vec<String> = (0..size).map(|i| format!("n{i}")).collect();
+        let members = node_ids
+            .iter()
+            .map(|node_id| {
+                let (network, outbound) = Network::new();
+                let mut node = N::from_init(network.clone(), node_id.clone(), node_ids.clone());
+                if let Err(e) = node.on_init() {
+                    panic!("{node_id} failed to initialize: {e:#}");
+                }
+
+                let member = Member {
+                    node,
+                    network,
+                    outbound,
+                };
+                (node_id.clone(), member)
+            })
+            .collect();
+
+        Self {
+            members,
+            pending: VecDeque::new(),
+            inboxes: HashMap::new(),
+            client_ids: 0,
+        }
+    }
+
+    /// The ids of the nodes in the cluster
+    pub fn node_ids(&self) -> Vec<String> {
+        self.members.keys().cloned().collect()
+    }
+
+    /// The node with `node_id`, if it is in the cluster
+    pub fn node(&self, node_id: &str) -> Option<&N> {
+        self.members.get(node_id).map(|member| &member.node)
+    }
+
+    /// Sends a request from `client` to `dest`, returning its msg_id.
+    /// The request is queued behind messages already sent.
+    pub fn request(&mut self, client: &str, dest: &str, payload: P) -> usize {
+        let msg_id = self.client_ids;
+        self.client_ids += 1;
+
+        let body = BodyBuilder::new(payload).msg_id(msg_id).build();
+        self.collect();
+        self.pending.push_back(Message::new(client, dest, body));
+        msg_id
+    }
+
+    /// Ticks every node, in node id order
+    pub fn tick(&mut self) -> Try {
+        for member in self.members.values_mut() {
+            member.node.tick(&member.network)?;
+        }
+
+        Ok(())
+    }
+
+    /// Delivers the next message, returning false if there was none
+    pub fn step(&mut self) -> anyhow::Result<bool> {
+        self.collect();
+        let Some(msg) = self.pending.pop_front() else {
+            return Ok(false);
+        };
+
+        self.deliver(msg)?;
+        Ok(true)
+    }
+
+    /// Delivers messages until none are left, including the ones sent while handling them.
+    /// Returns how many were delivered.
+    pub fn deliver_all(&mut self) -> anyhow::Result<usize> {
+        let mut delivered = 0;
+        while self.step()? {
+            delivered += 1;
+        }
+
+        Ok(delivered)
+    }
+
+    /// Removes and returns the messages delivered to `dest`, a client or another
+    /// destination outside the cluster, in the order they were delivered
+    pub fn take_inbox(&mut self, dest: &str) -> Vec<Message<P>> {
+        self.inboxes.remove(dest).unwrap_or_default()
+    }
+
+    /// Number of messages waiting to be delivered
+    pub fn pending(&mut self) -> usize {
+        self.collect();
+        self.pending.len()
+    }
+
+    /// Moves messages the nodes have sent onto the back of the pending queue
+    fn collect(&mut self) {
+        for member in self.members.values() {
+            for msg in member.outbound.try_iter() {
+                member.network.mark_written();
+                self.pending.push_back(msg);
+            }
+        }
+    }
+
+    fn deliver(&mut self, msg: Message<P>) -> Try {
+        let Some(member) = self.members.get_mut(&msg.dest) else {
+            self.inboxes.entry(msg.dest.clone()).or_default().push(msg);
+            return Ok(());
+        };
+
+        // as in the runtime, replies no rpc is waiting on are orphans
+        match member.network.check_callback(msg) {
+            Some(msg) if msg.body.in_reply_to.is_some() => member.node.handle_orphan_reply(msg),
+            Some(msg) => member.node.handle_message(msg),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Drives a Cluster deterministically, choosing which pending message is
+/// delivered or dropped next from an RNG seeded with a fixed seed,
+/// so a failing interleaving can be replayed by reusing its seed.
+#[derive(Debug)]
+pub struct Scheduler<P, N> {
+    cluster: Cluster<P, N>,
+    rng: StdRng,
+    drop_rate: f64,
+    reorder: bool,
+}
+
+impl<P: Payload, N: Node<P>> Scheduler<P, N> {
+    /// Constructs a scheduler that delivers the cluster's messages in the order they were sent
+    pub fn new(cluster: Cluster<P, N>, seed: u64) -> Self {
+        Self {
+            cluster,
+            rng: StdRng::seed_from_u64(seed),
+            drop_rate: 0.0,
+            reorder: false,
+        }
+    }
+
+    /// Drops each message `step` handles with probability `drop_rate`
+    pub fn with_drop_rate(mut self, drop_rate: f64) -> Self {
+        self.drop_rate = drop_rate;
+        self
+    }
+
+    /// Picks the next message at random from every pending message,
+    /// delaying messages behind ones sent after them
+    pub fn with_reordering(mut self) -> Self {
+        self.reorder = true;
+        self
+    }
+
+    /// The scheduled cluster, to send requests, tick nodes, and read inboxes
+    pub fn cluster(&mut self) -> &mut Cluster<P, N> {
+        &mut self.cluster
+    }
+
+    /// Delivers the next message, returning false if there was none
+    pub fn deliver_next(&mut self) -> anyhow::Result<bool> {
+        let Some(msg) = self.next() else {
+            return Ok(false);
+        };
+
+        self.cluster.deliver(msg)?;
+        Ok(true)
+    }
+
+    /// Discards the next message, returning it if there was one
+    pub fn drop_next(&mut self) -> Option<Message<P>> {
+        self.next()
+    }
+
+    /// Delivers or, with the configured drop rate, drops the next message.
+    /// Returns false if there was none.
+    pub fn step(&mut self) -> anyhow::Result<bool> {
+        if self.drop_rate > 0.0 && self.rng.gen_bool(self.drop_rate) {
+            return Ok(self.drop_next().is_some());
+        }
+
+        self.deliver_next()
+    }
+
+    /// Takes up to `k` steps, stopping early if no messages are left.
+    /// Returns how many steps were taken.
+    pub fn step_n(&mut self, k: usize) -> anyhow::Result<usize> {
+        for taken in 0..k {
+            if !self.step()? {
+                return Ok(taken);
+            }
+        }
+
+        Ok(k)
+    }
+
+    fn next(&mut self) -> Option<Message<P>> {
+        let pending = self.cluster.pending();
+        if pending == 0 {
+            return None;
+        }
+
+        let index = match self.reorder {
+            true => self.rng.gen_range(0..pending),
+            false => 0,
+        };
+        self.cluster.pending.remove(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use anyhow::bail;
+
+    use crate::payload;
+
+    use super::*;
+
+    payload!(
+        enum Payload {
+            Broadcast { message: usize },
+            BroadcastOk,
+            Replicate { messages: Vec<usize> },
+            Read,
+            ReadOk { messages: Vec<usize> },
+        }
+    );
+
+    /// Stores broadcasts and replicates them to every other node on tick
+    struct BroadcastNode {
+        id: String,
+        net: Network<Payload>,
+        messages: BTreeSet<usize>,
+        unreplicated: HashMap<String, Vec<usize>>,
+        /// replies that arrived with no rpc waiting on them
+        orphans: usize,
+    }
+
+    impl Node<Payload> for BroadcastNode {
+        fn from_init(network: Network<Payload>, node_id: String, node_ids: Vec<String>) -> Self {
+            let unreplicated = node_ids
+                .into_iter()
+                .filter(|id| id != &node_id)
+                .map(|id| (id, Vec::new()))
+                .collect();
+
+            Self {
+                id: node_id,
+                net: network,
+                messages: BTreeSet::new(),
+                unreplicated,
+                orphans: 0,
+            }
+        }
+
+        fn handle_message(&mut self, msg: Message<Payload>) -> Try {
+            match &msg.body.payload {
+                Payload::Broadcast { message } => {
+                    self.messages.insert(*message);
+                    for pending in self.unreplicated.values_mut() {
+                        pending.push(*message);
+                    }
+
+                    self.net.reply(msg, Payload::BroadcastOk)
+                }
+                Payload::Replicate { messages } => {
+                    self.messages.extend(messages);
+                    Ok(())
+                }
+                Payload::Read => {
+                    let messages = self.messages.iter().copied().collect();
+                    self.net.reply(msg, Payload::ReadOk { messages })
+                }
+                _ => bail!("unexpected message"),
+            }
+        }
+
+        fn handle_orphan_reply(&mut self, _msg: Message<Payload>) -> Try {
+            self.orphans += 1;
+            Ok(())
+        }
+
+        fn tick(&mut self, network: &Network<Payload>) -> Try {
+            for (peer, pending) in &mut self.unreplicated {
+                if pending.is_empty() {
+                    continue;
+                }
+
+                let messages = std::mem::take(pending);
+                let body = BodyBuilder::new(Payload::Replicate { messages }).build();
+                network.send(Message::new(&self.id, peer, body))?;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn node_ids() -> Vec<String> {
+        vec!["n0".into(), "n1".into()]
+    }
+
+    #[test]
+    fn test_captures_reply() -> Try {
+        let mock = MockNetwork::new();
+        let mut node = BroadcastNode::from_init(mock.network(), "n0".into(), node_ids());
+
+        let broadcast = BodyBuilder::new(Payload::Broadcast { message: 7 })
+            .msg_id(3)
+            .build();
+        node.handle_message(Message::new("c1", "n0", broadcast))?;
+
+        let sent = mock.take_sent();
+        assert_eq!(1, sent.len());
+        assert_eq!("c1", sent[0].dest);
+        assert_eq!(Some(3), sent[0].body.in_reply_to);
+        assert_eq!(Payload::BroadcastOk, sent[0].body.payload);
+
+        assert!(node.messages.contains(&7));
+        assert_eq!(Some(&vec![7]), node.unreplicated.get("n1"));
+        assert!(mock.sent().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_staged_response() -> Try {
+        let mock = MockNetwork::new();
+        let network = mock.network();
+        let read_ok = Payload::ReadOk { messages: vec![5] };
+        mock.respond(0, read_ok.clone());
+
+        let response = network.rpc_auto("n0", "n1", Payload::Read)?.recv()?;
+        assert_eq!(read_ok, response.body.payload);
+        assert_eq!(Some(0), response.body.in_reply_to);
+
+        let sent = mock.sent();
+        assert_eq!(1, sent.len());
+        assert_eq!("n1", sent[0].dest);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cluster_broadcast_converges() -> Try {
+        let mut cluster = Cluster::<Payload, BroadcastNode>::new(3);
+        assert_eq!(vec!["n0", "n1", "n2"], cluster.node_ids());
+
+        cluster.request("c1", "n0", Payload::Broadcast { message: 1 });
+        cluster.request("c2", "n2", Payload::Broadcast { message: 2 });
+        // both requests and both replies
+        assert_eq!(4, cluster.deliver_all()?);
+        assert_eq!(1, cluster.take_inbox("c1").len());
+        assert_eq!(1, cluster.take_inbox("c2").len());
+
+        // not replicated until the nodes tick
+        assert!(cluster.node("n1").unwrap().messages.is_empty());
+        cluster.tick()?;
+        assert_eq!(4, cluster.deliver_all()?);
+        cluster.tick()?;
+        assert_eq!(0, cluster.deliver_all()?);
+
+        for node_id in cluster.node_ids() {
+            let msg_id = cluster.request("c3", &node_id, Payload::Read);
+            cluster.deliver_all()?;
+
+            let reply = cluster.take_inbox("c3").pop().unwrap();
+            assert_eq!(Some(msg_id), reply.body.in_reply_to);
+            assert_eq!(
+                Payload::ReadOk {
+                    messages: vec![1, 2]
+                },
+                reply.body.payload
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cluster_orphan_reply() -> Try {
+        let mut cluster = Cluster::<Payload, BroadcastNode>::new(2);
+
+        // n0's reply reaches n1, which has no rpc waiting on it
+        cluster.request("n1", "n0", Payload::Broadcast { message: 1 });
+        assert_eq!(2, cluster.deliver_all()?);
+        assert_eq!(1, cluster.node("n1").unwrap().orphans);
+        assert!(cluster.node("n1").unwrap().messages.is_empty());
+        Ok(())
+    }
+
+    fn broadcasts(size: usize, seed: u64) -> Scheduler<Payload, BroadcastNode> {
+        let mut scheduler = Scheduler::new(Cluster::new(size), seed).with_reordering();
+        for message in 0..5 {
+            scheduler
+                .cluster()
+                .request("c1", "n0", Payload::Broadcast { message });
+        }
+
+        scheduler
+    }
+
+    #[test]
+    fn test_scheduler_replays_seed() {
+        let order = |seed| {
+            let mut scheduler = broadcasts(1, seed);
+            std::iter::from_fn(|| scheduler.drop_next())
+                .map(|msg| msg.body.msg_id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(5, order(7).len());
+        assert_eq!(order(7), order(7));
+    }
+
+    #[test]
+    fn test_scheduler_drops() -> Try {
+        let mut scheduler = broadcasts(2, 1).with_drop_rate(1.0);
+        assert_eq!(5, scheduler.step_n(10)?);
+        assert!(scheduler.cluster().take_inbox("c1").is_empty());
+        assert!(scheduler.cluster().node("n0").unwrap().messages.is_empty());
+        Ok(())
+    }
+
+    mod partition {
+        use std::time::Duration;
+
+        use crate::network::RpcTimeout;
+
+        use super::*;
+
+        payload!(
+            enum LogPayload {
+                Send { key: String, msg: usize },
+                SendOk { offset: usize },
+            }
+        );
+
+        /// `k1` is owned by `n1`, and so on
+        fn owner(key: &str) -> String {
+            format!("n{}", &key[1..])
+        }
+
+        /// Appends to the logs it owns, forwarding sends for other keys to their owner.
+        /// A `BLOCKING` node waits for the owner inside its handler.
+        struct LogNode<const BLOCKING: bool> {
+            id: String,
+            net: Network<LogPayload>,
+            logs: HashMap<String, Vec<usize>>,
+            /// client requests waiting on a forwarded send, by the forward's msg_id
+            forwarded: HashMap<usize, Message<LogPayload>>,
+        }
+
+        impl<const BLOCKING: bool> Node<LogPayload> for LogNode<BLOCKING> {
+            fn from_init(network: Network<LogPayload>, node_id: String, _: Vec<String>) -> Self {
+                Self {
+                    id: node_id,
+                    net: network,
+                    logs: HashMap::new(),
+                    forwarded: HashMap::new(),
+                }
+            }
+
+            fn handle_message(&mut self, msg: Message<LogPayload>) -> Try {
+                match &msg.body.payload {
+                    LogPayload::Send { key, msg: value } if owner(key) == self.id => {
+                        let log = self.logs.entry(key.clone()).or_default();
+                        log.push(*value);
+                        let offset = log.len() - 1;
+                        self.net.reply(msg, LogPayload::SendOk { offset })
+                    }
+                    LogPayload::Send { key, .. } => {
+                        let msg_id = self.net.next_id();
+                        let body = BodyBuilder::new(msg.body.payload.clone())
+                            .msg_id(msg_id)
+                            .build();
+                        let forward = Message::new(&self.id, owner(key), body);
+
+                        if BLOCKING {
+                            let response =
+                                self.net.rpc_timeout(forward, Duration::from_millis(50))?;
+                            return self.net.reply(msg, response.body.payload);
+                        }
+
+                        // sent without an rpc, so the owner's send_ok arrives as an orphan
+                        self.forwarded.insert(msg_id, msg);
+                        self.net.send(forward)
+                    }
+                    LogPayload::SendOk { .. } => bail!("unexpected send_ok"),
+                }
+            }
+
+            /// Completes the client request waiting on a forwarded send
+            fn handle_orphan_reply(&mut self, msg: Message<LogPayload>) -> Try {
+                let request = msg
+                    .body
+                    .in_reply_to
+                    .and_then(|id| self.forwarded.remove(&id));
+                let Some(request) = request else {
+                    bail!("unexpected reply");
+                };
+
+                self.net.reply(request, msg.body.payload)
+            }
+        }
+
+        fn cross_partition_sends<const BLOCKING: bool>() -> Cluster<LogPayload, LogNode<BLOCKING>> {
+            let mut cluster = Cluster::new(2);
+            cluster.request(
+                "c1",
+                "n0",
+                LogPayload::Send {
+                    key: "k1".into(),
+                    msg: 123,
+                },
+            );
+            cluster.request(
+                "c2",
+                "n1",
+                LogPayload::Send {
+                    key: "k0".into(),
+                    msg: 456,
+                },
+            );
+            cluster
+        }
+
+        #[test]
+        fn test_blocking_forward_deadlocks() {
+            // as in the kafka example's docs: each node waits on the other to handle
+            // its forwarded send, which can't happen while the node is busy waiting
+            let mut scheduler = Scheduler::new(cross_partition_sends::<true>(), 0);
+            for _ in 0..2 {
+                let error = scheduler.deliver_next().unwrap_err();
+                assert!(error.downcast_ref::<RpcTimeout>().is_some());
+            }
+
+            assert!(scheduler.cluster().take_inbox("c1").is_empty());
+            assert!(scheduler.cluster().take_inbox("c2").is_empty());
+        }
+
+        #[test]
+        fn test_async_forward_completes() -> Try {
+            for seed in 0..10 {
+                let mut scheduler =
+                    Scheduler::new(cross_partition_sends::<false>(), seed).with_reordering();
+                assert_eq!(8, scheduler.step_n(20)?);
+
+                for client in ["c1", "c2"] {
+                    let replies = scheduler.cluster().take_inbox(client);
+                    assert_eq!(1, replies.len());
+                    assert_eq!(LogPayload::SendOk { offset: 0 }, replies[0].body.payload);
+                }
+            }
+            Ok(())
+        }
+    }
+}
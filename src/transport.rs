@@ -0,0 +1,353 @@
+//! Defines Transport, the connection a node's messages travel over,
+//! with transports for stdin/stdout, TCP, and in-memory channels
+
+use std::{
+    io::{self, stdin, stdout, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+
+use anyhow::bail;
+
+use crate::{
+    codec::Codec,
+    framing::{BatchedLineFramer, Framer, LineFramer},
+};
+
+/// The runtime's ends of a transport, see `Transport::split`
+pub type Split = (Box<dyn Framer + Send>, Box<dyn Write + Send>);
+
+/// A connection carrying newline delimited messages in both directions.
+/// The runtime receives on one clone of the transport while sending on another,
+/// see `Runtime::with_transport`.
+pub trait Transport: Clone + Send + 'static {
+    /// Whether signals stopping the process end the runtime's input like the end
+    /// of the connection, as Maelstrom stops nodes talking over stdin/stdout
+    const STOPPED_BY_SIGNALS: bool = false;
+
+    /// Receives the next line, without its newline, or None once the connection closes
+    fn recv(&mut self) -> io::Result<Option<String>>;
+
+    /// Sends `line` followed by a newline
+    fn send(&mut self, line: &str) -> io::Result<()>;
+
+    /// Splits the transport into a framer for the runtime's input thread and a writer
+    /// for its output thread. By default they receive and send one line at a time,
+    /// which only carries JSON, so this fails for other codecs.
+    /// `input_batch` is the most messages read at a time, if reads are batched.
+    fn split(self, codec: Codec, _input_batch: Option<usize>) -> anyhow::Result<Split> {
+        if codec != Codec::Json {
+            bail!("transports carry JSON lines, not {codec:?}");
+        }
+
+        Ok((
+            Box::new(TransportFramer(self.clone())),
+            Box::new(TransportWriter::new(self)),
+        ))
+    }
+}
+
+/// The stdin/stdout Maelstrom talks to nodes over, the runtime's default transport
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdioTransport;
+
+impl Transport for StdioTransport {
+    const STOPPED_BY_SIGNALS: bool = true;
+
+    fn recv(&mut self) -> io::Result<Option<String>> {
+        // stdin's buffer is shared, so nothing is lost between locks
+        LineFramer::new(stdin().lock()).next_frame()
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        let mut stdout = stdout().lock();
+        stdout.write_all(line.as_bytes())?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()
+    }
+
+    /// Reads stdin as newline delimited messages, batched if configured,
+    /// and writes stdout, where the runtime frames output with its codec
+    fn split(self, _: Codec, input_batch: Option<usize>) -> anyhow::Result<Split> {
+        let reader = BufReader::new(stdin());
+        let framer: Box<dyn Framer + Send> = match input_batch {
+            Some(max) => Box::new(BatchedLineFramer::new(reader, max)),
+            None => Box::new(LineFramer::new(reader)),
+        };
+        Ok((framer, Box::new(stdout())))
+    }
+}
+
+/// A TCP connection to another node or client
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    stream: Arc<TcpStream>,
+    /// shared by every clone, so buffered input isn't lost or read twice
+    lines: Arc<Mutex<LineFramer<BufReader<TcpReader>>>>,
+}
+
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> Self {
+        let stream = Arc::new(stream);
+        let reader = BufReader::new(TcpReader(stream.clone()));
+        Self {
+            stream,
+            lines: Arc::new(Mutex::new(LineFramer::new(reader))),
+        }
+    }
+
+    /// Connects to a node listening at `addr`
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::new(TcpStream::connect(addr)?))
+    }
+
+    /// Waits for a connection on `listener`
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl Transport for TcpTransport {
+    fn recv(&mut self) -> io::Result<Option<String>> {
+        self.lines.lock().next_frame()
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        // one write, so the line isn't split across segments
+        let mut frame = Vec::with_capacity(line.len() + 1);
+        frame.extend_from_slice(line.as_bytes());
+        frame.push(b'\n');
+        (&*self.stream).write_all(&frame)
+    }
+}
+
+/// Reads from a stream shared with the writing half of a `TcpTransport`
+#[derive(Debug)]
+struct TcpReader(Arc<TcpStream>);
+
+impl Read for TcpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+/// One end of an in-memory connection, for running a node in the same process
+/// as whatever talks to it, such as a test. See `ChannelTransport::pair`.
+#[derive(Debug, Clone)]
+pub struct ChannelTransport {
+    tx: Sender<String>,
+    rx: Arc<Mutex<Receiver<String>>>,
+}
+
+impl ChannelTransport {
+    /// Both ends of a connection, lines sent on either end are received on the other.
+    /// An end stops receiving once every clone of the other end is dropped.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+        let a = Self {
+            tx: b_tx,
+            rx: Arc::new(Mutex::new(a_rx)),
+        };
+        let b = Self {
+            tx: a_tx,
+            rx: Arc::new(Mutex::new(b_rx)),
+        };
+        (a, b)
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn recv(&mut self) -> io::Result<Option<String>> {
+        Ok(self.rx.lock().recv().ok())
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        self.tx
+            .send(line.to_string())
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "the other end was dropped"))
+    }
+}
+
+/// Frames the lines received on a transport, for the runtime's input thread
+pub(crate) struct TransportFramer<T>(pub(crate) T);
+
+impl<T: Transport> Framer for TransportFramer<T> {
+    fn next_frame(&mut self) -> io::Result<Option<String>> {
+        self.0.recv()
+    }
+}
+
+/// Sends whole lines on a transport, for the runtime's output thread
+pub(crate) struct TransportWriter<T> {
+    transport: T,
+    /// written bytes not yet ended by a newline
+    pending: Vec<u8>,
+}
+
+impl<T> TransportWriter<T> {
+    pub(crate) fn new(transport: T) -> Self {
+        Self {
+            transport,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<T: Transport> Write for TransportWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = std::str::from_utf8(&line[..end])
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            self.transport.send(line)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Shutdown, thread};
+
+    use anyhow::bail;
+
+    use crate::{
+        network::Network,
+        node::Node,
+        payload,
+        runtime::Runtime,
+        types::{BodyBuilder, Init, Message, Try},
+    };
+
+    use super::*;
+
+    payload!(
+        enum EchoPayload {
+            Echo { echo: String },
+            EchoOk { echo: String },
+        }
+    );
+
+    struct EchoNode {
+        network: Network<EchoPayload>,
+    }
+
+    impl Node<EchoPayload> for EchoNode {
+        fn from_init(network: Network<EchoPayload>, _: String, _: Vec<String>) -> Self {
+            EchoNode { network }
+        }
+
+        fn handle_message(&mut self, msg: Message<EchoPayload>) -> Try {
+            let EchoPayload::Echo { echo } = &msg.body.payload else {
+                return Ok(());
+            };
+
+            let echo = echo.clone();
+            self.network.reply(msg, EchoPayload::EchoOk { echo })
+        }
+    }
+
+    /// Initializes the node on the other end of `client` and has it echo a message
+    fn echo(client: &mut impl Transport) -> Try {
+        let init = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(Init::Init {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            })
+            .msg_id(1)
+            .build(),
+        );
+        let echo = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(EchoPayload::Echo {
+                echo: "ding-dong!".into(),
+            })
+            .msg_id(2)
+            .build(),
+        );
+        client.send(&serde_json::to_string(&init)?)?;
+        client.send(&serde_json::to_string(&echo)?)?;
+
+        let Some(init_ok) = client.recv()? else {
+            bail!("expected init_ok");
+        };
+        let init_ok: Message<Init> = serde_json::from_str(&init_ok)?;
+        assert_eq!(Init::InitOk, init_ok.body.payload);
+
+        let Some(reply) = client.recv()? else {
+            bail!("expected echo_ok");
+        };
+        let reply: Message<EchoPayload> = serde_json::from_str(&reply)?;
+        assert_eq!(Some(2), reply.body.in_reply_to);
+        assert_eq!(
+            EchoPayload::EchoOk {
+                echo: "ding-dong!".into()
+            },
+            reply.body.payload
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tcp_echo() -> Try {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || -> Try {
+            let transport = TcpTransport::accept(&listener)?;
+            Runtime::<EchoPayload, EchoNode>::run_with_transport(transport)
+        });
+
+        let mut client = TcpTransport::connect(addr)?;
+        echo(&mut client)?;
+
+        // closing the connection ends the node's input
+        client.stream.shutdown(Shutdown::Both)?;
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_channel_echo() -> Try {
+        let (mut client, transport) = ChannelTransport::pair();
+        let handle = Runtime::<EchoPayload, EchoNode>::new()
+            .with_transport(transport)
+            .spawn()?;
+
+        echo(&mut client)?;
+
+        // dropping our end ends the node's input
+        drop(client);
+        handle.join()
+    }
+
+    #[test]
+    fn test_default_transport() {
+        // Runtime::run and start talk over stdin/stdout unless given another transport
+        let _: Runtime<EchoPayload, EchoNode, StdioTransport> = Runtime::new();
+    }
+
+    #[test]
+    fn test_transport_rejects_binary_codec() {
+        let (_client, transport) = ChannelTransport::pair();
+        let spawned = Runtime::<EchoPayload, EchoNode>::new()
+            .with_codec(Codec::MsgPack)
+            .with_transport(transport)
+            .spawn();
+        assert!(spawned.is_err());
+    }
+}
@@ -1,14 +1,19 @@
 //! Common type definitions for messages,
 //! as well as helper types and functions used throughout the crate
 
-use std::{fmt::Debug, sync::mpsc::Receiver};
+use std::fmt::{Debug, Display};
 
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
 
-use crate::payload;
+use crate::{
+    error::{ErrorCode, MaelstromError},
+    network::{Network, RpcReceiver},
+    payload,
+};
 
 pub type Try = anyhow::Result<()>;
-pub type Rpc<P> = anyhow::Result<Receiver<Message<P>>>;
+pub type Rpc<P> = anyhow::Result<RpcReceiver<P>>;
 
 /// Trait for non-required message body fields
 pub trait Payload: Clone + std::fmt::Debug + Serialize + DeserializeOwned + Send + 'static {}
@@ -62,6 +67,138 @@ impl<P> BodyBuilder<P> {
     }
 }
 
+/// The body of a Maelstrom error message.
+/// Payloads can include it as a variant, `Error(ErrorBody)`,
+/// which serializes as `{"type":"error","code":..,"text":..}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub code: usize,
+    pub text: String,
+}
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        ErrorBody {
+            code: code.into(),
+            text: text.into(),
+        }
+    }
+}
+
+impl Display for ErrorBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error {}: {}", self.code, self.text)
+    }
+}
+
+/// The body of a Maelstrom `read_ok`, as in the g-counter, g-set and kv workloads.
+/// Payloads can include it as a variant, `ReadOk(ReadOk<T>)`,
+/// which serializes as `{"type":"read_ok","value":..}`, see `payload!(include(..), ..)`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReadOk<T> {
+    pub value: T,
+}
+
+/// The id of a message source or destination.
+/// By Maelstrom convention clients are named `c1`, `c2`, ..., nodes `n1`, `n2`, ...,
+/// and services by name, such as `seq-kv`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(String);
+
+impl NodeId {
+    pub fn new(id: impl Into<String>) -> Self {
+        NodeId(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns true for client ids, such as `c1`
+    pub fn is_client(&self) -> bool {
+        self.has_prefix('c')
+    }
+
+    /// Returns true for node ids, such as `n1`
+    pub fn is_node(&self) -> bool {
+        self.has_prefix('n')
+    }
+
+    /// Returns true for service ids, such as `seq-kv`
+    pub fn is_service(&self) -> bool {
+        !self.is_client() && !self.is_node()
+    }
+
+    /// `prefix` followed by a number
+    fn has_prefix(&self, prefix: char) -> bool {
+        self.0
+            .strip_prefix(prefix)
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+    }
+}
+
+impl From<&str> for NodeId {
+    fn from(id: &str) -> Self {
+        NodeId::new(id)
+    }
+}
+
+impl From<String> for NodeId {
+    fn from(id: String) -> Self {
+        NodeId(id)
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Maelstrom's built-in services, addressed by their id like any other node.
+/// https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Service {
+    /// Sequentially consistent key-value service
+    SeqKv,
+    /// Linearizable key-value service
+    LinKv,
+    /// Last-write-wins key-value service
+    LwwKv,
+    /// Linearizable timestamp oracle
+    LinTso,
+}
+
+impl Service {
+    /// The id messages to the service are addressed to, such as `seq-kv`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Service::SeqKv => "seq-kv",
+            Service::LinKv => "lin-kv",
+            Service::LwwKv => "lww-kv",
+            Service::LinTso => "lin-tso",
+        }
+    }
+}
+
+impl Display for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<Service> for String {
+    fn from(service: Service) -> Self {
+        service.as_str().to_string()
+    }
+}
+
+impl From<Service> for NodeId {
+    fn from(service: Service) -> Self {
+        NodeId::new(service.as_str())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Message<Payload> {
     pub src: String,
@@ -78,11 +215,40 @@ impl<Payload> Message<Payload> {
         }
     }
 
+    /// The id of the message's source
+    pub fn src_id(&self) -> NodeId {
+        NodeId::new(&self.src)
+    }
+
+    /// The id of the message's destination
+    pub fn dest_id(&self) -> NodeId {
+        NodeId::new(&self.dest)
+    }
+
+    /// The message's msg_id, failing if it has none. Use it on requests that must be
+    /// replied to, a reply without in_reply_to can't be matched to its request.
+    pub fn expect_msg_id(&self) -> anyhow::Result<usize> {
+        self.body
+            .msg_id
+            .ok_or_else(|| anyhow::anyhow!("message from {} has no msg_id", self.src))
+    }
+
+    /// Consumes a request and produces a reply to it without a msg_id.
+    /// Use `into_reply_with_id` or `Network::reply` to give the reply an id
+    /// from the node's own sequence.
     pub fn into_reply(self, payload: Payload) -> Self {
-        let next_id = self.body.msg_id.map(|id| id + 1);
-        self.into_reply_with_id(payload, next_id)
+        self.into_reply_with_id(payload, None)
+    }
+
+    /// Consumes a request and produces an error reply to it
+    pub fn into_error(self, code: ErrorCode, text: impl Into<String>) -> Self
+    where
+        Payload: MaelstromError,
+    {
+        self.into_reply(Payload::error(code, text.into()))
     }
 
+    /// Consumes a request and produces a reply to it with the given msg_id
     pub fn into_reply_with_id(self, payload: Payload, msg_id: Option<usize>) -> Self {
         Message {
             src: self.dest,
@@ -94,6 +260,119 @@ impl<Payload> Message<Payload> {
             },
         }
     }
+
+    /// Consumes a message and sends it on from `src` to `dest` with a new msg_id,
+    /// such as a request forwarded to the node that can serve it.
+    /// The payload is kept and `in_reply_to` is cleared.
+    pub fn forward(self, src: impl Into<String>, dest: impl Into<String>, msg_id: usize) -> Self {
+        Message {
+            src: src.into(),
+            dest: dest.into(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload: self.body.payload,
+            },
+        }
+    }
+}
+
+impl<P: Payload> Message<P> {
+    /// Consumes a request and produces one message per `(dest, payload)` target.
+    /// Messages addressed back to the requester are replies to the request,
+    /// all others are fresh notifications from the request's destination.
+    /// Every message is stamped with a new msg_id from the network's `next_id`.
+    pub fn reply_all_to<D: Into<String>>(
+        self,
+        targets: impl IntoIterator<Item = (D, P)>,
+        network: &Network<P>,
+    ) -> Vec<Self> {
+        targets
+            .into_iter()
+            .map(|(dest, payload)| {
+                let dest = dest.into();
+                let in_reply_to = if dest == self.src {
+                    self.body.msg_id
+                } else {
+                    None
+                };
+
+                Message {
+                    src: self.dest.clone(),
+                    dest,
+                    body: Body {
+                        msg_id: Some(network.next_id()),
+                        in_reply_to,
+                        payload,
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// A message whose body is kept as raw JSON, for nodes passing messages through untouched.
+/// Every body field except `msg_id` and `in_reply_to` is kept in the payload.
+pub type PassthroughMessage = Message<Value>;
+
+/// A payload along with any body fields it doesn't define.
+/// A plain payload silently drops unknown fields, use `Message<WithExtra<P>>`
+/// to echo them back with `into_reply_with_extra`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithExtra<P> {
+    pub payload: P,
+    pub extra: Map<String, Value>,
+}
+
+impl<P> WithExtra<P> {
+    /// Wraps a payload without extra fields
+    pub fn new(payload: P) -> Self {
+        WithExtra {
+            payload,
+            extra: Map::new(),
+        }
+    }
+}
+
+impl<P: Serialize> Serialize for WithExtra<P> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Value::Object(mut fields) =
+            serde_json::to_value(&self.payload).map_err(serde::ser::Error::custom)?
+        else {
+            return Err(serde::ser::Error::custom("payload must serialize as a map"));
+        };
+
+        // the payload's own fields win over an extra field of the same name
+        for (key, value) in &self.extra {
+            fields.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+
+        fields.serialize(serializer)
+    }
+}
+
+impl<'de, P: Serialize + DeserializeOwned> Deserialize<'de> for WithExtra<P> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut extra = Map::deserialize(deserializer)?;
+        let payload: P = serde_json::from_value(Value::Object(extra.clone()))
+            .map_err(serde::de::Error::custom)?;
+
+        // whatever the payload writes back out is its own, the rest is extra
+        if let Ok(Value::Object(known)) = serde_json::to_value(&payload) {
+            extra.retain(|key, _| !known.contains_key(key));
+        }
+
+        Ok(WithExtra { payload, extra })
+    }
+}
+
+impl<P> Message<WithExtra<P>> {
+    /// Consumes a request and produces a reply to it without a msg_id,
+    /// carrying the request's extra fields
+    pub fn into_reply_with_extra(self, payload: P) -> Self {
+        let extra = self.body.payload.extra.clone();
+        self.into_reply(WithExtra { payload, extra })
+    }
 }
 
 payload!(
@@ -107,6 +386,12 @@ payload!(
     }
 );
 
+/// The building blocks most nodes need, `use maelbreaker::types::prelude::*`
+pub mod prelude {
+    pub use super::{Body, BodyBuilder, ErrorBody, Init, Message, ReadOk, Try};
+    pub use crate::{network::Network, node::Node, payload, runtime::Runtime};
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -136,7 +421,7 @@ mod tests {
     #[test]
     fn test_deserialize_init() {
         let json = r#"{"src":"c1","dest":"n3","body":{"msg_id":1,"in_reply_to":null,"type":"init","node_id":"n3","node_ids":["n1","n2","n3"]}}"#;
-        let init: Message<Init> = serde_json::from_str(&json).unwrap();
+        let init: Message<Init> = serde_json::from_str(json).unwrap();
         assert_eq!(&init.src, "c1");
         assert_eq!(&init.dest, "n3");
         assert_eq!(init.body.msg_id, Some(1));
@@ -149,4 +434,197 @@ mod tests {
             _ => panic!("Unexpected message type"),
         }
     }
+
+    #[test]
+    fn test_node_id() {
+        let client = NodeId::from("c12");
+        assert!(client.is_client() && !client.is_node() && !client.is_service());
+
+        let node = NodeId::from("n3");
+        assert!(node.is_node() && !node.is_client() && !node.is_service());
+
+        for service in ["seq-kv", "lin-kv", "lww-kv", "n", "c", "cluster"] {
+            assert!(NodeId::from(service).is_service(), "{service}");
+        }
+
+        let msg = Message::new("c1", "seq-kv", BodyBuilder::new(Init::InitOk).build());
+        assert!(msg.src_id().is_client());
+        assert!(msg.dest_id().is_service());
+        assert_eq!("c1", msg.src_id().to_string());
+    }
+
+    #[test]
+    fn test_service() {
+        for service in [
+            Service::SeqKv,
+            Service::LinKv,
+            Service::LwwKv,
+            Service::LinTso,
+        ] {
+            assert!(NodeId::from(service).is_service(), "{service}");
+        }
+
+        let msg = Message::new(
+            "n1",
+            Service::LinTso,
+            BodyBuilder::new(Init::InitOk).build(),
+        );
+        assert_eq!("lin-tso", msg.dest);
+        assert_eq!("lww-kv", Service::LwwKv.to_string());
+    }
+
+    payload!(
+        enum UniquePayload {
+            Generate,
+            GenerateOk { id: usize },
+            Error(ErrorBody),
+        }
+    );
+
+    impl MaelstromError for UniquePayload {
+        fn error(code: ErrorCode, text: String) -> Self {
+            UniquePayload::Error(ErrorBody::new(code, text))
+        }
+    }
+
+    #[test]
+    fn test_into_error() {
+        let request = Message::new(
+            "c1",
+            "n1",
+            BodyBuilder::new(UniquePayload::Generate).msg_id(5).build(),
+        );
+
+        let error = request.into_error(ErrorCode::NotSupported, "ids are exhausted");
+        assert_eq!(error.dest, "c1");
+        assert_eq!(error.body.in_reply_to, Some(5));
+        assert_eq!(
+            serde_json::to_string(&error.body.payload).unwrap(),
+            r#"{"type":"error","code":10,"text":"ids are exhausted"}"#
+        );
+
+        let json = r#"{"type":"error","code":22,"text":"expected 1, was 2"}"#;
+        let UniquePayload::Error(body) = serde_json::from_str(json).unwrap() else {
+            panic!("expected error");
+        };
+        assert_eq!(
+            body,
+            ErrorBody::new(ErrorCode::PreconditionFailed, "expected 1, was 2")
+        );
+    }
+
+    #[test]
+    fn test_into_reply() {
+        let request = Message::new("c1", "n1", BodyBuilder::new(Init::InitOk).msg_id(5).build());
+
+        // the request's id isn't reused for the reply, it belongs to the client's id space
+        let reply = request.clone().into_reply(Init::InitOk);
+        assert_eq!(reply.src, "n1");
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.in_reply_to, Some(5));
+        assert_eq!(reply.body.msg_id, None);
+
+        let reply = request.into_reply_with_id(Init::InitOk, Some(42));
+        assert_eq!(reply.body.in_reply_to, Some(5));
+        assert_eq!(reply.body.msg_id, Some(42));
+    }
+
+    #[test]
+    fn test_expect_msg_id() {
+        let request = Message::new("c1", "n1", BodyBuilder::new(Init::InitOk).msg_id(5).build());
+        assert_eq!(5, request.expect_msg_id().unwrap());
+
+        let request = Message::new("c1", "n1", BodyBuilder::new(Init::InitOk).build());
+        let error = request.expect_msg_id().unwrap_err();
+        assert_eq!("message from c1 has no msg_id", error.to_string());
+    }
+
+    #[test]
+    fn test_extra_fields_round_trip() {
+        let json = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"in_reply_to":null,"node_id":"n1","node_ids":["n1"],"trace":{"span":7}}}"#;
+
+        let request: Message<WithExtra<Init>> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            Init::Init {
+                node_id: "n1".to_string(),
+                node_ids: vec!["n1".to_string()],
+            },
+            request.body.payload.payload
+        );
+        assert_eq!(1, request.body.payload.extra.len());
+        assert_eq!(
+            serde_json::from_str::<Value>(json).unwrap(),
+            serde_json::to_value(&request).unwrap()
+        );
+
+        let reply = serde_json::to_value(request.into_reply_with_extra(Init::InitOk)).unwrap();
+        assert_eq!(r#"{"span":7}"#, reply["body"]["trace"].to_string());
+        assert_eq!(r#""init_ok""#, reply["body"]["type"].to_string());
+    }
+
+    #[test]
+    fn test_passthrough_round_trip() {
+        let json = r#"{"src":"c1","dest":"n1","body":{"type":"anything","msg_id":1,"in_reply_to":null,"extra":[1,2]}}"#;
+
+        let msg: PassthroughMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(Some(1), msg.body.msg_id);
+        assert_eq!(r#""anything""#, msg.body.payload["type"].to_string());
+        assert_eq!(
+            serde_json::from_str::<Value>(json).unwrap(),
+            serde_json::to_value(&msg).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_forward() {
+        let init = Init::Init {
+            node_id: "n2".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        };
+        let request = Message::new(
+            "c1",
+            "n0",
+            BodyBuilder::new(init.clone())
+                .msg_id(5)
+                .in_reply_to(3)
+                .build(),
+        );
+
+        let forwarded = request.forward("n1", "n2", 42);
+        assert_eq!(forwarded.src, "n1");
+        assert_eq!(forwarded.dest, "n2");
+        assert_eq!(forwarded.body.msg_id, Some(42));
+        assert_eq!(forwarded.body.in_reply_to, None);
+        assert_eq!(forwarded.body.payload, init);
+    }
+
+    #[test]
+    fn test_reply_all_to() {
+        let request = Message::new("c1", "n1", BodyBuilder::new(Init::InitOk).msg_id(7).build());
+
+        let (network, _outbound) = Network::new();
+        let replies = request.reply_all_to(
+            [
+                ("c1", Init::InitOk),
+                ("n2", Init::InitOk),
+                ("n3", Init::InitOk),
+            ],
+            &network,
+        );
+
+        assert_eq!(replies.len(), 3);
+        assert!(replies.iter().all(|reply| reply.src == "n1"));
+
+        assert_eq!(replies[0].dest, "c1");
+        assert_eq!(replies[0].body.in_reply_to, Some(7));
+        assert_eq!(replies[0].body.msg_id, Some(0));
+
+        assert_eq!(replies[1].dest, "n2");
+        assert_eq!(replies[1].body.in_reply_to, None);
+        assert_eq!(replies[1].body.msg_id, Some(1));
+
+        assert_eq!(replies[2].dest, "n3");
+        assert_eq!(replies[2].body.in_reply_to, None);
+        assert_eq!(replies[2].body.msg_id, Some(2));
+    }
 }